@@ -0,0 +1,41 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use osquery_rs::osquery::*;
+
+use std::io::Write;
+use std::net::Shutdown;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::thread;
+
+static LISTENER: OnceLock<(PathBuf, UnixListener)> = OnceLock::new();
+
+fn listener() -> &'static (PathBuf, UnixListener) {
+    LISTENER.get_or_init(|| {
+        let path = std::env::temp_dir().join(format!("osquery-rs-fuzz-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        (path, listener)
+    })
+}
+
+// Serves the fuzzer input as the extension manager response to a query and
+// makes sure the client turns it into an error instead of panicking.
+fuzz_target!(|data: &[u8]| {
+    let (path, listener) = listener();
+    let mut client = OsqueryClient::new(path.to_str().unwrap()).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+
+    let data = data.to_vec();
+    let writer = thread::spawn(move || {
+        let _ = server.write_all(&data);
+        let _ = server.shutdown(Shutdown::Write);
+        server
+    });
+
+    let _ = client.query("SELECT * FROM osquery_info");
+    let _ = client.query_with_warnings("SELECT * FROM osquery_info");
+    drop(client);
+    let _ = writer.join();
+});
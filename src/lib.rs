@@ -0,0 +1,4 @@
+#![allow(deprecated)]
+#![allow(dead_code)]
+extern crate thrift;
+pub mod osquery;
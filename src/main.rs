@@ -1,15 +1,10 @@
-#![allow(deprecated)]
-#![allow(dead_code)]
-extern crate thrift;
-mod osquery;
-
-use osquery::*;
+use osquery_rs::osquery::*;
 
 const EXTENSION_SOCKET: &str = "/Users/p0n002h/.osquery/shell.em";
 
 fn main() {
     let mut client = OsqueryClient::new(EXTENSION_SOCKET).unwrap();
-    client.register_extension("thrust");
+    let _ = client.register_extension("thrust");
     println!("{:?}", client.ping());
 
     println!("{:#?}", client.query("SELECT * FROM osquery_info"));
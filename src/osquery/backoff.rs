@@ -0,0 +1,119 @@
+use std::convert::TryFrom;
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// An exponential backoff with full jitter: every delay is picked uniformly
+/// at random between zero and the exponentially growing cap. This keeps a
+/// fleet of clients that lost the extension manager at the same time from
+/// retrying in lockstep once it comes back.
+pub(crate) struct Backoff {
+    initial: Duration,
+    cap: Duration,
+    max: Duration,
+    state: u64,
+}
+
+impl Backoff {
+    /// Returns a backoff starting at `initial` and growing up to `max`. The
+    /// jitter is drawn from `seed`, or from the time and process id when no
+    /// seed is given.
+    pub(crate) fn new(initial: Duration, max: Duration, seed: Option<u64>) -> Self {
+        let seed = seed.unwrap_or_else(|| {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or_default();
+            nanos ^ u64::from(process::id()).rotate_left(32)
+        });
+
+        Backoff {
+            initial,
+            cap: initial,
+            max,
+            state: seed,
+        }
+    }
+
+    /// Returns the delay before the next attempt.
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let cap = u64::try_from(self.cap.as_nanos()).unwrap_or(u64::MAX);
+        self.cap = self
+            .cap
+            .checked_mul(2)
+            .map_or(self.max, |cap| cap.min(self.max));
+        match cap.checked_add(1) {
+            Some(bound) => Duration::from_nanos(self.next_random() % bound),
+            None => Duration::from_nanos(self.next_random()),
+        }
+    }
+
+    /// Starts over from the initial delay, as after a successful attempt.
+    pub(crate) fn reset(&mut self) {
+        self.cap = self.initial;
+    }
+
+    /// Returns the next number of a splitmix64 sequence, which is plenty
+    /// for spreading out retries.
+    fn next_random(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osquery::builder::OsqueryClientBuilder;
+
+    const INITIAL: Duration = Duration::from_millis(10);
+    const MAX: Duration = Duration::from_millis(80);
+
+    fn delays(backoff: &mut Backoff, count: usize) -> Vec<Duration> {
+        (0..count).map(|_| backoff.next_delay()).collect()
+    }
+
+    #[test]
+    fn seeded_delays_are_the_same_on_every_run() {
+        let seed = OsqueryClientBuilder::new("").backoff_seed(42).backoff_seed;
+        let first = delays(&mut Backoff::new(INITIAL, MAX, seed), 8);
+        let second = delays(&mut Backoff::new(INITIAL, MAX, seed), 8);
+        assert_eq!(first, second);
+
+        let other = delays(&mut Backoff::new(INITIAL, MAX, Some(43)), 8);
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn delays_stay_under_the_growing_cap() {
+        let mut backoff = Backoff::new(INITIAL, MAX, Some(42));
+        let caps = [10, 20, 40, 80, 80, 80];
+        for (delay, cap) in delays(&mut backoff, caps.len()).into_iter().zip(caps) {
+            assert!(
+                delay <= Duration::from_millis(cap),
+                "{:?} > {}ms",
+                delay,
+                cap
+            );
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= INITIAL);
+    }
+
+    #[test]
+    fn huge_delays_do_not_overflow() {
+        let mut backoff = Backoff::new(Duration::MAX, Duration::MAX, Some(42));
+        for _ in 0..4 {
+            // Capped at what nanoseconds in a u64 can hold.
+            assert!(backoff.next_delay() <= Duration::from_nanos(u64::MAX));
+        }
+
+        let mut backoff = Backoff::new(Duration::from_secs(u64::MAX / 2), Duration::MAX, Some(7));
+        for _ in 0..4 {
+            backoff.next_delay();
+        }
+    }
+}
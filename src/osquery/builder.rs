@@ -0,0 +1,622 @@
+use crate::osquery::error::OsqueryError;
+use crate::osquery::register_extension::OsqueryClient;
+use crate::osquery::transport::SharedStream;
+
+use std::fmt;
+use std::io;
+#[cfg(feature = "health-endpoint")]
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The default capacity of the buffer responses are read through, which
+/// holds most responses to queries in a few reads.
+const DEFAULT_READ_BUFFER_CAPACITY: usize = 16 * 1024;
+
+/// The default capacity of the buffer requests are written through, which
+/// holds any request short of a very large registry in one write.
+const DEFAULT_WRITE_BUFFER_CAPACITY: usize = 4 * 1024;
+
+/// A closure returning the current path of the extension manager socket.
+#[derive(Clone)]
+pub(crate) struct SocketResolver(Arc<Mutex<dyn FnMut() -> String + Send>>);
+
+impl SocketResolver {
+    fn resolve(&self) -> String {
+        (self.0.lock().unwrap())()
+    }
+}
+
+impl fmt::Debug for SocketResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SocketResolver")
+    }
+}
+
+/// A builder for an `OsqueryClient`, for when the defaults used by
+/// `OsqueryClient::new` don't match the osquery being connected to.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use osquery_rs::osquery::*;
+/// let mut client = OsqueryClient::builder("/tmp/osquery.sock")
+///     .strict(false)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct OsqueryClientBuilder {
+    /// The path of the extension manager socket.
+    pub(crate) socket_file: String,
+
+    /// Finds the path of the socket for every connection, if set.
+    pub(crate) socket_resolver: Option<SocketResolver>,
+
+    /// Whether the binary protocol uses strict message framing.
+    pub(crate) strict: bool,
+
+    /// The osquery SDK version the extension is built against.
+    pub(crate) sdk_version: String,
+
+    /// The version of the extension itself.
+    pub(crate) extension_version: String,
+
+    /// The oldest osquery SDK version the extension works with.
+    pub(crate) min_sdk_version: String,
+
+    /// Queries taking longer than this are logged as slow.
+    pub(crate) slow_query_threshold: Option<Duration>,
+
+    /// The size requested for the socket receive buffer.
+    pub(crate) recv_buffer_size: Option<usize>,
+
+    /// The size requested for the socket send buffer.
+    pub(crate) send_buffer_size: Option<usize>,
+
+    /// The uid the process on the other end of the socket must run as.
+    pub(crate) peer_uid: Option<u32>,
+
+    /// The seed for the jitter added to retry delays.
+    pub(crate) backoff_seed: Option<u64>,
+
+    /// Whether queries are logged instead of sent.
+    pub(crate) dry_run: bool,
+
+    /// Aborts the waits of the client once set.
+    pub(crate) cancel: Option<Arc<AtomicBool>>,
+
+    /// Aborts every call of the client once set.
+    pub(crate) shutdown: Option<Arc<AtomicBool>>,
+
+    /// The largest serialized registry sent when registering.
+    pub(crate) max_registry_size: Option<usize>,
+
+    /// How often the extension manager is pinged in the background.
+    pub(crate) keepalive: Option<Duration>,
+
+    /// How long a registration lasts without a call through the client.
+    pub(crate) registration_ttl: Option<Duration>,
+
+    /// Whether the keepalive also registers the extension again when
+    /// osquery dropped it.
+    pub(crate) keepalive_verify_registration: bool,
+
+    /// Whether the client logs its own messages at debug level.
+    pub(crate) quiet: bool,
+
+    /// The statements sent before the first query of every connection.
+    pub(crate) prelude: Vec<String>,
+
+    /// Whether invalid UTF-8 in strings is replaced instead of failing.
+    pub(crate) utf8_lossy: bool,
+
+    /// Whether queries fail until an extension is registered.
+    pub(crate) require_registration: bool,
+
+    /// Whether the client never registers an extension.
+    pub(crate) inspect: bool,
+
+    /// Whether registering looks for an extension with the same name first.
+    pub(crate) check_name_in_use: bool,
+
+    /// The capacity of the buffer the transport reads through.
+    pub(crate) read_buffer_capacity: usize,
+
+    /// The capacity of the buffer the transport writes through.
+    pub(crate) write_buffer_capacity: usize,
+
+    /// The address the HTTP health endpoint is bound to.
+    #[cfg(feature = "health-endpoint")]
+    pub(crate) health_address: Option<SocketAddr>,
+}
+
+impl OsqueryClientBuilder {
+    /// Returns a builder for a client connecting to the given socket file
+    /// with the default settings.
+    pub fn new(socket_file: &str) -> Self {
+        OsqueryClientBuilder {
+            socket_file: String::from(socket_file),
+            socket_resolver: None,
+            strict: true,
+            sdk_version: String::from("0.0.0"),
+            extension_version: String::from("0.0.1"),
+            min_sdk_version: String::from("0.0.0"),
+            slow_query_threshold: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            peer_uid: None,
+            backoff_seed: None,
+            dry_run: false,
+            cancel: None,
+            shutdown: None,
+            max_registry_size: None,
+            keepalive: None,
+            keepalive_verify_registration: false,
+            registration_ttl: None,
+            quiet: false,
+            prelude: Vec::new(),
+            utf8_lossy: false,
+            require_registration: false,
+            inspect: false,
+            check_name_in_use: false,
+            read_buffer_capacity: DEFAULT_READ_BUFFER_CAPACITY,
+            write_buffer_capacity: DEFAULT_WRITE_BUFFER_CAPACITY,
+            #[cfg(feature = "health-endpoint")]
+            health_address: None,
+        }
+    }
+
+    /// Sets whether the thrift binary protocol uses strict message framing,
+    /// which includes the protocol version in every message. This applies
+    /// to both the input and output protocol and has to match what the
+    /// osquery build expects. Defaults to `true`.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Sets the osquery SDK version the extension reports when registering.
+    /// This is also the version given to registry routes that don't set one
+    /// of their own, see `RegistryBuilder`. Defaults to `0.0.0`.
+    pub fn sdk_version(mut self, sdk_version: &str) -> Self {
+        self.sdk_version = String::from(sdk_version);
+        self
+    }
+
+    /// Sets the version of the extension itself, which osquery lists in the
+    /// `version` column of `osquery_extensions`. It is informational only.
+    /// Defaults to `0.0.1`.
+    pub fn extension_version(mut self, version: &str) -> Self {
+        self.extension_version = String::from(version);
+        self
+    }
+
+    /// Sets the oldest osquery SDK version the extension works with, which
+    /// osquery compares against its own version when the extension
+    /// registers. Some osquery builds reject or warn about the default of
+    /// `0.0.0`, in which case this should be set to the SDK version the
+    /// extension was tested against.
+    ///
+    /// Together with the extension name, given when registering, and
+    /// `extension_version` and `sdk_version`, this makes up the extension
+    /// info sent to osquery: the name identifies the extension, the version
+    /// is the extension's own, the SDK version is the osquery SDK it was
+    /// built against and the minimum SDK version is the oldest osquery it
+    /// supports.
+    pub fn min_sdk_version(mut self, min_sdk_version: &str) -> Self {
+        self.min_sdk_version = String::from(min_sdk_version);
+        self
+    }
+
+    /// Logs a warning with the query text and elapsed time for every query
+    /// that takes longer than `threshold`. Slow queries are not logged
+    /// unless a threshold is set.
+    pub fn slow_query_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_query_threshold = Some(threshold);
+        self
+    }
+
+    /// Requests a receive buffer of `size` bytes for the socket, with
+    /// `SO_RCVBUF`. A larger buffer cuts down on syscalls when moving big
+    /// result sets. This is best effort: the kernel may adjust the size and
+    /// a failure to set it is ignored. It has no effect on a client built
+    /// with `build_stream`.
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Requests a send buffer of `size` bytes for the socket, with
+    /// `SO_SNDBUF`. Like `recv_buffer_size`, this is best effort and has no
+    /// effect on a client built with `build_stream`.
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Requires the process on the other end of the socket to run as `uid`,
+    /// such as 0 for an osquery running as root. The credentials of the
+    /// peer are read right after connecting, with `SO_PEERCRED` on Linux and
+    /// `getpeereid` on macOS and the BSDs, and a mismatch fails the
+    /// connection with `OsqueryError::PeerUid` before anything is sent.
+    /// This keeps a socket put in place by another user from being trusted.
+    ///
+    /// On platforms without a way to read the credentials of the peer, the
+    /// check is skipped with a warning. It has no effect on a client built
+    /// with `build_stream`.
+    pub fn verify_peer_uid(mut self, uid: u32) -> Self {
+        self.peer_uid = Some(uid);
+        self
+    }
+
+    /// Seeds the random jitter added to the delays between retries, both of
+    /// `register_extension_retry` and of the keepalive and lease connecting
+    /// again, so the delays are the same on every run. By default the
+    /// jitter is seeded from the time and process id, so that clients
+    /// retrying at the same time spread out.
+    pub fn backoff_seed(mut self, seed: u64) -> Self {
+        self.backoff_seed = Some(seed);
+        self
+    }
+
+    /// Sets whether queries are only logged instead of sent to osquery. In
+    /// dry run mode `query` and the other query methods log the SQL they
+    /// would run and return an empty result without touching the socket,
+    /// which is handy to check generated SQL without a live osquery.
+    /// Every other call, such as `ping` or `register_extension`, still goes
+    /// to the extension manager. Without an osquery to connect to, build
+    /// the client with `build_stream` over any stream, such as one end of a
+    /// `UnixStream::pair`. Defaults to `false`.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Sets a closure returning the path of the extension manager socket,
+    /// for setups where the path changes across osquery restarts, such as
+    /// one including a pid. The closure is called for every connection
+    /// made from the builder, so a client built again after osquery
+    /// restarted finds the new socket, and so do the connections the
+    /// keepalive and health endpoint make again after a failure. The path
+    /// given to `new` is only used when no resolver is set.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let builder = OsqueryClient::builder("").socket_resolver(|| {
+    ///     std::fs::read_to_string("/var/run/osquery/socket_path")
+    ///         .unwrap_or_default()
+    ///         .trim()
+    ///         .to_string()
+    /// });
+    /// let mut client = builder.build().unwrap();
+    /// ```
+    pub fn socket_resolver<F>(mut self, resolver: F) -> Self
+    where
+        F: FnMut() -> String + Send + 'static,
+    {
+        self.socket_resolver = Some(SocketResolver(Arc::new(Mutex::new(resolver))));
+        self
+    }
+
+    /// Returns the path of the socket to connect to right now, from the
+    /// resolver if one is set.
+    pub(crate) fn resolve_socket_file(&self) -> String {
+        match &self.socket_resolver {
+            Some(resolver) => resolver.resolve(),
+            None => self.socket_file.clone(),
+        }
+    }
+
+    /// Sets a flag that aborts the waits of the client, such as the delays
+    /// between registration retries, once it is set. An aborted wait
+    /// returns `OsqueryError::Cancelled`, so setting the flag from a signal
+    /// handler lets a process shutting down during startup exit promptly.
+    pub fn cancel_flag(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Sets a flag, such as the global shutdown signal of a service, that
+    /// stops every call of the client once set. A call made after the flag
+    /// is set isn't sent, a call waiting on osquery is abandoned within a
+    /// tenth of a second, and the waits aborted by `cancel_flag` are aborted
+    /// as well, all returning `OsqueryError::Cancelled`. An abandoned call
+    /// leaves the client needing a reconnect.
+    ///
+    /// Deregistering is still sent, so that a client dropped on shutdown
+    /// doesn't leave its extension registered. Calls waiting on a stream
+    /// given to `build_stream` can't be abandoned, as the stream has no
+    /// timeouts to wake up on.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// # use std::sync::atomic::AtomicBool;
+    /// # use std::sync::Arc;
+    /// let shutdown = Arc::new(AtomicBool::new(false));
+    /// let mut client = OsqueryClient::builder("/tmp/osquery.sock")
+    ///     .shutdown_flag(shutdown.clone())
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn shutdown_flag(mut self, shutdown: Arc<AtomicBool>) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Sets the largest registry, in bytes once serialized, that is sent
+    /// when registering an extension. A larger registry, such as one with a
+    /// table of thousands of columns, fails the registration with
+    /// `OsqueryError::RegistryTooLarge` naming the largest plugin, instead
+    /// of failing somewhere in the transport. There is no limit by default.
+    pub fn max_registry_size(mut self, size: usize) -> Self {
+        self.max_registry_size = Some(size);
+        self
+    }
+
+    /// Pings the extension manager every `interval` from a background
+    /// thread with its own connection, logging a warning when a ping fails.
+    /// The thread is stopped and joined when the client is dropped, which
+    /// takes at most a second as every ping has a short timeout. This has
+    /// no effect on a client built with `build_stream`.
+    pub fn keepalive(mut self, interval: Duration) -> Self {
+        self.keepalive = Some(interval);
+        self
+    }
+
+    /// Sets whether the keepalive, after every successful ping, also checks
+    /// that osquery still lists the extension registered by the client, and
+    /// registers it again with the same name and routes if not. The client
+    /// picks up the new uuid at its next call. A failed registration is
+    /// logged and tried again on the next ping. This needs `keepalive` to
+    /// be set, and defaults to `false`.
+    ///
+    /// This only restores the registration itself. An extension served with
+    /// an `ExtensionServer` also has to move its socket along with the new
+    /// uuid, which `ExtensionServer::keep_registered` takes care of.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// # use std::time::Duration;
+    /// let mut client = OsqueryClient::builder("/tmp/osquery.sock")
+    ///     .keepalive(Duration::from_secs(10))
+    ///     .keepalive_verify_registration(true)
+    ///     .build()
+    ///     .unwrap();
+    /// client.register_extension("plugin_name").unwrap();
+    /// ```
+    pub fn keepalive_verify_registration(mut self, verify: bool) -> Self {
+        self.keepalive_verify_registration = verify;
+        self
+    }
+
+    /// Holds the registration of the client's extension as a lease lasting
+    /// `ttl`. Every call the client makes renews the lease, as does
+    /// `OsqueryClient::renew_registration` for an application with nothing
+    /// to ask osquery. A background thread with its own connection checks
+    /// the lease every quarter of the ttl: while it is current, the check
+    /// pings the extension manager and makes sure it still lists the
+    /// extension, logging a warning if not. Once the client has gone a
+    /// whole ttl without renewing, such as when the application hangs or
+    /// leaks the client, the extension is deregistered and the client drops
+    /// the registration at its next call, as if it had never registered.
+    ///
+    /// osquery has no leases of its own, so this only helps while the
+    /// process is alive. If it is killed, the thread dies with it and
+    /// osquery reaps the registration once the extension stops answering
+    /// its pings, as it does for any extension.
+    ///
+    /// Keepalive pings don't renew the lease, as they carry on whether or
+    /// not the application does. With `keepalive_verify_registration`, a
+    /// lease that ran out also stops the keepalive from registering the
+    /// extension again, and the registration it last made is the one
+    /// deregistered. This has no effect on a client built with
+    /// `build_stream`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// # use std::time::Duration;
+    /// let mut client = OsqueryClient::builder("/tmp/osquery.sock")
+    ///     .registration_ttl(Duration::from_secs(30))
+    ///     .build()
+    ///     .unwrap();
+    /// client.register_extension("plugin_name").unwrap();
+    /// loop {
+    ///     client.renew_registration();
+    ///     std::thread::sleep(Duration::from_secs(10));
+    /// }
+    /// ```
+    pub fn registration_ttl(mut self, ttl: Duration) -> Self {
+        self.registration_ttl = Some(ttl);
+        self
+    }
+
+    /// Sets whether the client logs its own messages, such as the one logged
+    /// when an extension is registered or a ping fails, at debug level
+    /// rather than info or warn. The client never prints to stdout or
+    /// stderr itself; its messages go through the `log` crate, so this only
+    /// quiets them for loggers filtering out debug messages. Defaults to
+    /// `false`.
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// Adds a statement, such as a `PRAGMA`, sent before the first query of
+    /// every connection, including the connections the client is rebuilt
+    /// with after a reconnect. Statements are sent in the order they were
+    /// added. osquery rejecting a statement, which it does for the pragmas
+    /// it doesn't support, is logged as a warning rather than failing the
+    /// query.
+    pub fn prelude_statement(mut self, statement: &str) -> Self {
+        self.prelude.push(String::from(statement));
+        self
+    }
+
+    /// Serves the health of the client's extension over HTTP at `address`,
+    /// for Kubernetes liveness and readiness probes. Every request is
+    /// answered with a JSON object such as
+    /// `{"healthy":true,"registered":true,"uuid":42,"ping_latency_ms":0.210}`,
+    /// with status `200 OK` when healthy and `503 Service Unavailable`
+    /// otherwise, as decided by `Health::is_healthy`. The endpoint uses a
+    /// connection of its own to the extension manager and is stopped when
+    /// the client is dropped. This has no effect on a client built with
+    /// `build_stream`.
+    #[cfg(feature = "health-endpoint")]
+    pub fn health_endpoint(mut self, address: SocketAddr) -> Self {
+        self.health_address = Some(address);
+        self
+    }
+
+    /// Sets whether strings osquery returns that aren't valid UTF-8, such as
+    /// the values of a table with the occasional binary data, have their
+    /// invalid bytes replaced with U+FFFD instead of failing the call. Every
+    /// replacement is logged as a warning naming the column. Defaults to
+    /// `false`, so that corrupted data isn't hidden. `query_bytes` is the
+    /// way to get at the bytes themselves.
+    pub fn utf8_lossy(mut self, utf8_lossy: bool) -> Self {
+        self.utf8_lossy = utf8_lossy;
+        self
+    }
+
+    /// Sets whether queries fail with `OsqueryError::NotRegistered` until
+    /// the client registered an extension, instead of being sent. This
+    /// catches querying too early for sockets that only answer queries from
+    /// registered extensions. osquery itself answers queries either way,
+    /// so this defaults to `false`.
+    pub fn require_registration(mut self, require: bool) -> Self {
+        self.require_registration = require;
+        self
+    }
+
+    /// Sets whether the client is in inspect mode, where it only runs
+    /// queries and never registers an extension. See
+    /// `OsqueryClient::inspect` for what a client can do in inspect mode.
+    /// Defaults to `false`.
+    pub fn inspect(mut self, inspect: bool) -> Self {
+        self.inspect = inspect;
+        self
+    }
+
+    /// Sets whether registering an extension first looks for one already
+    /// registered with the same name, and fails with
+    /// `OsqueryError::NameInUse` if there is. This gives a clearer error
+    /// than the status osquery rejects a duplicate name with, at the cost
+    /// of listing the extensions before every registration. Defaults to
+    /// `false`.
+    pub fn check_name_in_use(mut self, check: bool) -> Self {
+        self.check_name_in_use = check;
+        self
+    }
+
+    /// Sets the capacity of the buffer responses are read through. A larger
+    /// buffer reads large query results in fewer reads, at the cost of
+    /// memory held for the life of the client. A capacity of 0 reads
+    /// straight off the socket. Defaults to 16 KiB.
+    pub fn read_buffer_capacity(mut self, capacity: usize) -> Self {
+        self.read_buffer_capacity = capacity;
+        self
+    }
+
+    /// Sets the capacity of the buffer requests are written through. Every
+    /// request is flushed once written, so this only needs to hold the
+    /// largest request to send it in one write. Defaults to 4 KiB.
+    pub fn write_buffer_capacity(mut self, capacity: usize) -> Self {
+        self.write_buffer_capacity = capacity;
+        self
+    }
+
+    /// Returns the settings for a connection a helper of the client makes
+    /// on its own, such as the keepalive or a config watch. The helpers a
+    /// client starts along with it are left out, so that a helper doesn't
+    /// start more of them or bind the health endpoint a second time, and so
+    /// is `require_registration`, as such a connection never registers.
+    pub(crate) fn for_helper(&self) -> Self {
+        let mut builder = self.clone();
+        builder.keepalive = None;
+        builder.registration_ttl = None;
+        builder.require_registration = false;
+        #[cfg(feature = "health-endpoint")]
+        {
+            builder.health_address = None;
+        }
+        builder
+    }
+
+    /// Connects to the socket and returns the configured client.
+    pub fn build(&self) -> Result<OsqueryClient, OsqueryError> {
+        OsqueryClient::connect(self)
+    }
+
+    /// Returns the configured client talking to the extension manager over
+    /// an already connected stream instead of the socket file. Any stream
+    /// that can be read from and written to works, which covers forwarded
+    /// sockets, proxies and in-memory pipes for tests.
+    pub fn build_stream<S>(&self, stream: S) -> OsqueryClient
+    where
+        S: io::Read + io::Write + Send + 'static,
+    {
+        let stream = SharedStream::new(stream);
+        OsqueryClient::with_transport(self, stream.clone(), stream, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osquery::fake::{test_socket, FakeExtensionManager};
+
+    use std::collections::BTreeMap;
+
+    /// Pings and queries a fake manager speaking the protocol as `strict`
+    /// says, with a client set up the same way.
+    fn round_trip(name: &str, strict: bool) {
+        let mut row = BTreeMap::new();
+        row.insert(String::from("version"), String::from("5.0.1"));
+        let manager = FakeExtensionManager::new()
+            .strict(strict)
+            .query_response("SELECT version FROM osquery_info", vec![row])
+            .start(&test_socket(name))
+            .unwrap();
+
+        let mut client = OsqueryClientBuilder::new(manager.socket_path())
+            .strict(strict)
+            .build()
+            .unwrap();
+        assert!(client.ping().unwrap());
+        let rows = client.query("SELECT version FROM osquery_info").unwrap();
+        assert_eq!(rows[0]["version"], "5.0.1");
+    }
+
+    #[test]
+    fn strict_round_trip() {
+        round_trip("strict", true);
+    }
+
+    #[test]
+    fn non_strict_round_trip() {
+        round_trip("non-strict", false);
+    }
+
+    #[test]
+    fn non_strict_client_is_rejected_by_strict_server() {
+        let manager = FakeExtensionManager::new()
+            .start(&test_socket("strict-mismatch"))
+            .unwrap();
+
+        let mut client = OsqueryClientBuilder::new(manager.socket_path())
+            .strict(false)
+            .build()
+            .unwrap();
+        assert!(client.ping().is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// The type of a column as understood by osquery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColumnType {
+    Text,
+    Integer,
+    BigInt,
+    Double,
+    UnsignedBigInt,
+    Blob,
+}
+
+impl ColumnType {
+    /// Returns the name osquery uses for the column type.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ColumnType::Text => "TEXT",
+            ColumnType::Integer => "INTEGER",
+            ColumnType::BigInt => "BIGINT",
+            ColumnType::Double => "DOUBLE",
+            ColumnType::UnsignedBigInt => "UNSIGNED BIGINT",
+            ColumnType::Blob => "BLOB",
+        }
+    }
+
+    /// Returns whether values of the type are numbers.
+    pub fn is_numeric(&self) -> bool {
+        matches!(
+            self,
+            ColumnType::Integer
+                | ColumnType::BigInt
+                | ColumnType::Double
+                | ColumnType::UnsignedBigInt
+        )
+    }
+}
+
+impl fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ColumnType {
+    type Err = ParseColumnTypeError;
+
+    /// Parses the osquery name of a column type. The match is case
+    /// insensitive as osquery itself is not consistent about it.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_uppercase().as_str() {
+            "TEXT" => Ok(ColumnType::Text),
+            "INTEGER" => Ok(ColumnType::Integer),
+            "BIGINT" => Ok(ColumnType::BigInt),
+            "DOUBLE" => Ok(ColumnType::Double),
+            "UNSIGNED BIGINT" => Ok(ColumnType::UnsignedBigInt),
+            "BLOB" => Ok(ColumnType::Blob),
+            _ => Err(ParseColumnTypeError(s.to_string())),
+        }
+    }
+}
+
+/// The error returned when a string is not a known osquery column type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseColumnTypeError(String);
+
+impl fmt::Display for ParseColumnTypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown column type: {}", self.0)
+    }
+}
+
+impl Error for ParseColumnTypeError {}
+
+/// A named and typed column of an osquery table or query result.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ColumnDefinition {
+    /// The name of the column.
+    pub name: String,
+
+    /// The type of the column.
+    pub col_type: ColumnType,
+}
+
+impl ColumnDefinition {
+    /// Returns a new column definition with the given name and type.
+    pub fn new(name: &str, col_type: ColumnType) -> Self {
+        ColumnDefinition {
+            name: String::from(name),
+            col_type,
+        }
+    }
+}
+
+impl fmt::Display for ColumnDefinition {
+    /// Formats the column the way it appears in a table schema, for
+    /// example `pid BIGINT`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.name, self.col_type)
+    }
+}
+
+impl FromStr for ColumnDefinition {
+    type Err = ParseColumnTypeError;
+
+    /// Parses a column in its schema form, for example `pid BIGINT`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.find(char::is_whitespace) {
+            Some(idx) => Ok(ColumnDefinition {
+                name: String::from(&s[..idx]),
+                col_type: s[idx..].parse()?,
+            }),
+            None => Err(ParseColumnTypeError(s.to_string())),
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use crate::osquery::error::OsqueryError;
+use crate::osquery::keepalive::sleep_unless_stopped;
+use crate::osquery::register_extension::OsqueryClient;
+
+use log::warn;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const CONFIG_HASH_QUERY: &str = "SELECT config_hash FROM osquery_info";
+
+/// How long a poll of the config hash may wait on the extension manager,
+/// which also bounds how long stopping a watch can block.
+const POLL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A change of the osquery config, as seen by `OsqueryClient::watch_config`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigChange {
+    /// The hash of the config before the change. This is `None` when
+    /// osquery had not loaded a config yet.
+    pub previous: Option<String>,
+
+    /// The hash of the config after the change.
+    pub current: String,
+}
+
+/// A watch of the osquery config, returned by `OsqueryClient::watch_config`.
+/// The watch is stopped when dropped.
+pub struct ConfigWatch {
+    receiver: Receiver<ConfigChange>,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ConfigWatch {
+    /// Returns the receiving end of the changes.
+    pub fn receiver(&self) -> &Receiver<ConfigChange> {
+        &self.receiver
+    }
+
+    /// Stops the watch and waits for its thread to finish, which takes at
+    /// most the timeout of a poll.
+    pub fn stop(mut self) {
+        self.stop_thread();
+    }
+
+    fn stop_thread(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ConfigWatch {
+    fn drop(&mut self) {
+        self.stop_thread();
+    }
+}
+
+impl OsqueryClient {
+    /// Returns the hash of the config currently loaded by osquery, read from
+    /// `osquery_info.config_hash`. The hash is empty until osquery has
+    /// loaded its first config.
+    pub fn config_hash(&mut self) -> Result<String, OsqueryError> {
+        let rows = self.query(CONFIG_HASH_QUERY)?;
+        Ok(rows
+            .into_iter()
+            .next()
+            .and_then(|mut row| row.remove("config_hash"))
+            .unwrap_or_default())
+    }
+
+    /// Watches the osquery config for changes, polling its hash every
+    /// `poll`. Each change is sent to the receiver of the returned watch,
+    /// including the first config load if osquery had none when the watch
+    /// started.
+    ///
+    /// The polling runs on a thread with its own connection to the
+    /// extension manager, built with the same settings as this client but
+    /// without its keepalive, lease or health endpoint. It stops when the
+    /// watch is dropped or stopped, and every poll gives up after a second,
+    /// so that stopping doesn't wait on a stuck osquery. Failed polls are
+    /// logged and retried on the next interval, over a new connection if
+    /// the failure left the old one unusable.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// # use std::time::Duration;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// let watch = client.watch_config(Duration::from_secs(10)).unwrap();
+    /// for change in watch.receiver() {
+    ///     println!("config changed to {}", change.current);
+    /// }
+    /// ```
+    pub fn watch_config(&mut self, poll: Duration) -> Result<ConfigWatch, OsqueryError> {
+        let mut last = self.config_hash()?;
+        let builder = self.config().for_helper();
+        let mut watcher = Some(builder.build()?);
+        let (tx, rx) = mpsc::channel();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread_stopped = stopped.clone();
+
+        let thread = thread::spawn(move || {
+            while sleep_unless_stopped(poll, &thread_stopped) {
+                if watcher.is_none() {
+                    watcher = match builder.build() {
+                        Ok(watcher) => Some(watcher),
+                        Err(e) => {
+                            warn!("Config watch failed to connect: {}", e);
+                            continue;
+                        }
+                    };
+                }
+
+                let w = match watcher.as_mut() {
+                    Some(w) => w,
+                    None => continue,
+                };
+                let current = match w.with_timeout(POLL_TIMEOUT, |c| c.config_hash()) {
+                    Ok(current) => current,
+                    Err(e) => {
+                        warn!("Failed to read the config hash: {}", e);
+                        if w.needs_reconnect() {
+                            watcher = None;
+                        }
+                        continue;
+                    }
+                };
+
+                if current.is_empty() || current == last {
+                    continue;
+                }
+
+                let previous = if last.is_empty() { None } else { Some(last) };
+                last = current.clone();
+                if tx.send(ConfigChange { previous, current }).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(ConfigWatch {
+            receiver: rx,
+            stopped,
+            thread: Some(thread),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osquery::builder::OsqueryClientBuilder;
+    use crate::osquery::fake::{test_socket, FakeExtensionManager, RunningFakeExtensionManager};
+
+    use std::collections::BTreeMap;
+
+    fn hash_rows(hash: &str) -> Vec<BTreeMap<String, String>> {
+        let mut row = BTreeMap::new();
+        row.insert(String::from("config_hash"), String::from(hash));
+        vec![row]
+    }
+
+    fn config_manager(name: &str) -> RunningFakeExtensionManager {
+        FakeExtensionManager::new()
+            .query_response(CONFIG_HASH_QUERY, hash_rows("first"))
+            .start(&test_socket(name))
+            .unwrap()
+    }
+
+    /// Watches the config with `builder` and checks that a change is seen.
+    fn assert_watches(manager: &RunningFakeExtensionManager, builder: OsqueryClientBuilder) {
+        let mut client = builder.build().unwrap();
+        client.register_extension("watcher").unwrap();
+        let watch = client.watch_config(Duration::from_millis(10)).unwrap();
+
+        manager.set_query_response(CONFIG_HASH_QUERY, hash_rows("second"));
+        let change = watch
+            .receiver()
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(change.previous.as_deref(), Some("first"));
+        assert_eq!(change.current, "second");
+    }
+
+    #[test]
+    fn watch_config_leaves_out_the_helpers_of_the_client() {
+        let manager = config_manager("watch-helpers");
+        let builder = OsqueryClientBuilder::new(manager.socket_path())
+            .quiet(true)
+            .keepalive(Duration::from_secs(60))
+            .registration_ttl(Duration::from_secs(60))
+            .require_registration(true);
+        assert_watches(&manager, builder);
+    }
+
+    #[test]
+    fn dropped_watch_stops_polling() {
+        let manager = config_manager("watch-drop");
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+        let watch = client.watch_config(Duration::from_millis(10)).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        drop(watch);
+
+        // The config never changes, so only stopping the watch ends the
+        // polls.
+        let polls = manager.queries().len();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(manager.queries().len(), polls);
+    }
+
+    #[cfg(feature = "health-endpoint")]
+    #[test]
+    fn watch_config_with_a_health_endpoint() {
+        // Bind and release a port, as a port of 0 would bind again fine.
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let manager = config_manager("watch-health");
+        let builder = OsqueryClientBuilder::new(manager.socket_path())
+            .quiet(true)
+            .health_endpoint(address);
+        assert_watches(&manager, builder);
+    }
+}
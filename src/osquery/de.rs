@@ -0,0 +1,164 @@
+use crate::osquery::error::OsqueryError;
+use crate::osquery::register_extension::OsqueryClient;
+
+use serde::de::value::{Error, MapAccessDeserializer, MapDeserializer};
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+impl OsqueryClient {
+    /// Runs a query expected to return a single row, such as a set of
+    /// aggregates, and deserializes the row into `T`. See `from_row` for how
+    /// the columns are mapped to fields.
+    ///
+    /// Returns `None` when the query returns no rows and
+    /// `OsqueryError::TooManyRows` when it returns more than one.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// #[derive(serde::Deserialize)]
+    /// struct Stats {
+    ///     n: u64,
+    ///     m: i64,
+    /// }
+    ///
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// let stats: Option<Stats> = client
+    ///     .query_row_as("SELECT count(*) AS n, max(pid) AS m FROM processes")
+    ///     .unwrap();
+    /// ```
+    pub fn query_row_as<T: DeserializeOwned>(
+        &mut self,
+        query: &str,
+    ) -> Result<Option<T>, OsqueryError> {
+        let rows = self.query(query)?;
+        match rows.as_slice() {
+            [] => Ok(None),
+            [row] => from_row(row).map(Some),
+            rows => Err(OsqueryError::TooManyRows(rows.len())),
+        }
+    }
+}
+
+/// Deserializes a result row into `T`, with every column as a field.
+///
+/// osquery returns every value as a string, so values are parsed into the
+/// type of the field: numbers are parsed, booleans accept `1`, `0`, `true`
+/// and `false`, and an empty value, which is how osquery returns `NULL`,
+/// deserializes into `None` for an optional field.
+pub fn from_row<T: DeserializeOwned>(row: &BTreeMap<String, String>) -> Result<T, OsqueryError> {
+    let mut deserializer = MapDeserializer::new(
+        row.iter()
+            .map(|(column, value)| (column.as_str(), ValueDeserializer(value))),
+    );
+    let value =
+        T::deserialize(MapAccessDeserializer::new(&mut deserializer)).map_err(deserialize_error)?;
+    deserializer.end().map_err(deserialize_error)?;
+    Ok(value)
+}
+
+fn deserialize_error(e: Error) -> OsqueryError {
+    OsqueryError::Deserialize(e.to_string())
+}
+
+/// Deserializes a single value, parsing it into the type asked for.
+struct ValueDeserializer<'a>(&'a str);
+
+impl<'a> ValueDeserializer<'a> {
+    fn parse<T: FromStr>(&self) -> Result<T, Error> {
+        self.0.trim().parse().map_err(|_| {
+            de::Error::custom(format!(
+                "invalid value {:?} for {}",
+                self.0,
+                std::any::type_name::<T>()
+            ))
+        })
+    }
+}
+
+impl<'de, 'a> IntoDeserializer<'de, Error> for ValueDeserializer<'a> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                visitor.$visit(self.parse()?)
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> de::Deserializer<'de> for ValueDeserializer<'a> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_str(self.0)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0.trim() {
+            "1" | "true" => visitor.visit_bool(true),
+            "0" | "false" => visitor.visit_bool(false),
+            _ => Err(de::Error::custom(format!(
+                "invalid value {:?} for bool",
+                self.0
+            ))),
+        }
+    }
+
+    deserialize_parsed! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.0.is_empty() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_enum(self.0.into_deserializer())
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 char str string bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
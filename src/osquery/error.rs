@@ -0,0 +1,375 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// The part of talking to osquery an error happened in, see
+/// `OsqueryError::phase`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// Connecting to the extension manager socket.
+    Connect,
+
+    /// Registering an extension.
+    Register,
+
+    /// Running a query or reading something else from the extension
+    /// manager, such as the list of extensions or the flags of osquery.
+    Query,
+
+    /// Pinging the extension manager.
+    Ping,
+
+    /// Deregistering an extension.
+    Deregister,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Connect => "connect",
+            Phase::Register => "register",
+            Phase::Query => "query",
+            Phase::Ping => "ping",
+            Phase::Deregister => "deregister",
+        })
+    }
+}
+
+/// The error returned by the osquery client.
+#[derive(Debug)]
+pub enum OsqueryError {
+    /// An I/O error on the extension manager socket in the phase.
+    Io { phase: Phase, error: io::Error },
+
+    /// Connecting to the extension manager socket failed.
+    Connect(io::Error),
+
+    /// An error from the thrift transport during a call in the phase.
+    Thrift { phase: Phase, error: thrift::Error },
+
+    /// The extension manager sent a malformed or incomplete response to a
+    /// call in the phase.
+    Protocol { phase: Phase, message: String },
+
+    /// The extension manager answered a call in the phase with a
+    /// non-success status.
+    Status {
+        phase: Phase,
+        code: i32,
+        message: String,
+    },
+
+    /// A call in the phase did not complete before its deadline.
+    Timeout { phase: Phase },
+
+    /// The response doesn't belong to the call in the phase that was made,
+    /// typically because an earlier call left part of its response on the
+    /// socket. The client has to be reconnected, see
+    /// `OsqueryClient::needs_reconnect`.
+    DesyncedConnection { phase: Phase, message: String },
+
+    /// A call or wait in the phase was aborted through the cancel or
+    /// shutdown flag of the client.
+    Cancelled { phase: Phase },
+
+    /// A call in the phase needs a registered extension, and none is
+    /// registered.
+    NotRegistered { phase: Phase },
+
+    /// The call would register an extension with a client in inspect mode,
+    /// see `OsqueryClient::inspect`.
+    InspectMode,
+
+    /// An extension with the name is already registered, found by checking
+    /// before registering, see `OsqueryClientBuilder::check_name_in_use`.
+    NameInUse(String),
+
+    /// The path given as the extension manager socket is not a socket.
+    NotASocket(String),
+
+    /// The process on the other end of the socket doesn't run as the
+    /// expected uid, see `OsqueryClientBuilder::verify_peer_uid`.
+    PeerUid { expected: u32, actual: u32 },
+
+    /// The estimated size of a result, in bytes, is above the budget.
+    OverBudget { estimate: usize, budget: usize },
+
+    /// The serialized registry, in bytes, is above the configured limit.
+    /// The largest plugin is named as `<registry>/<item>`.
+    RegistryTooLarge {
+        size: usize,
+        limit: usize,
+        largest: Option<String>,
+    },
+
+    /// A query expected to return rows returned none.
+    NoRows,
+
+    /// A query expected to return at most one row returned this many.
+    TooManyRows(usize),
+
+    /// The columns of a query don't match the expected ones.
+    ColumnMismatch {
+        missing: Vec<String>,
+        unexpected: Vec<String>,
+    },
+
+    /// A row is missing a column the call needs.
+    MissingColumn(String),
+
+    /// Two rows have the same value in a column expected to be unique.
+    DuplicateKey(String),
+
+    /// A query returns more than one column with the same name.
+    DuplicateColumn(String),
+
+    /// A column doesn't have the value it was expected to have.
+    ValueMismatch {
+        column: String,
+        expected: String,
+        actual: String,
+    },
+
+    /// A row could not be deserialized into the requested type.
+    Deserialize(String),
+
+    /// A table or column name can't be quoted safely.
+    InvalidIdentifier(String),
+
+    /// An osquery flag was read as a type other than its own.
+    FlagType {
+        name: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for OsqueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OsqueryError::Io { phase, error } => {
+                write!(f, "I/O error during {}: {}", phase, error)
+            }
+            OsqueryError::Connect(e) => write!(f, "failed to connect: {}", e),
+            OsqueryError::Thrift { phase, error } => {
+                write!(f, "thrift error during {}: {}", phase, error)
+            }
+            OsqueryError::Protocol { phase, message } => {
+                write!(f, "protocol error during {}: {}", phase, message)
+            }
+            OsqueryError::Status {
+                phase,
+                code,
+                message,
+            } => write!(
+                f,
+                "osquery returned status {} during {}: {}",
+                code, phase, message
+            ),
+            OsqueryError::Timeout { phase } => write!(f, "{} deadline exceeded", phase),
+            OsqueryError::DesyncedConnection { phase, message } => {
+                write!(f, "connection out of sync during {}: {}", phase, message)
+            }
+            OsqueryError::Cancelled { phase } => write!(f, "{} cancelled", phase),
+            OsqueryError::NotRegistered { phase } => {
+                write!(f, "no extension is registered to {}", phase)
+            }
+            OsqueryError::InspectMode => {
+                write!(f, "extensions can't be registered in inspect mode")
+            }
+            OsqueryError::NameInUse(name) => {
+                write!(f, "an extension named {:?} is already registered", name)
+            }
+            OsqueryError::NotASocket(path) => write!(f, "{} is not a socket", path),
+            OsqueryError::PeerUid { expected, actual } => write!(
+                f,
+                "socket peer runs as uid {}, expected uid {}",
+                actual, expected
+            ),
+            OsqueryError::OverBudget { estimate, budget } => write!(
+                f,
+                "estimated result size of {} bytes is above the budget of {} bytes",
+                estimate, budget
+            ),
+            OsqueryError::RegistryTooLarge {
+                size,
+                limit,
+                largest,
+            } => {
+                write!(
+                    f,
+                    "registry of {} bytes is above the limit of {} bytes",
+                    size, limit
+                )?;
+                match largest {
+                    Some(largest) => write!(f, ", the largest plugin is {}", largest),
+                    None => Ok(()),
+                }
+            }
+            OsqueryError::NoRows => write!(f, "query returned no rows"),
+            OsqueryError::TooManyRows(n) => write!(f, "expected at most one row, got {}", n),
+            OsqueryError::ColumnMismatch {
+                missing,
+                unexpected,
+            } => write!(
+                f,
+                "columns don't match, missing: {:?}, unexpected: {:?}",
+                missing, unexpected
+            ),
+            OsqueryError::MissingColumn(column) => write!(f, "row is missing column {}", column),
+            OsqueryError::DuplicateKey(key) => write!(f, "duplicate key {:?}", key),
+            OsqueryError::DuplicateColumn(column) => write!(f, "duplicate column {:?}", column),
+            OsqueryError::ValueMismatch {
+                column,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "column {:?} is {:?}, expected {:?}",
+                column, actual, expected
+            ),
+            OsqueryError::Deserialize(message) => {
+                write!(f, "failed to deserialize row: {}", message)
+            }
+            OsqueryError::InvalidIdentifier(name) => {
+                write!(f, "{:?} is not a valid identifier", name)
+            }
+            OsqueryError::FlagType {
+                name,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "flag {} has type {}, expected {}",
+                name, actual, expected
+            ),
+        }
+    }
+}
+
+impl Error for OsqueryError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            OsqueryError::Io { error, .. } | OsqueryError::Connect(error) => Some(error),
+            OsqueryError::Thrift { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl OsqueryError {
+    /// Returns a function wrapping an I/O error in the phase, for `map_err`.
+    pub(crate) fn io(phase: Phase) -> impl FnOnce(io::Error) -> Self {
+        move |error| OsqueryError::Io { phase, error }
+    }
+
+    /// Returns the error for a thrift error in a call in the phase.
+    /// Malformed data surfaces as a protocol error and a response to a
+    /// different call as a desynced connection, while anything else is kept
+    /// as the thrift error.
+    pub(crate) fn from_thrift(e: thrift::Error, phase: Phase) -> Self {
+        match e {
+            thrift::Error::Protocol(e) => OsqueryError::Protocol {
+                phase,
+                message: e.message,
+            },
+            thrift::Error::Application(e) if is_desync(&e) => OsqueryError::DesyncedConnection {
+                phase,
+                message: e.message,
+            },
+            error => OsqueryError::Thrift { phase, error },
+        }
+    }
+
+    /// Returns the phase the error happened in, so that callers can tell a
+    /// failure to connect from a failure to register or to query without
+    /// matching on messages. Errors that can happen in any phase, like
+    /// `Timeout` or `Cancelled`, carry the phase they happened in, while
+    /// errors only raised in one phase, like `NameInUse` or `NoRows`, are in
+    /// that phase.
+    pub fn phase(&self) -> Phase {
+        match self {
+            OsqueryError::Io { phase, .. }
+            | OsqueryError::Thrift { phase, .. }
+            | OsqueryError::Protocol { phase, .. }
+            | OsqueryError::Status { phase, .. }
+            | OsqueryError::Timeout { phase }
+            | OsqueryError::DesyncedConnection { phase, .. }
+            | OsqueryError::Cancelled { phase }
+            | OsqueryError::NotRegistered { phase } => *phase,
+            OsqueryError::Connect(_)
+            | OsqueryError::NotASocket(_)
+            | OsqueryError::PeerUid { .. } => Phase::Connect,
+            OsqueryError::InspectMode
+            | OsqueryError::NameInUse(_)
+            | OsqueryError::RegistryTooLarge { .. } => Phase::Register,
+            OsqueryError::OverBudget { .. }
+            | OsqueryError::NoRows
+            | OsqueryError::TooManyRows(_)
+            | OsqueryError::ColumnMismatch { .. }
+            | OsqueryError::MissingColumn(_)
+            | OsqueryError::DuplicateKey(_)
+            | OsqueryError::DuplicateColumn(_)
+            | OsqueryError::ValueMismatch { .. }
+            | OsqueryError::Deserialize(_)
+            | OsqueryError::InvalidIdentifier(_)
+            | OsqueryError::FlagType { .. } => Phase::Query,
+        }
+    }
+}
+
+/// Returns whether the error is the client rejecting a response that
+/// doesn't match the call it made.
+pub(crate) fn is_desync(e: &thrift::ApplicationError) -> bool {
+    matches!(
+        e.kind,
+        thrift::ApplicationErrorKind::BadSequenceId
+            | thrift::ApplicationErrorKind::WrongMethodName
+            | thrift::ApplicationErrorKind::InvalidMessageType
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thrift_errors_keep_the_phase_of_the_call() {
+        let protocol = thrift::Error::Protocol(thrift::ProtocolError::new(
+            thrift::ProtocolErrorKind::InvalidData,
+            "bad string",
+        ));
+        assert_eq!(
+            OsqueryError::from_thrift(protocol, Phase::Register).phase(),
+            Phase::Register
+        );
+
+        let desync = thrift::Error::Application(thrift::ApplicationError::new(
+            thrift::ApplicationErrorKind::BadSequenceId,
+            "seqid mismatch",
+        ));
+        let e = OsqueryError::from_thrift(desync, Phase::Ping);
+        assert!(matches!(e, OsqueryError::DesyncedConnection { .. }));
+        assert_eq!(e.phase(), Phase::Ping);
+    }
+
+    #[test]
+    fn errors_of_any_phase_carry_it() {
+        let io = OsqueryError::io(Phase::Deregister)(io::Error::from(io::ErrorKind::BrokenPipe));
+        assert_eq!(io.phase(), Phase::Deregister);
+        assert_eq!(
+            OsqueryError::Timeout {
+                phase: Phase::Query
+            }
+            .phase(),
+            Phase::Query
+        );
+        assert_eq!(
+            OsqueryError::Cancelled {
+                phase: Phase::Register
+            }
+            .to_string(),
+            "register cancelled"
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use crate::osquery::error::{OsqueryError, Phase};
+use crate::osquery::osquery::ExtensionPluginResponse;
+use crate::osquery::register_extension::OsqueryClient;
+
+use std::collections::BTreeMap;
+use std::mem;
+
+/// The number of rows fetched to estimate the size of a row.
+const SAMPLE_ROWS: usize = 100;
+
+impl OsqueryClient {
+    /// Returns a rough estimate of the memory, in bytes, the result of the
+    /// query takes once materialized. osquery has no way to estimate a row
+    /// count, so this is a heuristic: a small sample of the rows is fetched
+    /// to measure the average row size, which is then scaled by the row
+    /// count from a `count(*)` over the query.
+    ///
+    /// Counting runs the full query inside osquery, only without sending
+    /// the rows back, so this is only worth it when the result may be much
+    /// bigger than what the process can afford.
+    ///
+    /// The query is wrapped in a subquery, on lines of its own so that a
+    /// trailing `--` comment doesn't comment out the rest.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    pub fn estimate_result_size(&mut self, query: &str) -> Result<usize, OsqueryError> {
+        let base = query.trim().trim_end_matches(';');
+        let sample = self.query(&sample_query(base))?;
+        let sample_size: usize = sample.iter().map(row_size).sum();
+        if sample.len() < SAMPLE_ROWS {
+            return Ok(sample_size);
+        }
+
+        let count = self
+            .query(&count_query(base))?
+            .into_iter()
+            .next()
+            .and_then(|row| {
+                row.get("count")
+                    .and_then(|count| count.parse::<usize>().ok())
+            })
+            .ok_or_else(|| OsqueryError::Protocol {
+                phase: Phase::Query,
+                message: String::from("missing row count"),
+            })?;
+
+        Ok(sample_size / sample.len() * count)
+    }
+
+    /// Runs the query unless its estimated result size is above `budget`
+    /// bytes, in which case `OsqueryError::OverBudget` is returned without
+    /// fetching the rows. See `estimate_result_size` for how the size is
+    /// estimated.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    /// * `budget` - The largest result size to accept, in bytes
+    pub fn query_within_budget(
+        &mut self,
+        query: &str,
+        budget: usize,
+    ) -> Result<ExtensionPluginResponse, OsqueryError> {
+        let estimate = self.estimate_result_size(query)?;
+        if estimate > budget {
+            return Err(OsqueryError::OverBudget { estimate, budget });
+        }
+        self.query(query)
+    }
+}
+
+fn sample_query(base: &str) -> String {
+    format!("SELECT * FROM (\n{}\n) LIMIT {}", base, SAMPLE_ROWS)
+}
+
+fn count_query(base: &str) -> String {
+    format!("SELECT count(*) AS count FROM (\n{}\n)", base)
+}
+
+/// Returns the approximate memory taken by a row: the bytes of its column
+/// names and values plus the string headers holding them.
+fn row_size(row: &BTreeMap<String, String>) -> usize {
+    row.iter()
+        .map(|(column, value)| column.len() + value.len() + 2 * mem::size_of::<String>())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osquery::fake::{test_socket, FakeExtensionManager};
+
+    const QUERY: &str = "SELECT v FROM t -- every row";
+
+    /// Returns `count` rows of one `v` column with a 4 byte value.
+    fn rows(count: usize) -> ExtensionPluginResponse {
+        (0..count)
+            .map(|_| {
+                let mut row = BTreeMap::new();
+                row.insert(String::from("v"), String::from("abcd"));
+                row
+            })
+            .collect()
+    }
+
+    fn count(count: usize) -> ExtensionPluginResponse {
+        let mut row = BTreeMap::new();
+        row.insert(String::from("count"), count.to_string());
+        vec![row]
+    }
+
+    fn row_bytes() -> usize {
+        row_size(&rows(1)[0])
+    }
+
+    #[test]
+    fn small_result_is_measured_without_counting() {
+        let manager = FakeExtensionManager::new()
+            .query_response(&sample_query(QUERY), rows(3))
+            .start(&test_socket("estimate-small"))
+            .unwrap();
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+
+        assert_eq!(client.estimate_result_size(QUERY).unwrap(), 3 * row_bytes());
+        assert_eq!(manager.queries(), vec![sample_query(QUERY)]);
+    }
+
+    #[test]
+    fn large_result_is_scaled_by_the_count() {
+        let manager = FakeExtensionManager::new()
+            .query_response(&sample_query(QUERY), rows(SAMPLE_ROWS))
+            .query_response(&count_query(QUERY), count(10_000))
+            .start(&test_socket("estimate-scaled"))
+            .unwrap();
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+
+        assert_eq!(
+            client.estimate_result_size(QUERY).unwrap(),
+            10_000 * row_bytes()
+        );
+    }
+
+    #[test]
+    fn query_over_budget_is_not_run() {
+        let manager = FakeExtensionManager::new()
+            .query_response(&sample_query(QUERY), rows(SAMPLE_ROWS))
+            .query_response(&count_query(QUERY), count(10_000))
+            .query_response(QUERY, rows(10_000))
+            .start(&test_socket("estimate-budget"))
+            .unwrap();
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+
+        match client.query_within_budget(QUERY, 1024) {
+            Err(OsqueryError::OverBudget { estimate, budget }) => {
+                assert_eq!((estimate, budget), (10_000 * row_bytes(), 1024));
+            }
+            res => panic!(
+                "expected an over budget error, got {:?}",
+                res.map(|r| r.len())
+            ),
+        }
+        assert!(!manager.queries().iter().any(|sql| sql == QUERY));
+
+        let rows = client.query_within_budget(QUERY, usize::MAX).unwrap();
+        assert_eq!(rows.len(), 10_000);
+    }
+}
@@ -0,0 +1,267 @@
+use crate::osquery::columns::{ColumnDefinition, ColumnType};
+use crate::osquery::osquery::{ExtensionPluginRequest, ExtensionPluginResponse};
+use crate::osquery::plugin::TablePlugin;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The column added to every evented table with the time of the event.
+const TIME_COLUMN: &str = "time";
+
+/// A source of events backing an evented table.
+///
+/// Instead of producing rows when osquery queries the table, a subscriber
+/// pushes rows into an `EventStore` as the events happen, and the table
+/// returns the events retained in the store. This follows the way osquery
+/// builds its own evented tables, such as `process_events`.
+pub trait EventSubscriber: Send + Sync {
+    /// Returns the name of the evented table.
+    fn name(&self) -> String;
+
+    /// Returns the columns of an event. A `time` column with the unix time
+    /// the event was pushed at is added to these. A `time` column of the
+    /// subscriber's own is dropped, and a `time` value in a pushed row is
+    /// replaced by the time of the event.
+    fn columns(&self) -> Vec<ColumnDefinition>;
+
+    /// Starts pushing events into the store. This is called once, when the
+    /// table is created, and is expected to return right away, for example
+    /// by handing the store to a thread watching for the events.
+    fn subscribe(&self, store: EventStore);
+}
+
+/// The retained events of an evented table, kept as a ring buffer.
+///
+/// Once the store holds its maximum number of events, every new event
+/// evicts the oldest one. Events older than the maximum age, if one is set,
+/// are dropped as well. The store is a handle, so clones share the events.
+#[derive(Debug, Clone)]
+pub struct EventStore {
+    events: Arc<Mutex<VecDeque<Event>>>,
+    max_events: usize,
+    max_age: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct Event {
+    received: Instant,
+    time: u64,
+    row: BTreeMap<String, String>,
+}
+
+impl EventStore {
+    /// Returns an empty store retaining up to `max_events` events.
+    pub fn new(max_events: usize) -> Self {
+        EventStore {
+            events: Arc::new(Mutex::new(VecDeque::new())),
+            max_events,
+            max_age: None,
+        }
+    }
+
+    /// Sets how long an event is retained for, regardless of how many
+    /// events the store holds.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Adds an event, evicting the oldest events beyond the retention.
+    pub fn push(&self, row: BTreeMap<String, String>) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        let mut events = self.events.lock().unwrap();
+        events.push_back(Event {
+            received: Instant::now(),
+            time,
+            row,
+        });
+        while events.len() > self.max_events {
+            events.pop_front();
+        }
+        self.expire(&mut events);
+    }
+
+    /// Returns the number of events retained.
+    pub fn len(&self) -> usize {
+        let mut events = self.events.lock().unwrap();
+        self.expire(&mut events);
+        events.len()
+    }
+
+    /// Returns whether no events are retained.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the retained events as rows, oldest first, each with the
+    /// `time` column set.
+    pub fn rows(&self) -> ExtensionPluginResponse {
+        let mut events = self.events.lock().unwrap();
+        self.expire(&mut events);
+        events
+            .iter()
+            .map(|event| {
+                let mut row = event.row.clone();
+                row.insert(String::from(TIME_COLUMN), event.time.to_string());
+                row
+            })
+            .collect()
+    }
+
+    fn expire(&self, events: &mut VecDeque<Event>) {
+        if let Some(max_age) = self.max_age {
+            while let Some(event) = events.front() {
+                if event.received.elapsed() <= max_age {
+                    break;
+                }
+                events.pop_front();
+            }
+        }
+    }
+}
+
+/// A table plugin serving the events pushed by an `EventSubscriber`.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use osquery_rs::osquery::events::*;
+/// # use osquery_rs::osquery::plugin::Plugins;
+/// # use std::time::Duration;
+/// # fn run<S: EventSubscriber + 'static>(subscriber: S) {
+/// let store = EventStore::new(10_000).max_age(Duration::from_secs(3600));
+/// let plugins = Plugins::new().add_table(EventTable::new(subscriber, store));
+/// # }
+/// ```
+pub struct EventTable<S: EventSubscriber> {
+    subscriber: S,
+    store: EventStore,
+}
+
+impl<S: EventSubscriber> EventTable<S> {
+    /// Returns a table retaining the events of the subscriber in `store`,
+    /// and subscribes to the events.
+    pub fn new(subscriber: S, store: EventStore) -> Self {
+        subscriber.subscribe(store.clone());
+        EventTable { subscriber, store }
+    }
+
+    /// Returns the store holding the events of the table.
+    pub fn store(&self) -> &EventStore {
+        &self.store
+    }
+}
+
+impl<S: EventSubscriber> TablePlugin for EventTable<S> {
+    fn name(&self) -> String {
+        self.subscriber.name()
+    }
+
+    fn columns(&self) -> Vec<ColumnDefinition> {
+        let mut columns = self.subscriber.columns();
+        columns.retain(|column| column.name != TIME_COLUMN);
+        columns.push(ColumnDefinition::new(TIME_COLUMN, ColumnType::BigInt));
+        columns
+    }
+
+    fn generate(
+        &self,
+        _request: &ExtensionPluginRequest,
+    ) -> Result<ExtensionPluginResponse, String> {
+        Ok(self.store.rows())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn event(id: u32) -> BTreeMap<String, String> {
+        BTreeMap::from([(String::from("id"), id.to_string())])
+    }
+
+    fn ids(store: &EventStore) -> Vec<String> {
+        store
+            .rows()
+            .into_iter()
+            .map(|row| row["id"].clone())
+            .collect()
+    }
+
+    struct Subscriber;
+
+    impl EventSubscriber for Subscriber {
+        fn name(&self) -> String {
+            String::from("test_events")
+        }
+
+        fn columns(&self) -> Vec<ColumnDefinition> {
+            vec![
+                ColumnDefinition::new("id", ColumnType::Integer),
+                ColumnDefinition::new(TIME_COLUMN, ColumnType::Text),
+            ]
+        }
+
+        fn subscribe(&self, store: EventStore) {
+            let mut row = event(1);
+            row.insert(String::from(TIME_COLUMN), String::from("yesterday"));
+            store.push(row);
+        }
+    }
+
+    #[test]
+    fn full_store_evicts_the_oldest_events() {
+        let store = EventStore::new(3);
+        for id in 0..5 {
+            store.push(event(id));
+        }
+        assert_eq!(store.len(), 3);
+        assert_eq!(ids(&store), vec!["2", "3", "4"]);
+    }
+
+    #[test]
+    fn store_without_room_retains_nothing() {
+        let store = EventStore::new(0);
+        store.push(event(1));
+        assert!(store.is_empty());
+        assert!(store.rows().is_empty());
+    }
+
+    #[test]
+    fn old_events_expire() {
+        let store = EventStore::new(10).max_age(Duration::from_millis(100));
+        store.push(event(1));
+        thread::sleep(Duration::from_millis(200));
+        store.push(event(2));
+        assert_eq!(ids(&store), vec!["2"]);
+        thread::sleep(Duration::from_millis(200));
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn time_column_belongs_to_the_table() {
+        let table = EventTable::new(Subscriber, EventStore::new(10));
+        let columns: Vec<(String, ColumnType)> = table
+            .columns()
+            .into_iter()
+            .map(|column| (column.name, column.col_type))
+            .collect();
+        assert_eq!(
+            columns,
+            vec![
+                (String::from("id"), ColumnType::Integer),
+                (String::from(TIME_COLUMN), ColumnType::BigInt),
+            ]
+        );
+
+        let rows = table.generate(&ExtensionPluginRequest::new()).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert!(rows[0][TIME_COLUMN].parse::<u64>().is_ok());
+    }
+}
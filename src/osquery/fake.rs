@@ -0,0 +1,551 @@
+use crate::osquery::osquery::*;
+use crate::osquery::server::serve;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use thrift::protocol::{
+    TFieldIdentifier, TInputProtocol, TListIdentifier, TMapIdentifier, TMessageIdentifier,
+    TOutputProtocol, TSetIdentifier, TStructIdentifier,
+};
+use thrift::server::TProcessor;
+
+/// The private use chars standing for the bytes from 0x80 up in the values
+/// set with `query_response_bytes`, one char per byte.
+const RAW_BYTE_BASE: u32 = 0xf700;
+
+/// An in-process stand-in for the osquery extension manager, answering on
+/// a unix socket with canned data. This lets code using an `OsqueryClient`
+/// be tested without osquery.
+///
+/// Registrations are accepted and listed by `extensions`, with uuids
+/// handed out from 1 up. Queries are answered with the rows set for them
+/// with `query_response`, and any other query fails with a status of
+/// `ExtFailed`. The columns of a query are the columns of its first canned
+/// row, unless set with `query_columns`.
+///
+/// Values set with `query_response_bytes` are sent as the raw bytes given,
+/// which need not be valid UTF-8. They are held as strings with every byte
+/// from 0x80 up standing in for a char from U+F780 to U+F7FF, so other
+/// canned strings can't use those chars.
+///
+/// The fake speaks the strict binary protocol, as osquery does, unless set
+/// otherwise with `strict`. The server stops and removes its socket when
+/// dropped.
+///
+/// # Examples
+///
+/// ```
+/// # use osquery_rs::osquery::*;
+/// # use osquery_rs::osquery::fake::FakeExtensionManager;
+/// # use std::collections::BTreeMap;
+/// let mut row = BTreeMap::new();
+/// row.insert(String::from("version"), String::from("5.0.1"));
+/// let socket = std::env::temp_dir().join(format!("fake-{}.sock", std::process::id()));
+/// let manager = FakeExtensionManager::new()
+///     .query_response("SELECT version FROM osquery_info", vec![row])
+///     .start(socket.to_str().unwrap())
+///     .unwrap();
+///
+/// let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+/// let rows = client.query("SELECT version FROM osquery_info").unwrap();
+/// assert_eq!(rows[0]["version"], "5.0.1");
+/// assert_eq!(manager.queries(), vec!["SELECT version FROM osquery_info"]);
+/// ```
+#[derive(Clone)]
+pub struct FakeExtensionManager {
+    state: Arc<Mutex<FakeState>>,
+    strict: bool,
+}
+
+#[derive(Default)]
+struct FakeState {
+    responses: BTreeMap<String, ExtensionPluginResponse>,
+    replies: BTreeMap<String, ExtensionResponse>,
+    columns: BTreeMap<String, Vec<(String, String)>>,
+    options: InternalOptionList,
+    extensions: InternalExtensionList,
+    registries: BTreeMap<ExtensionRouteUUID, ExtensionRegistry>,
+    next_uuid: ExtensionRouteUUID,
+    queries: Vec<String>,
+}
+
+/// A running `FakeExtensionManager`.
+pub struct RunningFakeExtensionManager {
+    manager: FakeExtensionManager,
+    socket_path: String,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Default for FakeExtensionManager {
+    fn default() -> Self {
+        FakeExtensionManager {
+            state: Arc::default(),
+            strict: true,
+        }
+    }
+}
+
+impl FakeExtensionManager {
+    /// Returns a fake extension manager without canned data.
+    pub fn new() -> Self {
+        FakeExtensionManager::default()
+    }
+
+    /// Sets whether the fake expects and sends strict binary protocol
+    /// messages, see `OsqueryClientBuilder::strict`. Defaults to `true`.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Answers `sql` with `rows`.
+    pub fn query_response(self, sql: &str, rows: ExtensionPluginResponse) -> Self {
+        self.state
+            .lock()
+            .unwrap()
+            .responses
+            .insert(String::from(sql), rows);
+        self
+    }
+
+    /// Answers `sql` with `rows` whose values are raw bytes, such as values
+    /// that aren't valid UTF-8 or that hold NUL bytes, for testing
+    /// `OsqueryClient::query_bytes`.
+    pub fn query_response_bytes(self, sql: &str, rows: Vec<BTreeMap<String, Vec<u8>>>) -> Self {
+        let rows = rows
+            .into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .map(|(column, value)| (column, escape_raw_bytes(&value)))
+                    .collect()
+            })
+            .collect();
+        self.query_response(sql, rows)
+    }
+
+    /// Answers `sql` with `reply` as is, status and all, for replies
+    /// `query_response` can't make, such as a failure or a reply without
+    /// the list of rows.
+    pub fn query_reply(self, sql: &str, reply: ExtensionResponse) -> Self {
+        self.state
+            .lock()
+            .unwrap()
+            .replies
+            .insert(String::from(sql), reply);
+        self
+    }
+
+    /// Answers asking for the columns of `sql` with `columns`, as pairs of
+    /// column name and type.
+    pub fn query_columns(self, sql: &str, columns: &[(&str, &str)]) -> Self {
+        let columns = columns
+            .iter()
+            .map(|(name, type_)| (String::from(*name), String::from(*type_)))
+            .collect();
+        self.state
+            .lock()
+            .unwrap()
+            .columns
+            .insert(String::from(sql), columns);
+        self
+    }
+
+    /// Lists the flag `name` among the options, with the value and type.
+    pub fn option(self, name: &str, value: &str, type_: &str) -> Self {
+        let option = InternalOptionInfo::new(
+            String::from(value),
+            String::from(value),
+            String::from(type_),
+        );
+        self.state
+            .lock()
+            .unwrap()
+            .options
+            .insert(String::from(name), option);
+        self
+    }
+
+    /// Starts answering on a new socket at `socket_path`, replacing any file
+    /// already there.
+    pub fn start(self, socket_path: &str) -> io::Result<RunningFakeExtensionManager> {
+        let _ = fs::remove_file(socket_path);
+        let listener = UnixListener::bind(socket_path)?;
+
+        let processor = Arc::new(RawBytesProcessor(ExtensionManagerSyncProcessor::new(
+            self.clone(),
+        )));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let accept_stopped = stopped.clone();
+        let strict = self.strict;
+        let thread = thread::spawn(move || serve(listener, processor, accept_stopped, strict));
+
+        Ok(RunningFakeExtensionManager {
+            manager: self,
+            socket_path: String::from(socket_path),
+            stopped,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl RunningFakeExtensionManager {
+    /// Returns the path of the socket to connect clients to.
+    pub fn socket_path(&self) -> &str {
+        &self.socket_path
+    }
+
+    /// Answers `sql` with `rows` from now on, as osquery would once the data
+    /// behind a query changed.
+    pub fn set_query_response(&self, sql: &str, rows: ExtensionPluginResponse) {
+        self.manager
+            .state
+            .lock()
+            .unwrap()
+            .responses
+            .insert(String::from(sql), rows);
+    }
+
+    /// Returns the queries received so far, in order.
+    pub fn queries(&self) -> Vec<String> {
+        self.manager.state.lock().unwrap().queries.clone()
+    }
+
+    /// Returns the extensions currently registered, keyed by uuid.
+    pub fn extensions(&self) -> InternalExtensionList {
+        self.manager.state.lock().unwrap().extensions.clone()
+    }
+
+    /// Returns the registry the extension with the uuid registered with.
+    pub fn registry(&self, uuid: ExtensionRouteUUID) -> Option<ExtensionRegistry> {
+        self.manager
+            .state
+            .lock()
+            .unwrap()
+            .registries
+            .get(&uuid)
+            .cloned()
+    }
+
+    /// Drops the registration of the extension with the uuid, as osquery
+    /// does when an extension stops answering.
+    pub fn expire(&self, uuid: ExtensionRouteUUID) {
+        let mut state = self.manager.state.lock().unwrap();
+        state.extensions.remove(&uuid);
+        state.registries.remove(&uuid);
+    }
+}
+
+impl Drop for RunningFakeExtensionManager {
+    fn drop(&mut self) {
+        // The accept loop only checks the flag between connections, so
+        // wake it up with a connection of our own.
+        self.stopped.store(true, Ordering::SeqCst);
+        let _ = UnixStream::connect(&self.socket_path);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = fs::remove_file(&self.socket_path);
+    }
+}
+
+impl ExtensionSyncHandler for FakeExtensionManager {
+    fn handle_ping(&self) -> thrift::Result<ExtensionStatus> {
+        Ok(status(ExtensionCode::ExtSuccess, "OK", None))
+    }
+
+    fn handle_call(
+        &self,
+        registry: String,
+        item: String,
+        _request: ExtensionPluginRequest,
+    ) -> thrift::Result<ExtensionResponse> {
+        Ok(ExtensionResponse::new(
+            status(
+                ExtensionCode::ExtFailed,
+                &format!("No plugin {} in registry {}", item, registry),
+                None,
+            ),
+            ExtensionPluginResponse::new(),
+        ))
+    }
+
+    fn handle_shutdown(&self) -> thrift::Result<()> {
+        Ok(())
+    }
+}
+
+impl ExtensionManagerSyncHandler for FakeExtensionManager {
+    fn handle_extensions(&self) -> thrift::Result<InternalExtensionList> {
+        Ok(self.state.lock().unwrap().extensions.clone())
+    }
+
+    fn handle_options(&self) -> thrift::Result<InternalOptionList> {
+        Ok(self.state.lock().unwrap().options.clone())
+    }
+
+    fn handle_register_extension(
+        &self,
+        info: InternalExtensionInfo,
+        registry: ExtensionRegistry,
+    ) -> thrift::Result<ExtensionStatus> {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .extensions
+            .values()
+            .any(|extension| extension.name == info.name)
+        {
+            return Ok(status(
+                ExtensionCode::ExtFailed,
+                "Duplicate extension registered",
+                None,
+            ));
+        }
+
+        state.next_uuid += 1;
+        let uuid = state.next_uuid;
+        state.extensions.insert(uuid, info);
+        state.registries.insert(uuid, registry);
+        Ok(status(ExtensionCode::ExtSuccess, "OK", Some(uuid)))
+    }
+
+    fn handle_deregister_extension(
+        &self,
+        uuid: ExtensionRouteUUID,
+    ) -> thrift::Result<ExtensionStatus> {
+        let mut state = self.state.lock().unwrap();
+        if state.extensions.remove(&uuid).is_none() {
+            return Ok(status(
+                ExtensionCode::ExtFailed,
+                "No extension UUID registered",
+                None,
+            ));
+        }
+        state.registries.remove(&uuid);
+        Ok(status(ExtensionCode::ExtSuccess, "OK", None))
+    }
+
+    fn handle_query(&self, sql: String) -> thrift::Result<ExtensionResponse> {
+        let mut state = self.state.lock().unwrap();
+        state.queries.push(sql.clone());
+        if let Some(reply) = state.replies.get(&sql) {
+            return Ok(reply.clone());
+        }
+        Ok(match state.responses.get(&sql) {
+            Some(rows) => {
+                ExtensionResponse::new(status(ExtensionCode::ExtSuccess, "OK", None), rows.clone())
+            }
+            None => ExtensionResponse::new(
+                status(
+                    ExtensionCode::ExtFailed,
+                    &format!("No canned response for: {}", sql),
+                    None,
+                ),
+                ExtensionPluginResponse::new(),
+            ),
+        })
+    }
+
+    fn handle_get_query_columns(&self, sql: String) -> thrift::Result<ExtensionResponse> {
+        let state = self.state.lock().unwrap();
+        let columns = match (state.columns.get(&sql), state.responses.get(&sql)) {
+            (Some(columns), _) => columns.clone(),
+            (None, Some(rows)) => rows
+                .first()
+                .map(|row| {
+                    row.keys()
+                        .map(|name| (name.clone(), String::from("TEXT")))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            (None, None) => {
+                return Ok(ExtensionResponse::new(
+                    status(
+                        ExtensionCode::ExtFailed,
+                        &format!("No canned columns for: {}", sql),
+                        None,
+                    ),
+                    ExtensionPluginResponse::new(),
+                ))
+            }
+        };
+
+        let rows: ExtensionPluginResponse = columns
+            .into_iter()
+            .map(|(name, type_)| {
+                let mut row = BTreeMap::new();
+                row.insert(name, type_);
+                row
+            })
+            .collect();
+        Ok(ExtensionResponse::new(
+            status(ExtensionCode::ExtSuccess, "OK", None),
+            rows,
+        ))
+    }
+}
+
+/// Serves calls with a processor whose output goes through
+/// `RawBytesOutputProtocol`.
+struct RawBytesProcessor<P>(P);
+
+impl<P: TProcessor> TProcessor for RawBytesProcessor<P> {
+    fn process(
+        &self,
+        i: &mut dyn TInputProtocol,
+        o: &mut dyn TOutputProtocol,
+    ) -> thrift::Result<()> {
+        self.0.process(i, &mut RawBytesOutputProtocol(o))
+    }
+}
+
+/// An output protocol writing the escaped bytes of `escape_raw_bytes` as the
+/// bytes they stand for, and everything else as it is.
+struct RawBytesOutputProtocol<'a>(&'a mut dyn TOutputProtocol);
+
+impl TOutputProtocol for RawBytesOutputProtocol<'_> {
+    fn write_message_begin(&mut self, identifier: &TMessageIdentifier) -> thrift::Result<()> {
+        self.0.write_message_begin(identifier)
+    }
+
+    fn write_message_end(&mut self) -> thrift::Result<()> {
+        self.0.write_message_end()
+    }
+
+    fn write_struct_begin(&mut self, identifier: &TStructIdentifier) -> thrift::Result<()> {
+        self.0.write_struct_begin(identifier)
+    }
+
+    fn write_struct_end(&mut self) -> thrift::Result<()> {
+        self.0.write_struct_end()
+    }
+
+    fn write_field_begin(&mut self, identifier: &TFieldIdentifier) -> thrift::Result<()> {
+        self.0.write_field_begin(identifier)
+    }
+
+    fn write_field_end(&mut self) -> thrift::Result<()> {
+        self.0.write_field_end()
+    }
+
+    fn write_field_stop(&mut self) -> thrift::Result<()> {
+        self.0.write_field_stop()
+    }
+
+    fn write_bool(&mut self, b: bool) -> thrift::Result<()> {
+        self.0.write_bool(b)
+    }
+
+    fn write_bytes(&mut self, b: &[u8]) -> thrift::Result<()> {
+        self.0.write_bytes(b)
+    }
+
+    fn write_i8(&mut self, i: i8) -> thrift::Result<()> {
+        self.0.write_i8(i)
+    }
+
+    fn write_i16(&mut self, i: i16) -> thrift::Result<()> {
+        self.0.write_i16(i)
+    }
+
+    fn write_i32(&mut self, i: i32) -> thrift::Result<()> {
+        self.0.write_i32(i)
+    }
+
+    fn write_i64(&mut self, i: i64) -> thrift::Result<()> {
+        self.0.write_i64(i)
+    }
+
+    fn write_double(&mut self, d: f64) -> thrift::Result<()> {
+        self.0.write_double(d)
+    }
+
+    fn write_string(&mut self, s: &str) -> thrift::Result<()> {
+        match unescape_raw_bytes(s) {
+            Some(bytes) => self.0.write_bytes(&bytes),
+            None => self.0.write_string(s),
+        }
+    }
+
+    fn write_list_begin(&mut self, identifier: &TListIdentifier) -> thrift::Result<()> {
+        self.0.write_list_begin(identifier)
+    }
+
+    fn write_list_end(&mut self) -> thrift::Result<()> {
+        self.0.write_list_end()
+    }
+
+    fn write_set_begin(&mut self, identifier: &TSetIdentifier) -> thrift::Result<()> {
+        self.0.write_set_begin(identifier)
+    }
+
+    fn write_set_end(&mut self) -> thrift::Result<()> {
+        self.0.write_set_end()
+    }
+
+    fn write_map_begin(&mut self, identifier: &TMapIdentifier) -> thrift::Result<()> {
+        self.0.write_map_begin(identifier)
+    }
+
+    fn write_map_end(&mut self) -> thrift::Result<()> {
+        self.0.write_map_end()
+    }
+
+    fn flush(&mut self) -> thrift::Result<()> {
+        self.0.flush()
+    }
+
+    fn write_byte(&mut self, b: u8) -> thrift::Result<()> {
+        self.0.write_byte(b)
+    }
+}
+
+/// Holds raw bytes in a string, with the bytes from 0x80 up standing in
+/// for the chars from `RAW_BYTE_BASE` on.
+fn escape_raw_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| match *b {
+            b if b < 0x80 => char::from(b),
+            b => char::from_u32(RAW_BYTE_BASE + u32::from(b)).unwrap(),
+        })
+        .collect()
+}
+
+/// Returns the bytes a string from `escape_raw_bytes` holds, or `None` if
+/// the string holds no escaped bytes.
+fn unescape_raw_bytes(s: &str) -> Option<Vec<u8>> {
+    let is_raw = |c: char| (RAW_BYTE_BASE + 0x80..=RAW_BYTE_BASE + 0xff).contains(&(c as u32));
+    if !s.chars().any(is_raw) {
+        return None;
+    }
+
+    let mut bytes = Vec::with_capacity(s.len());
+    for c in s.chars() {
+        if is_raw(c) {
+            bytes.push((c as u32 - RAW_BYTE_BASE) as u8);
+        } else {
+            let mut buf = [0u8; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+        }
+    }
+    Some(bytes)
+}
+
+fn status(code: ExtensionCode, message: &str, uuid: Option<ExtensionRouteUUID>) -> ExtensionStatus {
+    ExtensionStatus::new(code as i32, String::from(message), uuid)
+}
+
+/// Returns a path for the socket of a test, unique to the process and name.
+#[cfg(test)]
+pub(crate) fn test_socket(name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("osquery-rs-{}-{}.sock", std::process::id(), name))
+        .to_string_lossy()
+        .into_owned()
+}
@@ -0,0 +1,242 @@
+use crate::osquery::error::{OsqueryError, Phase};
+use crate::osquery::register_extension::OsqueryClient;
+
+/// The flag types that hold integers.
+const INT_TYPES: &[&str] = &["int32", "int64", "uint32", "uint64"];
+
+const FLAGS_QUERY: &str = "SELECT * FROM osquery_flags";
+
+/// A flag osquery is running with, as listed by the `osquery_flags` table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OsqueryFlag {
+    pub name: String,
+
+    /// The type of the flag, such as `bool`, `int32` or `string`.
+    pub type_: String,
+    pub description: String,
+    pub default_value: String,
+    pub value: String,
+
+    /// Whether the flag only applies to the osquery shell.
+    pub shell_only: bool,
+}
+
+impl OsqueryFlag {
+    /// Returns the value of a boolean flag, which osquery lists as `true` or
+    /// `false`, or `None` for a flag of another type.
+    pub fn bool_value(&self) -> Option<bool> {
+        if self.type_ != "bool" {
+            return None;
+        }
+        parse_bool(&self.value)
+    }
+
+    /// Returns the value of an integer flag, or `None` for a flag of another
+    /// type, or for a `uint64` flag set above `i64::MAX`.
+    pub fn int_value(&self) -> Option<i64> {
+        if !INT_TYPES.contains(&self.type_.as_str()) {
+            return None;
+        }
+        self.value.parse().ok()
+    }
+
+    /// Returns whether the flag is set to its default value.
+    pub fn is_default(&self) -> bool {
+        self.value == self.default_value
+    }
+}
+
+impl OsqueryClient {
+    /// Returns every flag osquery is running with, read from the
+    /// `osquery_flags` table. This lists the description and default value
+    /// of every flag, which `options` doesn't.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// for flag in client.flags().unwrap() {
+    ///     if !flag.is_default() {
+    ///         println!("--{}={}", flag.name, flag.value);
+    ///     }
+    /// }
+    /// ```
+    pub fn flags(&mut self) -> Result<Vec<OsqueryFlag>, OsqueryError> {
+        Ok(self
+            .query(FLAGS_QUERY)?
+            .into_iter()
+            .map(|mut row| {
+                let mut column = |name: &str| row.remove(name).unwrap_or_default();
+                OsqueryFlag {
+                    name: column("name"),
+                    type_: column("type"),
+                    description: column("description"),
+                    default_value: column("default_value"),
+                    value: column("value"),
+                    shell_only: parse_bool(&column("shell_only")).unwrap_or(false),
+                }
+            })
+            .collect())
+    }
+
+    /// Returns the value of a boolean osquery flag, or `None` if osquery
+    /// has no flag with the name. A flag of another type is returned as
+    /// `OsqueryError::FlagType`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// if client.get_flag_bool("disable_events").unwrap() == Some(false) {
+    ///     println!("events are enabled");
+    /// }
+    /// ```
+    pub fn get_flag_bool(&mut self, name: &str) -> Result<Option<bool>, OsqueryError> {
+        self.get_flag(name, &["bool"], parse_bool)
+    }
+
+    /// Returns the value of an integer osquery flag, or `None` if osquery
+    /// has no flag with the name. A flag of another type is returned as
+    /// `OsqueryError::FlagType`. The value is read as an `i64`, so a
+    /// `uint64` flag set above `i64::MAX` is returned as
+    /// `OsqueryError::Protocol`.
+    pub fn get_flag_int(&mut self, name: &str) -> Result<Option<i64>, OsqueryError> {
+        self.get_flag(name, INT_TYPES, |value| value.parse().ok())
+    }
+
+    /// Returns the value of a string osquery flag, or `None` if osquery has
+    /// no flag with the name. A flag of another type is returned as
+    /// `OsqueryError::FlagType`.
+    pub fn get_flag_string(&mut self, name: &str) -> Result<Option<String>, OsqueryError> {
+        self.get_flag(name, &["string"], |value| Some(String::from(value)))
+    }
+
+    /// Returns whether osquery asks the distributed plugin named `plugin`
+    /// for queries, which it only does with distributed queries enabled
+    /// and the plugin picked as `--distributed_plugin`. A distributed
+    /// plugin is never called otherwise.
+    pub fn distributed_enabled(&mut self, plugin: &str) -> Result<bool, OsqueryError> {
+        if self.get_flag_bool("disable_distributed")?.unwrap_or(true) {
+            return Ok(false);
+        }
+        Ok(self.get_flag_string("distributed_plugin")?.as_deref() == Some(plugin))
+    }
+
+    /// Looks up the flag and parses its value with `parse`, provided its
+    /// type is one of `types`.
+    fn get_flag<T>(
+        &mut self,
+        name: &str,
+        types: &[&str],
+        parse: impl Fn(&str) -> Option<T>,
+    ) -> Result<Option<T>, OsqueryError> {
+        let option = match self.options()?.remove(name) {
+            Some(option) => option,
+            None => return Ok(None),
+        };
+
+        let actual = option.type_.unwrap_or_default();
+        if !types.contains(&actual.as_str()) {
+            return Err(OsqueryError::FlagType {
+                name: String::from(name),
+                expected: types.join(" or "),
+                actual,
+            });
+        }
+
+        let value = option.value.unwrap_or_default();
+        parse(&value)
+            .map(Some)
+            .ok_or_else(|| OsqueryError::Protocol {
+                phase: Phase::Query,
+                message: format!(
+                    "flag {} of type {} has the invalid value {:?}",
+                    name, actual, value
+                ),
+            })
+    }
+}
+
+/// Parses a boolean as osquery lists it, either as `true` and `false` or as
+/// `1` and `0`.
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" | "1" => Some(true),
+        "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osquery::fake::{test_socket, FakeExtensionManager, RunningFakeExtensionManager};
+
+    fn flags_manager(name: &str) -> RunningFakeExtensionManager {
+        FakeExtensionManager::new()
+            .option("disable_events", "false", "bool")
+            .option("verbose", "1", "bool")
+            .option("broken", "maybe", "bool")
+            .option("worker_threads", "4", "int32")
+            .option("huge", "18446744073709551615", "uint64")
+            .option("logger_plugin", "filesystem", "string")
+            .start(&test_socket(name))
+            .unwrap()
+    }
+
+    #[test]
+    fn missing_flag_is_none() {
+        let manager = flags_manager("flags-missing");
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+        assert_eq!(client.get_flag_bool("no_such_flag").unwrap(), None);
+        assert_eq!(client.get_flag_int("no_such_flag").unwrap(), None);
+        assert_eq!(client.get_flag_string("no_such_flag").unwrap(), None);
+    }
+
+    #[test]
+    fn flag_of_another_type_is_rejected() {
+        let manager = flags_manager("flags-type");
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+        match client.get_flag_int("logger_plugin") {
+            Err(OsqueryError::FlagType {
+                name,
+                expected,
+                actual,
+            }) => {
+                assert_eq!(name, "logger_plugin");
+                assert_eq!(expected, INT_TYPES.join(" or "));
+                assert_eq!(actual, "string");
+            }
+            res => panic!("expected a flag type error, got {:?}", res),
+        }
+        assert_eq!(client.get_flag_int("worker_threads").unwrap(), Some(4));
+        assert_eq!(
+            client.get_flag_string("logger_plugin").unwrap().as_deref(),
+            Some("filesystem")
+        );
+    }
+
+    #[test]
+    fn bool_flags_accept_what_the_flag_list_accepts() {
+        let manager = flags_manager("flags-bool");
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+        assert_eq!(client.get_flag_bool("disable_events").unwrap(), Some(false));
+        assert_eq!(client.get_flag_bool("verbose").unwrap(), Some(true));
+        match client.get_flag_bool("broken") {
+            Err(OsqueryError::Protocol { .. }) => {}
+            res => panic!("expected a protocol error, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn uint64_above_i64_is_a_protocol_error() {
+        let manager = flags_manager("flags-uint64");
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+        match client.get_flag_int("huge") {
+            Err(OsqueryError::Protocol { .. }) => {}
+            res => panic!("expected a protocol error, got {:?}", res),
+        }
+    }
+}
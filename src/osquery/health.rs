@@ -0,0 +1,113 @@
+use crate::osquery::builder::OsqueryClientBuilder;
+use crate::osquery::error::OsqueryError;
+use crate::osquery::osquery::ExtensionRouteUUID;
+use crate::osquery::register_extension::{ConnectionState, OsqueryClient};
+
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The health of a client's connection to the extension manager.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Health {
+    /// The uuid of the extension registered by the client, if any.
+    pub uuid: Option<ExtensionRouteUUID>,
+
+    /// Whether the extension manager still lists the registered extension.
+    /// Always `false` when no extension is registered.
+    pub registered: bool,
+
+    /// How long the extension manager took to answer a ping.
+    pub ping_latency: Duration,
+}
+
+impl Health {
+    /// Returns whether the connection is healthy, which is the case unless
+    /// an extension was registered but the extension manager dropped it.
+    pub fn is_healthy(&self) -> bool {
+        self.uuid.is_none() || self.registered
+    }
+}
+
+impl OsqueryClient {
+    /// Pings the extension manager and checks that it still lists the
+    /// registered extension, if any. A failed ping is returned as an error.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// client.register_extension("plugin_name").unwrap();
+    /// let health = client.health().unwrap();
+    /// println!("healthy: {}, ping: {:?}", health.is_healthy(), health.ping_latency);
+    /// ```
+    pub fn health(&mut self) -> Result<Health, OsqueryError> {
+        let uuid = match self.connection_state() {
+            ConnectionState::Registered { uuid } => uuid,
+            _ => 0,
+        };
+        self.health_of(uuid)
+    }
+
+    /// Returns the health of the extension registered as `uuid`, or of the
+    /// connection alone if `uuid` is 0.
+    pub(crate) fn health_of(&mut self, uuid: ExtensionRouteUUID) -> Result<Health, OsqueryError> {
+        let started = Instant::now();
+        self.ping()?;
+        let ping_latency = started.elapsed();
+
+        let registered = uuid != 0 && self.extensions()?.contains_key(&uuid);
+        Ok(Health {
+            uuid: if uuid == 0 { None } else { Some(uuid) },
+            registered,
+            ping_latency,
+        })
+    }
+}
+
+/// Returns whether an osquery extension manager answers a ping on
+/// `socket_file` within `timeout`, for gating readiness on osquery without
+/// building a client. Nothing is registered and the connection is closed
+/// right after the ping.
+///
+/// The timeout covers both connecting and the ping. Connecting to a unix
+/// socket can block while the listener's backlog is full, so the connection
+/// is made on a thread of its own, which is left to finish in the
+/// background when the timeout passes first.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use osquery_rs::osquery::health::osquery_available;
+/// # use std::time::Duration;
+/// while !osquery_available("/var/osquery/osquery.em", Duration::from_secs(1)) {
+///     std::thread::sleep(Duration::from_secs(1));
+/// }
+/// ```
+pub fn osquery_available(socket_file: &str, timeout: Duration) -> bool {
+    let started = Instant::now();
+    let (tx, rx) = mpsc::channel();
+    let path = String::from(socket_file);
+    thread::spawn(move || {
+        let _ = tx.send(UnixStream::connect(path));
+    });
+
+    let stream = match rx.recv_timeout(timeout) {
+        Ok(Ok(stream)) => stream,
+        _ => return false,
+    };
+    let remaining = timeout.saturating_sub(started.elapsed());
+    if remaining.as_nanos() == 0 {
+        return false;
+    }
+
+    let (reader, socket) = match (stream.try_clone(), stream.try_clone()) {
+        (Ok(reader), Ok(socket)) => (reader, socket),
+        _ => return false,
+    };
+    let builder = OsqueryClientBuilder::new(socket_file).quiet(true);
+    let mut client = OsqueryClient::with_transport(&builder, reader, stream, Some(socket));
+    client.ping_timeout(remaining).is_ok()
+}
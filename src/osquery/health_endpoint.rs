@@ -0,0 +1,167 @@
+use crate::osquery::builder::OsqueryClientBuilder;
+use crate::osquery::health::Health;
+use crate::osquery::keepalive::sleep_unless_stopped;
+use crate::osquery::osquery::ExtensionRouteUUID;
+use crate::osquery::register_extension::OsqueryClient;
+use crate::osquery::status_log::escape_json;
+
+use log::warn;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How long the endpoint waits on a slow HTTP client, and how long a health
+/// check may wait on the extension manager.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often the endpoint checks for a new HTTP connection.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A tiny HTTP server answering every request with the health of the
+/// client's extension as JSON, for liveness and readiness probes. Answers
+/// are `200 OK` when healthy and `503 Service Unavailable` otherwise.
+pub(crate) struct HealthEndpoint {
+    uuid: Arc<AtomicI64>,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl HealthEndpoint {
+    /// Binds `address` and starts answering health checks, made over a
+    /// connection of its own to the extension manager the builder connects
+    /// to.
+    pub(crate) fn start(builder: &OsqueryClientBuilder, address: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+
+        let builder = builder.for_helper();
+
+        let uuid = Arc::new(AtomicI64::new(0));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread_uuid = uuid.clone();
+        let thread_stopped = stopped.clone();
+        let thread = thread::spawn(move || {
+            let mut client: Option<OsqueryClient> = None;
+            while !thread_stopped.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let uuid = thread_uuid.load(Ordering::SeqCst);
+                        if let Err(e) = answer(stream, &builder, &mut client, uuid) {
+                            warn!("Failed to answer a health check: {}", e);
+                        }
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        sleep_unless_stopped(ACCEPT_POLL_INTERVAL, &thread_stopped);
+                    }
+                    Err(e) => warn!("Failed to accept a health check: {}", e),
+                }
+            }
+        });
+
+        Ok(HealthEndpoint {
+            uuid,
+            stopped,
+            thread: Some(thread),
+        })
+    }
+
+    /// Sets the uuid of the extension the client registered, or 0 if none.
+    pub(crate) fn set_uuid(&self, uuid: ExtensionRouteUUID) {
+        self.uuid.store(uuid, Ordering::SeqCst);
+    }
+
+    /// Stops the endpoint and waits for its thread to finish.
+    pub(crate) fn stop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for HealthEndpoint {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Reads the request, whatever it is, and answers it with the health of the
+/// extension. The connection to the extension manager is made again after a
+/// failed check.
+fn answer(
+    mut stream: TcpStream,
+    builder: &OsqueryClientBuilder,
+    client: &mut Option<OsqueryClient>,
+    uuid: ExtensionRouteUUID,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    read_request_head(&mut stream)?;
+
+    if client.is_none() {
+        *client = builder.build().ok();
+    }
+    let health = match client.as_mut() {
+        Some(c) => {
+            let health = c.with_timeout(REQUEST_TIMEOUT, |c| c.health_of(uuid));
+            if health.is_err() {
+                *client = None;
+            }
+            health.map_err(|e| e.to_string())
+        }
+        None => Err(String::from("failed to connect to the extension manager")),
+    };
+
+    let (status, body) = match health {
+        Ok(health) if health.is_healthy() => ("200 OK", health_json(&health)),
+        Ok(health) => ("503 Service Unavailable", health_json(&health)),
+        Err(message) => (
+            "503 Service Unavailable",
+            format!(
+                "{{\"healthy\":false,\"error\":\"{}\"}}",
+                escape_json(&message)
+            ),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Reads up to the end of the request headers, which are all there is to a
+/// health check.
+fn read_request_head(stream: &mut TcpStream) -> io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 512];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < 8192 {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(())
+}
+
+fn health_json(health: &Health) -> String {
+    let uuid = health
+        .uuid
+        .map(|uuid| uuid.to_string())
+        .unwrap_or_else(|| String::from("null"));
+    format!(
+        "{{\"healthy\":{},\"registered\":{},\"uuid\":{},\"ping_latency_ms\":{:.3}}}",
+        health.is_healthy(),
+        health.registered,
+        uuid,
+        health.ping_latency.as_secs_f64() * 1000.0
+    )
+}
@@ -0,0 +1,190 @@
+use crate::osquery::plugin::TablePlugin;
+
+use log::warn;
+use std::mem;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The shortest time the flushing thread sleeps for, so that a zero window
+/// doesn't keep it spinning.
+const MIN_FLUSH_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Rows inserted into a writable table, held until enough of them arrived
+/// or no more arrived for a while, then passed to the table at once.
+pub(crate) struct InsertBatcher {
+    table: Arc<dyn TablePlugin>,
+    max_rows: usize,
+    window: Duration,
+    pending: Mutex<Pending>,
+}
+
+struct Pending {
+    rows: Vec<String>,
+    last_insert: Instant,
+}
+
+impl InsertBatcher {
+    /// Returns a batcher passing up to `max_rows` rows at a time to the
+    /// table, along with the thread flushing a partial batch once no row was
+    /// inserted for `window`. The thread exits once the batcher is dropped.
+    pub(crate) fn start(
+        table: Arc<dyn TablePlugin>,
+        max_rows: usize,
+        window: Duration,
+    ) -> Arc<Self> {
+        let batcher = Arc::new(InsertBatcher {
+            table,
+            max_rows: max_rows.max(1),
+            window,
+            pending: Mutex::new(Pending {
+                rows: Vec::new(),
+                last_insert: Instant::now(),
+            }),
+        });
+
+        let weak = Arc::downgrade(&batcher);
+        thread::spawn(move || flush_when_idle(weak, window));
+        batcher
+    }
+
+    /// Adds a row, as the JSON array of values osquery sends, flushing the
+    /// batch once it is full.
+    pub(crate) fn push(&self, row: String) {
+        let full = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.rows.push(row);
+            pending.last_insert = Instant::now();
+            if pending.rows.len() >= self.max_rows {
+                mem::take(&mut pending.rows)
+            } else {
+                Vec::new()
+            }
+        };
+        self.insert(full);
+    }
+
+    /// Flushes the batch if no row was inserted for the window.
+    fn flush_if_idle(&self) {
+        let rows = {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.last_insert.elapsed() < self.window {
+                return;
+            }
+            mem::take(&mut pending.rows)
+        };
+        self.insert(rows);
+    }
+
+    fn insert(&self, rows: Vec<String>) {
+        if rows.is_empty() {
+            return;
+        }
+        let count = rows.len();
+        if let Err(e) = self.table.insert_many(rows) {
+            warn!(
+                "Failed to insert {} rows into {}: {}",
+                count,
+                self.table.name(),
+                e
+            );
+        }
+    }
+}
+
+impl Drop for InsertBatcher {
+    fn drop(&mut self) {
+        let rows = mem::take(&mut self.pending.get_mut().unwrap().rows);
+        self.insert(rows);
+    }
+}
+
+/// Checks the batch every window, until the batcher is dropped.
+fn flush_when_idle(batcher: Weak<InsertBatcher>, window: Duration) {
+    loop {
+        thread::sleep(window.max(MIN_FLUSH_INTERVAL));
+        match batcher.upgrade() {
+            Some(batcher) => batcher.flush_if_idle(),
+            None => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osquery::columns::ColumnDefinition;
+    use crate::osquery::osquery::{ExtensionPluginRequest, ExtensionPluginResponse};
+
+    /// A table recording every batch of rows inserted into it.
+    #[derive(Default)]
+    struct RecordingTable {
+        batches: Mutex<Vec<Vec<String>>>,
+    }
+
+    impl RecordingTable {
+        fn batches(&self) -> Vec<Vec<String>> {
+            self.batches.lock().unwrap().clone()
+        }
+    }
+
+    impl TablePlugin for RecordingTable {
+        fn name(&self) -> String {
+            String::from("recording")
+        }
+
+        fn columns(&self) -> Vec<ColumnDefinition> {
+            Vec::new()
+        }
+
+        fn generate(
+            &self,
+            _request: &ExtensionPluginRequest,
+        ) -> Result<ExtensionPluginResponse, String> {
+            Ok(Vec::new())
+        }
+
+        fn insert_many(&self, rows: Vec<String>) -> Result<(), String> {
+            self.batches.lock().unwrap().push(rows);
+            Ok(())
+        }
+    }
+
+    fn row(id: u32) -> String {
+        format!("[\"{}\"]", id)
+    }
+
+    #[test]
+    fn full_batch_is_flushed_right_away() {
+        let table = Arc::new(RecordingTable::default());
+        let batcher = InsertBatcher::start(table.clone(), 2, Duration::from_secs(3600));
+        batcher.push(row(1));
+        assert!(table.batches().is_empty());
+        batcher.push(row(2));
+        batcher.push(row(3));
+        assert_eq!(table.batches(), vec![vec![row(1), row(2)]]);
+    }
+
+    #[test]
+    fn partial_batch_is_flushed_once_idle() {
+        let table = Arc::new(RecordingTable::default());
+        let batcher = InsertBatcher::start(table.clone(), 10, Duration::from_millis(50));
+        batcher.push(row(1));
+        batcher.push(row(2));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while table.batches().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(table.batches(), vec![vec![row(1), row(2)]]);
+    }
+
+    #[test]
+    fn dropped_batcher_flushes_what_it_holds() {
+        let table = Arc::new(RecordingTable::default());
+        let batcher = InsertBatcher::start(table.clone(), 10, Duration::from_secs(3600));
+        batcher.push(row(1));
+        drop(batcher);
+        assert_eq!(table.batches(), vec![vec![row(1)]]);
+    }
+}
@@ -0,0 +1,64 @@
+use crate::osquery::error::OsqueryError;
+use crate::osquery::register_extension::OsqueryClient;
+use crate::osquery::result_set::ResultSet;
+
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+impl OsqueryClient {
+    /// Runs the query and returns the rows as a JSON array of objects, for
+    /// callers that want to work with the result as JSON. Every value is
+    /// kept as the string osquery returned, numbers included, so nothing is
+    /// lost in a conversion.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// let value = client.query_value("SELECT pid, name FROM processes").unwrap();
+    /// println!("{}", value[0]["name"]);
+    /// ```
+    pub fn query_value(&mut self, query: &str) -> Result<Value, OsqueryError> {
+        Ok(to_value(&self.query(query)?))
+    }
+}
+
+/// Returns the rows as a JSON array of objects with string values.
+pub fn to_value(rows: &[BTreeMap<String, String>]) -> Value {
+    Value::Array(
+        rows.iter()
+            .map(|row| {
+                let object: Map<String, Value> = row
+                    .iter()
+                    .map(|(column, value)| (column.clone(), Value::String(value.clone())))
+                    .collect();
+                Value::Object(object)
+            })
+            .collect(),
+    )
+}
+
+impl From<&ResultSet> for Value {
+    /// Converts the rows to a JSON array of objects with string values,
+    /// the same way as `to_value`.
+    fn from(rows: &ResultSet) -> Self {
+        Value::Array(
+            rows.rows()
+                .map(|row| {
+                    let object: Map<String, Value> = row
+                        .into_iter()
+                        .map(|(column, value)| {
+                            (String::from(column), Value::String(String::from(value)))
+                        })
+                        .collect();
+                    Value::Object(object)
+                })
+                .collect(),
+        )
+    }
+}
@@ -0,0 +1,282 @@
+use crate::osquery::backoff::Backoff;
+use crate::osquery::builder::OsqueryClientBuilder;
+use crate::osquery::error::OsqueryError;
+use crate::osquery::osquery::{ExtensionRegistry, ExtensionRouteUUID};
+use crate::osquery::register_extension::OsqueryClient;
+
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How long a keepalive ping may take before it is given up on, which also
+/// bounds how long stopping the keepalive can block.
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often a sleeping background thread checks whether it was stopped.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The longest a background thread waits between two attempts to connect,
+/// unless its interval is longer.
+pub(crate) const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A thread pinging the extension manager every interval over a connection
+/// of its own, until stopped.
+pub(crate) struct Keepalive {
+    /// Whether the registration is verified after every ping.
+    verify: bool,
+
+    /// The registration to verify, shared with the thread.
+    registration: SharedRegistration,
+
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// The registration a keepalive keeps, shared with its thread.
+pub(crate) type SharedRegistration = Arc<Mutex<Option<KeptRegistration>>>;
+
+/// An extension the keepalive keeps registered.
+pub(crate) struct KeptRegistration {
+    name: String,
+    uuid: ExtensionRouteUUID,
+    registry: ExtensionRegistry,
+}
+
+impl Keepalive {
+    /// Starts pinging the extension manager the builder connects to every
+    /// `interval`. A failed ping is logged, and the connection is made again
+    /// for the next one. Failing to connect is retried with a jittered
+    /// backoff starting at `interval`, see `OsqueryClientBuilder::backoff_seed`.
+    /// If the builder asks for it, every successful ping is followed by
+    /// verifying the registration set with `set_registration`.
+    pub(crate) fn start(builder: &OsqueryClientBuilder, interval: Duration) -> Self {
+        let verify = builder.keepalive_verify_registration;
+        let builder = builder.for_helper();
+
+        let registration = Arc::new(Mutex::new(None));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread_registration = registration.clone();
+        let thread_stopped = stopped.clone();
+        let thread = thread::spawn(move || {
+            let mut backoff = reconnect_backoff(&builder, interval);
+            let mut delay = interval;
+            let mut client: Option<OsqueryClient> = None;
+            while sleep_unless_stopped(delay, &thread_stopped) {
+                delay = interval;
+                if client.is_none() {
+                    client = match builder.build() {
+                        Ok(client) => {
+                            backoff.reset();
+                            Some(client)
+                        }
+                        Err(e) => {
+                            warn!("Keepalive failed to connect: {}", e);
+                            delay = backoff.next_delay();
+                            continue;
+                        }
+                    };
+                }
+
+                if let Some(c) = client.as_mut() {
+                    if let Err(e) = c.ping_timeout(PING_TIMEOUT) {
+                        warn!("Keepalive ping failed: {}", e);
+                        client = None;
+                        continue;
+                    }
+
+                    if let Err(e) = verify_registration(c, &thread_registration) {
+                        warn!("Keepalive failed to verify the registration: {}", e);
+                        if c.needs_reconnect() {
+                            client = None;
+                        }
+                    }
+                }
+            }
+        });
+
+        Keepalive {
+            verify,
+            registration,
+            stopped,
+            thread: Some(thread),
+        }
+    }
+
+    /// Sets the registration to verify, if the keepalive verifies one.
+    pub(crate) fn set_registration(
+        &self,
+        name: &str,
+        uuid: ExtensionRouteUUID,
+        registry: &ExtensionRegistry,
+    ) {
+        if self.verify {
+            *self.registration.lock().unwrap() = Some(KeptRegistration {
+                name: String::from(name),
+                uuid,
+                registry: registry.clone(),
+            });
+        }
+    }
+
+    /// Stops verifying the registration, as the extension was deregistered.
+    pub(crate) fn clear_registration(&self) {
+        *self.registration.lock().unwrap() = None;
+    }
+
+    /// Returns the uuid of the registration being verified, which changes
+    /// when the keepalive registers the extension again. `None` is returned
+    /// while the registration is being verified, rather than waiting on it.
+    pub(crate) fn registered_uuid(&self) -> Option<ExtensionRouteUUID> {
+        match self.registration.try_lock() {
+            Ok(registration) => registration.as_ref().map(|kept| kept.uuid),
+            Err(_) => None,
+        }
+    }
+
+    /// Returns the registration the keepalive keeps, for a lease to take
+    /// when it runs out.
+    pub(crate) fn shared_registration(&self) -> SharedRegistration {
+        self.registration.clone()
+    }
+
+    /// Stops the keepalive and waits for its thread to finish, which takes
+    /// at most the ping timeout.
+    pub(crate) fn stop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Registers the extension again if osquery no longer lists it. The
+/// registration stays locked meanwhile, so that the client can't deregister
+/// the extension while it is being registered again. The connection used
+/// here forgets the new registration, which belongs to the client.
+fn verify_registration(
+    client: &mut OsqueryClient,
+    registration: &Mutex<Option<KeptRegistration>>,
+) -> Result<(), OsqueryError> {
+    let mut registration = registration.lock().unwrap();
+    let kept = match registration.as_mut() {
+        Some(kept) => kept,
+        None => return Ok(()),
+    };
+
+    if client
+        .with_timeout(PING_TIMEOUT, |c| c.extensions())?
+        .contains_key(&kept.uuid)
+    {
+        return Ok(());
+    }
+
+    let uuid = client.with_timeout(PING_TIMEOUT, |c| {
+        c.register_extension_with_registry(&kept.name, kept.registry.clone())
+    })?;
+    client.forget_registration();
+    info!(
+        "Keepalive registered extension {} again as {}",
+        kept.name, uuid
+    );
+    kept.uuid = uuid;
+    Ok(())
+}
+
+/// Stops keeping the registration, returning the uuid it was last
+/// registered as, if any. This waits for a registration in progress.
+pub(crate) fn take_kept_uuid(registration: &SharedRegistration) -> Option<ExtensionRouteUUID> {
+    registration.lock().unwrap().take().map(|kept| kept.uuid)
+}
+
+/// Returns the backoff a background thread checking every `interval`
+/// reconnects with, so that the helpers of a fleet of clients that lost
+/// osquery at the same time don't all connect again in lockstep.
+pub(crate) fn reconnect_backoff(builder: &OsqueryClientBuilder, interval: Duration) -> Backoff {
+    Backoff::new(
+        interval,
+        interval.max(RECONNECT_MAX_BACKOFF),
+        builder.backoff_seed,
+    )
+}
+
+/// Sleeps for `duration` unless stopped first. Returns whether the sleep
+/// completed without being stopped. A duration too long to be represented
+/// sleeps until stopped.
+pub(crate) fn sleep_unless_stopped(duration: Duration, stopped: &AtomicBool) -> bool {
+    let until = Instant::now().checked_add(duration);
+    while !stopped.load(Ordering::SeqCst) {
+        let remaining = match until {
+            Some(until) => until.saturating_duration_since(Instant::now()),
+            None => STOP_POLL_INTERVAL,
+        };
+        if remaining.as_nanos() == 0 {
+            return true;
+        }
+        thread::sleep(remaining.min(STOP_POLL_INTERVAL));
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osquery::fake::{test_socket, FakeExtensionManager};
+
+    #[test]
+    fn endless_sleep_ends_when_stopped() {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stopper = {
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                stopped.store(true, Ordering::SeqCst);
+            })
+        };
+
+        assert!(!sleep_unless_stopped(Duration::MAX, &stopped));
+        stopper.join().unwrap();
+    }
+
+    #[test]
+    fn endless_interval_backs_off_without_overflowing() {
+        let builder = OsqueryClientBuilder::new("").backoff_seed(42);
+        let mut backoff = reconnect_backoff(&builder, Duration::MAX);
+        for _ in 0..4 {
+            backoff.next_delay();
+        }
+    }
+
+    #[test]
+    fn dropped_extension_is_registered_again() {
+        let manager = FakeExtensionManager::new()
+            .start(&test_socket("keepalive-reregister"))
+            .unwrap();
+        let mut client = OsqueryClientBuilder::new(manager.socket_path())
+            .keepalive(Duration::from_millis(50))
+            .keepalive_verify_registration(true)
+            .build()
+            .unwrap();
+        client.register_extension("kept").unwrap();
+        let first = *manager.extensions().keys().next().unwrap();
+
+        manager.expire(first);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while manager.extensions().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        let extensions = manager.extensions();
+        assert_eq!(extensions.len(), 1);
+        let (uuid, info) = extensions.iter().next().unwrap();
+        assert_ne!(*uuid, first);
+        assert_eq!(info.name.as_deref(), Some("kept"));
+    }
+}
@@ -0,0 +1,159 @@
+use crate::osquery::error::OsqueryError;
+use crate::osquery::register_extension::OsqueryClient;
+
+use std::collections::BTreeMap;
+use std::sync::mpsc::Sender;
+
+impl OsqueryClient {
+    /// Runs the query and returns an iterator decoding the rows one at a
+    /// time as they are read off the connection, instead of decoding the
+    /// whole result before returning it like `query` does. osquery still
+    /// sends the whole result at once, but a caller that stops early only
+    /// pays for decoding the rows it read, and only one row is held in
+    /// memory at a time.
+    ///
+    /// Dropping the iterator before the last row leaves the rest of the
+    /// result on the connection, so the client then needs to be rebuilt, as
+    /// reported by `needs_reconnect`. A failure to read a row ends the
+    /// iteration with the error.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// let big = client
+    ///     .query_lazy("SELECT path, size FROM file WHERE path LIKE '/%'")
+    ///     .unwrap()
+    ///     .filter_map(Result::ok)
+    ///     .find(|row| row["size"].parse::<u64>().unwrap_or(0) > 1 << 30);
+    /// ```
+    pub fn query_lazy(&mut self, query: &str) -> Result<LazyRows<'_>, OsqueryError> {
+        let remaining = self.start_lazy_query(query)?;
+        Ok(LazyRows {
+            client: self,
+            remaining: remaining.unwrap_or(0),
+            open: remaining.is_some(),
+        })
+    }
+
+    /// Runs the query and sends every row to `tx` as soon as it is decoded,
+    /// so that another thread can work through the rows while the rest are
+    /// still being read. Returns the number of rows sent.
+    ///
+    /// Should the receiver be dropped, the rows not sent yet are skipped
+    /// without being decoded, and the number of rows sent until then is
+    /// returned. The connection stays usable either way, unless reading a
+    /// row fails.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    /// * `tx` - The channel to send the rows to
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// # use std::sync::mpsc;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// let (tx, rx) = mpsc::channel();
+    /// let consumer = std::thread::spawn(move || {
+    ///     for row in rx {
+    ///         println!("{:?}", row);
+    ///     }
+    /// });
+    /// let sent = client.query_to_channel("SELECT * FROM processes", tx).unwrap();
+    /// consumer.join().unwrap();
+    /// println!("{} rows", sent);
+    /// ```
+    pub fn query_to_channel(
+        &mut self,
+        query: &str,
+        tx: Sender<BTreeMap<String, String>>,
+    ) -> Result<usize, OsqueryError> {
+        let mut rows = self.query_lazy(query)?;
+        let mut sent = 0;
+        while let Some(row) = rows.next() {
+            if tx.send(row?).is_err() {
+                rows.skip_rest()?;
+                break;
+            }
+            sent += 1;
+        }
+        Ok(sent)
+    }
+}
+
+/// The rows of a query, decoded as they are iterated over. Returned by
+/// `OsqueryClient::query_lazy`.
+pub struct LazyRows<'a> {
+    client: &'a mut OsqueryClient,
+    remaining: usize,
+
+    /// Whether the reply wasn't read to its end yet.
+    open: bool,
+}
+
+impl<'a> LazyRows<'a> {
+    /// Reads the rest of the reply without decoding the rows left.
+    fn skip_rest(&mut self) -> Result<(), OsqueryError> {
+        if !self.open {
+            return Ok(());
+        }
+
+        self.open = false;
+        if let Err(e) = self.client.skip_lazy_rows(self.remaining) {
+            self.client.abandon_call();
+            return Err(e);
+        }
+        self.client.finish_lazy_query()
+    }
+}
+
+impl<'a> Iterator for LazyRows<'a> {
+    type Item = Result<BTreeMap<String, String>, OsqueryError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.open {
+            return None;
+        }
+
+        if self.remaining == 0 {
+            self.open = false;
+            return self.client.finish_lazy_query().err().map(Err);
+        }
+
+        self.remaining -= 1;
+        match self.client.read_lazy_row() {
+            Ok(row) => Some(Ok(row)),
+            Err(e) => {
+                self.open = false;
+                self.client.abandon_call();
+                Some(Err(e))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // Reading the end of the reply may still fail.
+        (0, Some(self.remaining + 1))
+    }
+}
+
+impl<'a> Drop for LazyRows<'a> {
+    fn drop(&mut self) {
+        if !self.open {
+            return;
+        }
+        if self.remaining == 0 {
+            let _ = self.client.finish_lazy_query();
+        } else {
+            self.client.abandon_call();
+        }
+    }
+}
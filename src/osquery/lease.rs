@@ -0,0 +1,209 @@
+use crate::osquery::builder::OsqueryClientBuilder;
+use crate::osquery::keepalive::{
+    reconnect_backoff, sleep_unless_stopped, take_kept_uuid, SharedRegistration,
+};
+use crate::osquery::osquery::ExtensionRouteUUID;
+use crate::osquery::register_extension::OsqueryClient;
+
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How long a renewal or deregistration may wait on the extension manager.
+const CALL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The shortest time between two checks of a lease, whatever its ttl.
+const MIN_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A thread holding the registration of a client's extension as a lease,
+/// over a connection of its own. The lease is renewed by the client, and
+/// the thread deregisters the extension once the client has gone a whole
+/// ttl without renewing it.
+pub(crate) struct Lease {
+    state: Arc<Mutex<LeaseState>>,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct LeaseState {
+    /// The uuid of the leased extension, or 0 if none.
+    uuid: ExtensionRouteUUID,
+
+    /// When the client last renewed the lease.
+    renewed: Instant,
+
+    /// Set once the lease ran out and the extension was deregistered.
+    expired: bool,
+}
+
+impl Lease {
+    /// Starts checking the lease every quarter of `ttl`. While the lease is
+    /// current, every check pings the extension manager and checks that it
+    /// still lists the extension. Once it ran out, the extension is
+    /// deregistered, along with the registration the keepalive keeps, if
+    /// any, so that the keepalive doesn't register it again. Failing to
+    /// connect is retried with a jittered backoff, like for the keepalive.
+    pub(crate) fn start(
+        builder: &OsqueryClientBuilder,
+        ttl: Duration,
+        kept: Option<SharedRegistration>,
+    ) -> Self {
+        let builder = builder.for_helper();
+
+        let interval = (ttl / 4).max(MIN_CHECK_INTERVAL);
+        let state = Arc::new(Mutex::new(LeaseState {
+            uuid: 0,
+            renewed: Instant::now(),
+            expired: false,
+        }));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread_state = state.clone();
+        let thread_stopped = stopped.clone();
+        let thread = thread::spawn(move || {
+            let mut backoff = reconnect_backoff(&builder, interval);
+            let mut delay = interval;
+            let mut client: Option<OsqueryClient> = None;
+            while sleep_unless_stopped(delay, &thread_stopped) {
+                delay = interval;
+                if thread_state.lock().unwrap().uuid == 0 {
+                    continue;
+                }
+                if client.is_none() {
+                    client = match builder.build() {
+                        Ok(client) => {
+                            backoff.reset();
+                            Some(client)
+                        }
+                        Err(e) => {
+                            warn!("Lease failed to connect: {}", e);
+                            delay = backoff.next_delay();
+                            continue;
+                        }
+                    };
+                }
+
+                if let Some(c) = client.as_mut() {
+                    check_lease(c, &thread_state, ttl, kept.as_ref());
+                    if c.needs_reconnect() {
+                        client = None;
+                    }
+                }
+            }
+        });
+
+        Lease {
+            state,
+            stopped,
+            thread: Some(thread),
+        }
+    }
+
+    /// Leases the extension registered as `uuid`, or ends the lease if 0.
+    pub(crate) fn set_uuid(&self, uuid: ExtensionRouteUUID) {
+        let mut state = self.state.lock().unwrap();
+        state.uuid = uuid;
+        state.renewed = Instant::now();
+        state.expired = false;
+    }
+
+    /// Renews the lease, unless it already ran out.
+    pub(crate) fn renew(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !state.expired {
+            state.renewed = Instant::now();
+        }
+    }
+
+    /// Returns whether the lease ran out and the extension was deregistered.
+    pub(crate) fn expired(&self) -> bool {
+        self.state.lock().unwrap().expired
+    }
+
+    /// Stops the lease and waits for its thread to finish, which takes at
+    /// most the timeout of a call.
+    pub(crate) fn stop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Deregisters the extension if the lease ran out, and otherwise pings the
+/// extension manager and checks that it still lists the extension. The
+/// state stays locked meanwhile, so the client can't renew a lease that is
+/// being given up.
+fn check_lease(
+    client: &mut OsqueryClient,
+    state: &Mutex<LeaseState>,
+    ttl: Duration,
+    kept: Option<&SharedRegistration>,
+) {
+    let mut state = state.lock().unwrap();
+    if state.uuid == 0 {
+        return;
+    }
+
+    if state.renewed.elapsed() >= ttl {
+        // The keepalive may have registered the extension again under a new
+        // uuid the client hasn't picked up yet.
+        let uuid = kept.and_then(take_kept_uuid).unwrap_or(state.uuid);
+        match client.with_timeout(CALL_TIMEOUT, |c| c.deregister_uuid(uuid)) {
+            Ok(()) => info!(
+                "Lease of extension {} ran out after {:?}, deregistered it",
+                uuid, ttl
+            ),
+            Err(e) => warn!("Lease failed to deregister extension {}: {}", uuid, e),
+        }
+        state.uuid = 0;
+        state.expired = true;
+        return;
+    }
+
+    let listed = client
+        .ping_timeout(CALL_TIMEOUT)
+        .and_then(|_| client.with_timeout(CALL_TIMEOUT, |c| c.extensions()));
+    match listed {
+        Ok(extensions) if !extensions.contains_key(&state.uuid) => {
+            warn!("osquery no longer lists leased extension {}", state.uuid)
+        }
+        Ok(_) => {}
+        Err(e) => warn!("Lease failed to renew: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::osquery::builder::OsqueryClientBuilder;
+    use crate::osquery::fake::{test_socket, FakeExtensionManager};
+
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn lease_running_out_deregisters_the_extension() {
+        let manager = FakeExtensionManager::new()
+            .start(&test_socket("lease-expiry"))
+            .unwrap();
+        let mut client = OsqueryClientBuilder::new(manager.socket_path())
+            .registration_ttl(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        client.register_extension("leased").unwrap();
+        assert_eq!(manager.extensions().len(), 1);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !manager.extensions().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(manager.extensions().is_empty());
+    }
+}
@@ -1,5 +1,42 @@
+mod backoff;
+pub mod builder;
+pub mod columns;
+pub mod config;
+#[cfg(feature = "serde")]
+pub mod de;
+pub mod error;
+pub mod estimate;
+pub mod events;
+#[cfg(any(test, feature = "test-util"))]
+pub mod fake;
+pub mod flags;
+pub mod health;
+#[cfg(feature = "health-endpoint")]
+mod health_endpoint;
+mod insert_batch;
+#[cfg(feature = "serde_json")]
+pub mod json;
+mod keepalive;
+pub mod lazy;
+mod lease;
+#[allow(clippy::all)]
 pub mod osquery;
+pub mod plugin;
+pub mod profile;
+mod protocol;
 pub mod register_extension;
+pub mod result_set;
+pub mod rows;
+pub mod sample;
+pub mod schema;
+pub mod script;
+pub mod server;
+pub mod sql;
+mod status_log;
+pub mod tables;
+pub mod tail;
+mod transport;
+pub mod version;
 
 pub use osquery::*;
 pub use register_extension::*;
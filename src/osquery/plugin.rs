@@ -0,0 +1,574 @@
+use crate::osquery::columns::ColumnDefinition;
+use crate::osquery::insert_batch::InsertBatcher;
+use crate::osquery::osquery::*;
+use crate::osquery::status_log::{
+    decorate_record, decorate_status_logs, escape_json, filter_status_logs,
+};
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The registry osquery routes table plugin calls to.
+pub const TABLE_REGISTRY: &str = "table";
+
+/// The registry osquery routes config plugin calls to.
+pub const CONFIG_REGISTRY: &str = "config";
+
+/// The registry osquery routes logger plugin calls to.
+pub const LOGGER_REGISTRY: &str = "logger";
+
+/// The registry osquery routes distributed plugin calls to.
+pub const DISTRIBUTED_REGISTRY: &str = "distributed";
+
+/// A table that osquery can query through the extension.
+pub trait TablePlugin: Send + Sync {
+    /// Returns the name of the table.
+    fn name(&self) -> String;
+
+    /// Returns the columns of the table.
+    fn columns(&self) -> Vec<ColumnDefinition>;
+
+    /// Returns the rows of the table. The request carries the query
+    /// constraints as JSON under the `context` key.
+    fn generate(&self, request: &ExtensionPluginRequest)
+        -> Result<ExtensionPluginResponse, String>;
+
+    /// Inserts rows into a writable table. Each row is the JSON array of
+    /// values osquery sends with an insert, in column order. Tables are
+    /// read only unless overridden.
+    fn insert_many(&self, _rows: Vec<String>) -> Result<(), String> {
+        Err(String::from("table is read only"))
+    }
+}
+
+/// A source of configuration for osquery.
+pub trait ConfigPlugin: Send + Sync {
+    /// Returns the name of the config plugin.
+    fn name(&self) -> String;
+
+    /// Returns the config, as a map of source name to the JSON config from
+    /// that source.
+    fn generate_config(&self) -> Result<BTreeMap<String, String>, String>;
+}
+
+/// A destination for the results and status logs osquery produces.
+pub trait LoggerPlugin: Send + Sync {
+    /// Returns the name of the logger plugin.
+    fn name(&self) -> String;
+
+    /// Logs a result, which osquery sends serialized as JSON.
+    fn log_string(&self, s: &str) -> Result<(), String>;
+
+    /// Logs a snapshot result. The snapshot is logged like any other result
+    /// unless overridden.
+    fn log_snapshot(&self, s: &str) -> Result<(), String> {
+        self.log_string(s)
+    }
+
+    /// Logs a batch of status logs, which osquery sends as a JSON array.
+    /// Status logs are dropped unless overridden.
+    fn log_status(&self, _logs: &str) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// A source of distributed queries for osquery, such as a fleet manager.
+///
+/// osquery only uses the plugin when started with
+/// `--disable_distributed=false` and `--distributed_plugin` naming it, which
+/// `OsqueryClient::distributed_enabled` checks. osquery then asks for
+/// queries every `--distributed_interval` seconds, runs them and hands the
+/// results back.
+pub trait DistributedPlugin: Send + Sync {
+    /// Returns the name of the distributed plugin.
+    fn name(&self) -> String;
+
+    /// Returns the queries for osquery to run, keyed by a name that the
+    /// results are then reported under.
+    fn get_queries(&self) -> Result<BTreeMap<String, String>, String>;
+
+    /// Accepts the results of the queries, which osquery sends as a JSON
+    /// object holding the rows of every query under `queries` and the status
+    /// code of every query under `statuses`, both keyed by query name, for
+    /// example `{"queries":{"q1":[{"pid":"1"}]},"statuses":{"q1":0}}`.
+    fn write_results(&self, results: &str) -> Result<(), String>;
+}
+
+/// The table, config, logger and distributed plugins provided by one
+/// extension.
+///
+/// `Plugins` builds the registry sent to osquery when registering the
+/// extension and serves the calls osquery makes for every plugin in it,
+/// routing each call by its registry and item name.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use osquery_rs::osquery::plugin::*;
+/// # use osquery_rs::osquery::server::ExtensionServer;
+/// # fn run<T: TablePlugin + 'static, L: LoggerPlugin + 'static>(table: T, logger: L) {
+/// let plugins = Plugins::new().add_table(table).add_logger(logger);
+/// let registry = plugins.registry();
+/// let server = ExtensionServer::start("/tmp/osquery.sock", "example", registry, plugins).unwrap();
+/// # }
+/// ```
+#[derive(Default)]
+pub struct Plugins {
+    tables: BTreeMap<String, Table>,
+    configs: BTreeMap<String, Box<dyn ConfigPlugin>>,
+    loggers: BTreeMap<String, Logger>,
+    distributed: BTreeMap<String, Box<dyn DistributedPlugin>>,
+}
+
+/// A table plugin and the batcher its inserts go through, if batched.
+struct Table {
+    plugin: Arc<dyn TablePlugin>,
+    batcher: Option<Arc<InsertBatcher>>,
+}
+
+/// Returns the decorations merged into the logs passed to a logger plugin.
+type Decorations = Box<dyn Fn() -> BTreeMap<String, String> + Send + Sync>;
+
+/// A logger plugin, the lowest severity of status logs passed to it and the
+/// decorations merged into its logs, if any.
+struct Logger {
+    plugin: Box<dyn LoggerPlugin>,
+    min_status: StatusSeverity,
+    decorations: Option<Decorations>,
+}
+
+/// The severity of an osquery status log, as sent to logger plugins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum StatusSeverity {
+    Info = 0,
+    Warning = 1,
+    Error = 2,
+    Fatal = 3,
+}
+
+impl Plugins {
+    /// Returns an empty set of plugins.
+    pub fn new() -> Self {
+        Plugins::default()
+    }
+
+    /// Adds a table plugin, replacing any table with the same name.
+    pub fn add_table<P: TablePlugin + 'static>(mut self, plugin: P) -> Self {
+        let table = Table {
+            plugin: Arc::new(plugin),
+            batcher: None,
+        };
+        self.tables.insert(table.plugin.name(), table);
+        self
+    }
+
+    /// Adds a writable table plugin whose inserts are batched, replacing any
+    /// table with the same name. Inserts are acknowledged to osquery right
+    /// away and passed to `insert_many` once `max_rows` of them arrived, or
+    /// once no insert arrived for `window`, so that a partial batch isn't
+    /// held indefinitely. An `insert_many` failure can't be reported back to
+    /// osquery at that point, so it is logged instead.
+    pub fn add_table_with_batching<P: TablePlugin + 'static>(
+        mut self,
+        plugin: P,
+        max_rows: usize,
+        window: Duration,
+    ) -> Self {
+        let plugin: Arc<dyn TablePlugin> = Arc::new(plugin);
+        let table = Table {
+            plugin: plugin.clone(),
+            batcher: Some(InsertBatcher::start(plugin, max_rows, window)),
+        };
+        self.tables.insert(table.plugin.name(), table);
+        self
+    }
+
+    /// Adds a config plugin, replacing any config plugin with the same name.
+    pub fn add_config<P: ConfigPlugin + 'static>(mut self, plugin: P) -> Self {
+        self.configs.insert(plugin.name(), Box::new(plugin));
+        self
+    }
+
+    /// Adds a logger plugin, replacing any logger plugin with the same name.
+    pub fn add_logger<P: LoggerPlugin + 'static>(self, plugin: P) -> Self {
+        self.add_logger_with_min_status(plugin, StatusSeverity::Info)
+    }
+
+    /// Adds a logger plugin that is only passed status logs of at least
+    /// `min_status`, like osquery's `--logger_min_status` flag. Lower
+    /// severity status logs are dropped before reaching the plugin, and a
+    /// batch left empty isn't passed on at all.
+    pub fn add_logger_with_min_status<P: LoggerPlugin + 'static>(
+        mut self,
+        plugin: P,
+        min_status: StatusSeverity,
+    ) -> Self {
+        let logger = Logger {
+            plugin: Box::new(plugin),
+            min_status,
+            decorations: None,
+        };
+        self.loggers.insert(logger.plugin.name(), logger);
+        self
+    }
+
+    /// Merges the decorations returned by `decorations` into every log
+    /// passed to the logger plugin named `name`, such as a host id or the
+    /// environment, the way osquery adds its own decorations. `decorations`
+    /// is called once for every call osquery makes to the logger, so the
+    /// decorations can change over time.
+    ///
+    /// The decorations are added to the `decorations` object of every
+    /// result, snapshot and status log, which is added if the log has none.
+    /// Where a log already has a decoration, such as one osquery added, the
+    /// value in the log is kept. Logs that aren't JSON objects are passed on
+    /// as they are. This has no effect unless a logger plugin named `name`
+    /// was added before.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::plugin::*;
+    /// # use std::collections::BTreeMap;
+    /// # fn run<L: LoggerPlugin + 'static>(logger: L) {
+    /// let mut decorations = BTreeMap::new();
+    /// decorations.insert(String::from("environment"), String::from("production"));
+    /// let plugins = Plugins::new()
+    ///     .add_logger(logger)
+    ///     .decorate_logger("shipper", move || decorations.clone());
+    /// # }
+    /// ```
+    pub fn decorate_logger<F>(mut self, name: &str, decorations: F) -> Self
+    where
+        F: Fn() -> BTreeMap<String, String> + Send + Sync + 'static,
+    {
+        if let Some(logger) = self.loggers.get_mut(name) {
+            logger.decorations = Some(Box::new(decorations));
+        }
+        self
+    }
+
+    /// Adds a distributed plugin, replacing any distributed plugin with the
+    /// same name.
+    pub fn add_distributed<P: DistributedPlugin + 'static>(mut self, plugin: P) -> Self {
+        self.distributed.insert(plugin.name(), Box::new(plugin));
+        self
+    }
+
+    /// Returns the registry describing every plugin, to register the
+    /// extension with.
+    pub fn registry(&self) -> ExtensionRegistry {
+        let mut registry = ExtensionRegistry::new();
+        for (name, routes) in self.registry_builder().registry {
+            let routes = routes
+                .into_iter()
+                .map(|(item, route)| (item, route.routes))
+                .collect();
+            registry.insert(name, routes);
+        }
+        registry
+    }
+
+    /// Returns a registry builder holding the routes of every plugin, so
+    /// version metadata can be set on them before building the registry.
+    pub fn registry_builder(&self) -> RegistryBuilder {
+        let mut builder = RegistryBuilder::new();
+        for (name, table) in &self.tables {
+            builder = builder.route(TABLE_REGISTRY, name, table_routes(table.plugin.as_ref()));
+        }
+        for name in self.configs.keys() {
+            builder = builder.route(CONFIG_REGISTRY, name, ExtensionPluginResponse::new());
+        }
+        for name in self.loggers.keys() {
+            builder = builder.route(LOGGER_REGISTRY, name, ExtensionPluginResponse::new());
+        }
+        for name in self.distributed.keys() {
+            builder = builder.route(DISTRIBUTED_REGISTRY, name, ExtensionPluginResponse::new());
+        }
+        builder
+    }
+
+    fn call_table(&self, item: &str, request: &ExtensionPluginRequest) -> ExtensionResponse {
+        let table = match self.tables.get(item) {
+            Some(table) => table,
+            None => return failure(format!("Unknown table: {}", item)),
+        };
+
+        match request.get("action").map(String::as_str) {
+            Some("generate") => response(table.plugin.generate(request)),
+            Some("columns") => success(table_routes(table.plugin.as_ref())),
+            Some("insert") => {
+                let row = request
+                    .get("json_value_array")
+                    .cloned()
+                    .unwrap_or_else(|| String::from("[]"));
+                let res = match &table.batcher {
+                    Some(batcher) => {
+                        batcher.push(row);
+                        Ok(())
+                    }
+                    None => table.plugin.insert_many(vec![row]),
+                };
+                response(res.map(|_| vec![insert_status(request)]))
+            }
+            action => failure(format!("Unsupported table action: {:?}", action)),
+        }
+    }
+
+    fn call_config(&self, item: &str, request: &ExtensionPluginRequest) -> ExtensionResponse {
+        let config = match self.configs.get(item) {
+            Some(config) => config,
+            None => return failure(format!("Unknown config plugin: {}", item)),
+        };
+
+        match request.get("action").map(String::as_str) {
+            Some("genConfig") => response(config.generate_config().map(|c| vec![c])),
+            action => failure(format!("Unsupported config action: {:?}", action)),
+        }
+    }
+
+    fn call_logger(&self, item: &str, request: &ExtensionPluginRequest) -> ExtensionResponse {
+        let logger = match self.loggers.get(item) {
+            Some(logger) => logger,
+            None => return failure(format!("Unknown logger plugin: {}", item)),
+        };
+
+        let decorations = match &logger.decorations {
+            Some(decorations) => decorations(),
+            None => BTreeMap::new(),
+        };
+        let decorate = |record: &str| {
+            if decorations.is_empty() {
+                return String::from(record);
+            }
+            decorate_record(record, &decorations).unwrap_or_else(|| String::from(record))
+        };
+
+        let res = if let Some(s) = request.get("string") {
+            logger.plugin.log_string(&decorate(s))
+        } else if let Some(s) = request.get("snapshot") {
+            logger.plugin.log_snapshot(&decorate(s))
+        } else if request.contains_key("status") {
+            let logs = request.get("log").map(String::as_str).unwrap_or("[]");
+            let logs = match logger.min_status {
+                StatusSeverity::Info => Some(String::from(logs)),
+                // Logs that can't be filtered are passed on as they are.
+                min_status => match filter_status_logs(logs, min_status as i64) {
+                    Some(logs) => Some(logs).filter(|logs| logs != "[]"),
+                    None => Some(String::from(logs)),
+                },
+            };
+            match logs {
+                Some(logs) if !decorations.is_empty() => logger
+                    .plugin
+                    .log_status(&decorate_status_logs(&logs, &decorations).unwrap_or(logs)),
+                Some(logs) => logger.plugin.log_status(&logs),
+                None => Ok(()),
+            }
+        } else {
+            // Init, health and other notifications need no handling.
+            Ok(())
+        };
+
+        response(res.map(|_| ExtensionPluginResponse::new()))
+    }
+
+    fn call_distributed(&self, item: &str, request: &ExtensionPluginRequest) -> ExtensionResponse {
+        let plugin = match self.distributed.get(item) {
+            Some(plugin) => plugin,
+            None => return failure(format!("Unknown distributed plugin: {}", item)),
+        };
+
+        match request.get("action").map(String::as_str) {
+            Some("getQueries") => response(plugin.get_queries().map(|queries| {
+                let mut row = BTreeMap::new();
+                row.insert(String::from("results"), queries_json(&queries));
+                vec![row]
+            })),
+            Some("writeResults") => {
+                let results = request.get("results").map(String::as_str).unwrap_or("{}");
+                response(
+                    plugin
+                        .write_results(results)
+                        .map(|_| ExtensionPluginResponse::new()),
+                )
+            }
+            action => failure(format!("Unsupported distributed action: {:?}", action)),
+        }
+    }
+}
+
+/// A builder for the registry an extension registers with, which lets an
+/// SDK version be attached to each route.
+///
+/// The version is sent as an extra route entry of the form
+/// `{"id": "version", "version": "<sdk version>"}`, which osquery versions
+/// that don't know about it skip over. Newer osquery versions can use it to
+/// gate how they treat the plugin.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use osquery_rs::osquery::*;
+/// # use osquery_rs::osquery::plugin::RegistryBuilder;
+/// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+/// let registry = RegistryBuilder::new()
+///     .route("table", "example", ExtensionPluginResponse::new())
+///     .version("table", "example", "5.0.0")
+///     .build(client.sdk_version());
+/// client.register_extension_with_registry("example", registry).unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct RegistryBuilder {
+    registry: BTreeMap<String, BTreeMap<String, Route>>,
+}
+
+/// The routes of a single plugin and the SDK version set for it.
+#[derive(Debug, Clone, Default)]
+struct Route {
+    routes: ExtensionPluginResponse,
+    version: Option<String>,
+}
+
+impl RegistryBuilder {
+    /// Returns an empty registry builder.
+    pub fn new() -> Self {
+        RegistryBuilder::default()
+    }
+
+    /// Adds the routes of the plugin `item` in `registry`, replacing the
+    /// routes already added for it.
+    pub fn route(mut self, registry: &str, item: &str, routes: ExtensionPluginResponse) -> Self {
+        self.registry
+            .entry(String::from(registry))
+            .or_default()
+            .entry(String::from(item))
+            .or_default()
+            .routes = routes;
+        self
+    }
+
+    /// Sets the SDK version of the plugin `item` in `registry`, adding the
+    /// plugin without routes if it wasn't added yet.
+    pub fn version(mut self, registry: &str, item: &str, version: &str) -> Self {
+        self.registry
+            .entry(String::from(registry))
+            .or_default()
+            .entry(String::from(item))
+            .or_default()
+            .version = Some(String::from(version));
+        self
+    }
+
+    /// Builds the registry, giving every plugin without a version of its
+    /// own the `default_version`, usually the SDK version of the client.
+    pub fn build(&self, default_version: &str) -> ExtensionRegistry {
+        self.registry
+            .iter()
+            .map(|(name, items)| {
+                let items = items
+                    .iter()
+                    .map(|(item, route)| {
+                        let version = route.version.as_deref().unwrap_or(default_version);
+                        let mut routes = route.routes.clone();
+                        routes.push(version_route(version));
+                        (item.clone(), routes)
+                    })
+                    .collect();
+                (name.clone(), items)
+            })
+            .collect()
+    }
+}
+
+impl ExtensionSyncHandler for Plugins {
+    fn handle_ping(&self) -> thrift::Result<ExtensionStatus> {
+        Ok(ok_status())
+    }
+
+    fn handle_call(
+        &self,
+        registry: String,
+        item: String,
+        request: ExtensionPluginRequest,
+    ) -> thrift::Result<ExtensionResponse> {
+        Ok(match registry.as_str() {
+            TABLE_REGISTRY => self.call_table(&item, &request),
+            CONFIG_REGISTRY => self.call_config(&item, &request),
+            LOGGER_REGISTRY => self.call_logger(&item, &request),
+            DISTRIBUTED_REGISTRY => self.call_distributed(&item, &request),
+            _ => failure(format!("Unknown registry: {}", registry)),
+        })
+    }
+
+    fn handle_shutdown(&self) -> thrift::Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns the routes of a table, which describe its columns.
+fn table_routes(table: &dyn TablePlugin) -> ExtensionPluginResponse {
+    table
+        .columns()
+        .into_iter()
+        .map(|column| {
+            let mut route = BTreeMap::new();
+            route.insert(String::from("id"), String::from("column"));
+            route.insert(String::from("name"), column.name);
+            route.insert(String::from("type"), column.col_type.to_string());
+            route.insert(String::from("op"), String::from("0"));
+            route
+        })
+        .collect()
+}
+
+/// Returns the row osquery expects in answer to a successful insert, which
+/// carries the row id the insert asked for, if any.
+fn insert_status(request: &ExtensionPluginRequest) -> BTreeMap<String, String> {
+    let mut status = BTreeMap::new();
+    status.insert(String::from("status"), String::from("success"));
+    if let Some(id) = request.get("id") {
+        status.insert(String::from("id"), id.clone());
+    }
+    status
+}
+
+/// Returns the queries in the JSON form osquery reads them in, such as
+/// `{"queries":{"q1":"SELECT * FROM uptime"}}`.
+fn queries_json(queries: &BTreeMap<String, String>) -> String {
+    let queries: Vec<String> = queries
+        .iter()
+        .map(|(name, sql)| format!("\"{}\":\"{}\"", escape_json(name), escape_json(sql)))
+        .collect();
+    format!("{{\"queries\":{{{}}}}}", queries.join(","))
+}
+
+fn version_route(version: &str) -> BTreeMap<String, String> {
+    let mut route = BTreeMap::new();
+    route.insert(String::from("id"), String::from("version"));
+    route.insert(String::from("version"), String::from(version));
+    route
+}
+
+fn ok_status() -> ExtensionStatus {
+    ExtensionStatus::new(ExtensionCode::ExtSuccess as i32, String::from("OK"), None)
+}
+
+fn success(rows: ExtensionPluginResponse) -> ExtensionResponse {
+    ExtensionResponse::new(ok_status(), rows)
+}
+
+fn failure(message: String) -> ExtensionResponse {
+    ExtensionResponse::new(
+        ExtensionStatus::new(ExtensionCode::ExtFailed as i32, message, None),
+        ExtensionPluginResponse::new(),
+    )
+}
+
+fn response(res: Result<ExtensionPluginResponse, String>) -> ExtensionResponse {
+    match res {
+        Ok(rows) => success(rows),
+        Err(message) => failure(message),
+    }
+}
@@ -0,0 +1,53 @@
+use crate::osquery::error::OsqueryError;
+use crate::osquery::osquery::ExtensionPluginResponse;
+use crate::osquery::register_extension::OsqueryClient;
+
+use std::time::{Duration, Instant};
+
+/// Where the time of a query went, as measured by `query_profiled`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timings {
+    /// The time from sending the query until the first bytes of the
+    /// response arrived, which is mostly osquery running the query.
+    pub wait: Duration,
+
+    /// The time from the first bytes of the response arriving until the
+    /// rows were decoded. This covers reading the rest of the response as
+    /// well as decoding it, since the two are interleaved.
+    pub decode: Duration,
+}
+
+impl OsqueryClient {
+    /// Runs the query like `query`, and measures how long was spent
+    /// waiting for osquery compared to reading and decoding the response.
+    /// This tells apart a slow query in osquery from a large result that
+    /// is slow to decode on the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// let (rows, timings) = client.query_profiled("SELECT * FROM processes").unwrap();
+    /// println!("{} rows, {:?}", rows.len(), timings);
+    /// ```
+    pub fn query_profiled(
+        &mut self,
+        query: &str,
+    ) -> Result<(ExtensionPluginResponse, Timings), OsqueryError> {
+        let started = Instant::now();
+        let rows = self.query(query)?;
+        let finished = Instant::now();
+
+        let first_read = self.last_first_read().unwrap_or(finished);
+        let timings = Timings {
+            wait: first_read.saturating_duration_since(started),
+            decode: finished.saturating_duration_since(first_read),
+        };
+        Ok((rows, timings))
+    }
+}
@@ -0,0 +1,593 @@
+use log::warn;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use thrift::protocol::{
+    verify_expected_message_type, verify_expected_sequence_number, verify_expected_service_call,
+    TBinaryInputProtocol, TFieldIdentifier, TInputProtocol, TListIdentifier, TMapIdentifier,
+    TMessageIdentifier, TMessageType, TOutputProtocol, TSetIdentifier, TStructIdentifier, TType,
+};
+use thrift::transport::TReadTransport;
+use thrift::{
+    ApplicationError, ApplicationErrorKind, ProtocolError, ProtocolErrorKind, TThriftClient,
+};
+
+use crate::osquery::osquery::{
+    ExtensionStatus, TExtensionManagerSyncClient, TExtensionManagerSyncClientMarker,
+    TExtensionSyncClientMarker,
+};
+
+/// The largest string or binary value accepted from the peer.
+const MAX_STRING_SIZE: usize = 256 * 1024 * 1024;
+
+/// The largest number of elements accepted in a list, set or map, which
+/// bounds what the generated code preallocates for a container.
+const MAX_CONTAINER_SIZE: i32 = 1 << 24;
+
+/// A binary input protocol that validates every length read off the wire
+/// before acting on it. The thrift binary protocol trusts these lengths and
+/// allocates for them upfront, so a malformed message with a negative or
+/// huge length panics or exhausts memory instead of returning an error.
+///
+/// While `raw_strings` is set, strings are not decoded as UTF-8. Every byte
+/// is instead mapped to the char with the same code point, which keeps
+/// binary values intact so they can be turned back into bytes with
+/// `raw_string_bytes`.
+///
+/// When `utf8_lossy` is set, strings that aren't valid UTF-8 have the
+/// invalid bytes replaced rather than failing the call, with a warning
+/// naming the column when the string is the value of a map, as the values
+/// of result rows are.
+pub(crate) struct CheckedInputProtocol<T: TReadTransport> {
+    inner: TBinaryInputProtocol<T>,
+    strict: bool,
+    raw_strings: Arc<AtomicBool>,
+    utf8_lossy: bool,
+
+    /// While lossy, the key of the map entry being read, if the next string
+    /// is its value.
+    map_key: Option<String>,
+
+    /// While lossy, whether the next string read is the key of a map entry.
+    next_is_key: bool,
+}
+
+impl<T: TReadTransport> CheckedInputProtocol<T> {
+    pub(crate) fn new(
+        transport: T,
+        strict: bool,
+        raw_strings: Arc<AtomicBool>,
+        utf8_lossy: bool,
+    ) -> Self {
+        CheckedInputProtocol {
+            inner: TBinaryInputProtocol::new(transport, strict),
+            strict,
+            raw_strings,
+            utf8_lossy,
+            map_key: None,
+            next_is_key: false,
+        }
+    }
+
+    /// Reads `size` bytes off the transport. The buffer grows as the bytes
+    /// arrive, so a bogus size fails on the end of the stream rather than
+    /// allocating for it.
+    fn read_sized(&mut self, size: i32) -> thrift::Result<Vec<u8>> {
+        let size = checked_size(size, MAX_STRING_SIZE)?;
+        let mut buf = Vec::new();
+        (&mut self.inner.transport)
+            .take(size as u64)
+            .read_to_end(&mut buf)?;
+
+        if buf.len() != size {
+            return Err(invalid_data("unexpected end of stream"));
+        }
+        Ok(buf)
+    }
+}
+
+impl<T: TReadTransport> TInputProtocol for CheckedInputProtocol<T> {
+    fn read_message_begin(&mut self) -> thrift::Result<TMessageIdentifier> {
+        let header = self.inner.read_i32()?;
+        if header < 0 {
+            // A strict message starts with the version followed by the
+            // message type in the lowest byte.
+            if (header as u32) & 0xffff_0000 != 0x8001_0000 {
+                return Err(thrift::Error::Protocol(ProtocolError {
+                    kind: ProtocolErrorKind::BadVersion,
+                    message: format!("received bad version: {:#x}", header),
+                }));
+            }
+
+            let message_type = TMessageType::try_from(header as u8)?;
+            let name = self.read_string()?;
+            let sequence_number = self.read_i32()?;
+            Ok(TMessageIdentifier::new(name, message_type, sequence_number))
+        } else if self.strict {
+            Err(thrift::Error::Protocol(ProtocolError {
+                kind: ProtocolErrorKind::BadVersion,
+                message: String::from("received message without a version"),
+            }))
+        } else {
+            // Without the version the header is the length of the name.
+            let name = String::from_utf8(self.read_sized(header)?)?;
+            let message_type = TMessageType::try_from(self.read_byte()?)?;
+            let sequence_number = self.read_i32()?;
+            Ok(TMessageIdentifier::new(name, message_type, sequence_number))
+        }
+    }
+
+    fn read_message_end(&mut self) -> thrift::Result<()> {
+        self.inner.read_message_end()
+    }
+
+    fn read_struct_begin(&mut self) -> thrift::Result<Option<TStructIdentifier>> {
+        self.inner.read_struct_begin()
+    }
+
+    fn read_struct_end(&mut self) -> thrift::Result<()> {
+        self.inner.read_struct_end()
+    }
+
+    fn read_field_begin(&mut self) -> thrift::Result<TFieldIdentifier> {
+        self.inner.read_field_begin()
+    }
+
+    fn read_field_end(&mut self) -> thrift::Result<()> {
+        self.inner.read_field_end()
+    }
+
+    fn read_bytes(&mut self) -> thrift::Result<Vec<u8>> {
+        let size = self.inner.read_i32()?;
+        self.read_sized(size)
+    }
+
+    fn read_bool(&mut self) -> thrift::Result<bool> {
+        self.inner.read_bool()
+    }
+
+    fn read_i8(&mut self) -> thrift::Result<i8> {
+        self.inner.read_i8()
+    }
+
+    fn read_i16(&mut self) -> thrift::Result<i16> {
+        self.inner.read_i16()
+    }
+
+    fn read_i32(&mut self) -> thrift::Result<i32> {
+        self.inner.read_i32()
+    }
+
+    fn read_i64(&mut self) -> thrift::Result<i64> {
+        self.inner.read_i64()
+    }
+
+    fn read_double(&mut self) -> thrift::Result<f64> {
+        self.inner.read_double()
+    }
+
+    fn read_string(&mut self) -> thrift::Result<String> {
+        let bytes = self.read_bytes()?;
+        if self.raw_strings.load(Ordering::Relaxed) {
+            return Ok(bytes.into_iter().map(char::from).collect());
+        }
+        if !self.utf8_lossy {
+            return Ok(String::from_utf8(bytes)?);
+        }
+
+        let is_key = self.next_is_key;
+        self.next_is_key = !is_key;
+        let column = self.map_key.take();
+        let s = match String::from_utf8(bytes) {
+            Ok(s) => s,
+            Err(e) => {
+                match &column {
+                    Some(column) => warn!("Replaced invalid UTF-8 in column {:?}", column),
+                    None => warn!("Replaced invalid UTF-8 in a string"),
+                }
+                String::from_utf8_lossy(e.as_bytes()).into_owned()
+            }
+        };
+        if is_key {
+            self.map_key = Some(s.clone());
+        }
+        Ok(s)
+    }
+
+    fn read_list_begin(&mut self) -> thrift::Result<TListIdentifier> {
+        let ident = self.inner.read_list_begin()?;
+        checked_size(ident.size, MAX_CONTAINER_SIZE as usize)?;
+        Ok(ident)
+    }
+
+    fn read_list_end(&mut self) -> thrift::Result<()> {
+        self.inner.read_list_end()
+    }
+
+    fn read_set_begin(&mut self) -> thrift::Result<TSetIdentifier> {
+        let ident = self.inner.read_set_begin()?;
+        checked_size(ident.size, MAX_CONTAINER_SIZE as usize)?;
+        Ok(ident)
+    }
+
+    fn read_set_end(&mut self) -> thrift::Result<()> {
+        self.inner.read_set_end()
+    }
+
+    fn read_map_begin(&mut self) -> thrift::Result<TMapIdentifier> {
+        let ident = self.inner.read_map_begin()?;
+        checked_size(ident.size, MAX_CONTAINER_SIZE as usize)?;
+        self.next_is_key = true;
+        Ok(ident)
+    }
+
+    fn read_map_end(&mut self) -> thrift::Result<()> {
+        self.next_is_key = false;
+        self.map_key = None;
+        self.inner.read_map_end()
+    }
+
+    fn read_byte(&mut self) -> thrift::Result<u8> {
+        self.inner.read_byte()
+    }
+}
+
+/// An extension manager client whose sequence ids are shared with the
+/// `OsqueryClient`, so the id of the last call can be read and the id of
+/// the next call chosen by the caller. The generated client code checks
+/// that every response carries the id of its call.
+pub(crate) struct SequencedClient<IP: TInputProtocol, OP: TOutputProtocol> {
+    in_proto: IP,
+    out_proto: OP,
+    seqid: Arc<AtomicI32>,
+    next_seqid: Arc<Mutex<Option<i32>>>,
+}
+
+impl<IP: TInputProtocol, OP: TOutputProtocol> SequencedClient<IP, OP> {
+    pub(crate) fn new(
+        in_proto: IP,
+        out_proto: OP,
+        seqid: Arc<AtomicI32>,
+        next_seqid: Arc<Mutex<Option<i32>>>,
+    ) -> Self {
+        SequencedClient {
+            in_proto,
+            out_proto,
+            seqid,
+            next_seqid,
+        }
+    }
+}
+
+impl<IP: TInputProtocol, OP: TOutputProtocol> TThriftClient for SequencedClient<IP, OP> {
+    fn i_prot_mut(&mut self) -> &mut dyn TInputProtocol {
+        &mut self.in_proto
+    }
+
+    fn o_prot_mut(&mut self) -> &mut dyn TOutputProtocol {
+        &mut self.out_proto
+    }
+
+    fn sequence_number(&self) -> i32 {
+        self.seqid.load(Ordering::Relaxed)
+    }
+
+    fn increment_sequence_number(&mut self) -> i32 {
+        let seqid = match self.next_seqid.lock().unwrap().take() {
+            Some(seqid) => seqid,
+            // Sequence ids are never negative on the wire.
+            None => self.sequence_number().checked_add(1).unwrap_or(1),
+        };
+        self.seqid.store(seqid, Ordering::Relaxed);
+        seqid
+    }
+}
+
+impl<IP: TInputProtocol, OP: TOutputProtocol> TExtensionManagerSyncClientMarker
+    for SequencedClient<IP, OP>
+{
+}
+
+impl<IP: TInputProtocol, OP: TOutputProtocol> TExtensionSyncClientMarker
+    for SequencedClient<IP, OP>
+{
+}
+
+/// Returns the bytes of a string read while `raw_strings` was set.
+pub(crate) fn raw_string_bytes(s: &str) -> Vec<u8> {
+    s.chars().map(|c| c as u8).collect()
+}
+
+/// Returns the size as a `usize` if it is neither negative nor above `max`.
+fn checked_size(size: i32, max: usize) -> thrift::Result<usize> {
+    if size < 0 {
+        return Err(thrift::Error::Protocol(ProtocolError {
+            kind: ProtocolErrorKind::NegativeSize,
+            message: format!("received negative size {}", size),
+        }));
+    }
+
+    if size as usize > max {
+        return Err(thrift::Error::Protocol(ProtocolError {
+            kind: ProtocolErrorKind::SizeLimit,
+            message: format!("received size {} above the limit of {}", size, max),
+        }));
+    }
+
+    Ok(size as usize)
+}
+
+fn invalid_data(message: &str) -> thrift::Error {
+    thrift::Error::Protocol(ProtocolError {
+        kind: ProtocolErrorKind::InvalidData,
+        message: String::from(message),
+    })
+}
+
+/// A client for the extension manager that also exposes the protocols it
+/// speaks, for the calls that decode their reply as it is read.
+pub(crate) trait ManagerClient: TExtensionManagerSyncClient + TThriftClient + Send {}
+
+impl<C: TExtensionManagerSyncClient + TThriftClient + Send> ManagerClient for C {}
+
+/// Sends a query and reads its reply up to the start of the list of rows,
+/// which is then left for `read_row` to decode one row at a time. Returns
+/// the status read so far and the number of rows in the list. When the
+/// reply has no list of rows, the whole reply is read and `None` is
+/// returned for it.
+pub(crate) fn send_lazy_query(
+    client: &mut dyn ManagerClient,
+    sql: &str,
+) -> thrift::Result<(Option<ExtensionStatus>, Option<i32>)> {
+    client.increment_sequence_number();
+    let message = TMessageIdentifier::new("query", TMessageType::Call, client.sequence_number());
+    let out = client.o_prot_mut();
+    out.write_message_begin(&message)?;
+    out.write_struct_begin(&TStructIdentifier::new("ExtensionManager_query_args"))?;
+    out.write_field_begin(&TFieldIdentifier::new("sql", TType::String, 1))?;
+    out.write_string(sql)?;
+    out.write_field_end()?;
+    out.write_field_stop()?;
+    out.write_struct_end()?;
+    out.write_message_end()?;
+    out.flush()?;
+
+    let message = client.i_prot_mut().read_message_begin()?;
+    verify_expected_sequence_number(client.sequence_number(), message.sequence_number)?;
+    verify_expected_service_call("query", &message.name)?;
+    if message.message_type == TMessageType::Exception {
+        let remote = thrift::Error::read_application_error_from_in_protocol(client.i_prot_mut())?;
+        client.i_prot_mut().read_message_end()?;
+        return Err(thrift::Error::Application(remote));
+    }
+    verify_expected_message_type(TMessageType::Reply, message.message_type)?;
+
+    // The reply is a result struct whose field 0 holds the response, which
+    // holds the status in field 1 and the rows in field 2.
+    let i_prot = client.i_prot_mut();
+    i_prot.read_struct_begin()?;
+    let mut status = None;
+    let mut found = false;
+    loop {
+        let field = i_prot.read_field_begin()?;
+        if field.field_type == TType::Stop {
+            break;
+        }
+        if field.id == Some(0) && field.field_type == TType::Struct {
+            found = true;
+            i_prot.read_struct_begin()?;
+            if let Some(size) = read_response_fields(i_prot, &mut status)? {
+                return Ok((status, Some(size)));
+            }
+            i_prot.read_struct_end()?;
+        } else {
+            i_prot.skip(field.field_type)?;
+        }
+        i_prot.read_field_end()?;
+    }
+    i_prot.read_struct_end()?;
+    i_prot.read_message_end()?;
+
+    if !found {
+        return Err(thrift::Error::Application(ApplicationError::new(
+            ApplicationErrorKind::MissingResult,
+            "no result received for query",
+        )));
+    }
+    Ok((status, None))
+}
+
+/// Reads one row off the list `send_lazy_query` stopped at.
+pub(crate) fn read_row(client: &mut dyn ManagerClient) -> thrift::Result<BTreeMap<String, String>> {
+    let mut row = BTreeMap::new();
+    read_row_cells(client, &mut |column, value| {
+        row.insert(column, value);
+    })?;
+    Ok(row)
+}
+
+/// Reads one row off the list `send_lazy_query` stopped at, handing every
+/// column and value to `cell` as it is decoded.
+pub(crate) fn read_row_cells(
+    client: &mut dyn ManagerClient,
+    cell: &mut dyn FnMut(String, String),
+) -> thrift::Result<()> {
+    let i_prot = client.i_prot_mut();
+    let map = i_prot.read_map_begin()?;
+    for _ in 0..map.size {
+        let column = i_prot.read_string()?;
+        let value = i_prot.read_string()?;
+        cell(column, value);
+    }
+    i_prot.read_map_end()
+}
+
+/// Skips `count` rows of the list `send_lazy_query` stopped at.
+pub(crate) fn skip_rows(client: &mut dyn ManagerClient, count: i32) -> thrift::Result<()> {
+    for _ in 0..count {
+        client.i_prot_mut().skip(TType::Map)?;
+    }
+    Ok(())
+}
+
+/// Reads the rest of the reply once every row of the list was read,
+/// returning the status if it came after the rows.
+pub(crate) fn finish_lazy_query(
+    client: &mut dyn ManagerClient,
+) -> thrift::Result<Option<ExtensionStatus>> {
+    let i_prot = client.i_prot_mut();
+    i_prot.read_list_end()?;
+    i_prot.read_field_end()?;
+
+    let mut status = None;
+    if let Some(size) = read_response_fields(i_prot, &mut status)? {
+        // A second list of rows is as invalid as it is unlikely.
+        return Err(invalid_data(&format!(
+            "received a second list of {} rows",
+            size
+        )));
+    }
+    i_prot.read_struct_end()?;
+    i_prot.read_field_end()?;
+    loop {
+        let field = i_prot.read_field_begin()?;
+        if field.field_type == TType::Stop {
+            break;
+        }
+        i_prot.skip(field.field_type)?;
+        i_prot.read_field_end()?;
+    }
+    i_prot.read_struct_end()?;
+    i_prot.read_message_end()?;
+    Ok(status)
+}
+
+/// Reads the fields of a response struct, reading the status into `status`,
+/// until either the list of rows, whose size is returned with the list left
+/// unread, or the end of the struct.
+fn read_response_fields(
+    i_prot: &mut dyn TInputProtocol,
+    status: &mut Option<ExtensionStatus>,
+) -> thrift::Result<Option<i32>> {
+    loop {
+        let field = i_prot.read_field_begin()?;
+        match (field.id, field.field_type) {
+            (_, TType::Stop) => return Ok(None),
+            (Some(1), TType::Struct) => {
+                *status = Some(ExtensionStatus::read_from_in_protocol(i_prot)?)
+            }
+            (Some(2), TType::List) => return Ok(Some(i_prot.read_list_begin()?.size)),
+            (_, field_type) => i_prot.skip(field_type)?,
+        }
+        i_prot.read_field_end()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::osquery::builder::OsqueryClientBuilder;
+    use crate::osquery::error::{OsqueryError, Phase};
+    use crate::osquery::osquery::{ExtensionCode, ExtensionStatus};
+
+    use std::os::unix::net::UnixStream;
+    use std::thread::{self, JoinHandle};
+    use thrift::protocol::{
+        TBinaryInputProtocol, TBinaryOutputProtocol, TFieldIdentifier, TInputProtocol,
+        TMessageIdentifier, TMessageType, TOutputProtocol, TStructIdentifier, TType,
+    };
+
+    /// Answers every ping on `stream` with a successful status carrying the
+    /// sequence id of the call plus `offset`, until the client hangs up.
+    /// Returns the number of calls answered.
+    fn answer_pings(stream: UnixStream, offset: i32) -> JoinHandle<usize> {
+        thread::spawn(move || {
+            let mut i_prot = TBinaryInputProtocol::new(stream.try_clone().unwrap(), true);
+            let mut o_prot = TBinaryOutputProtocol::new(stream, true);
+            let mut calls = 0;
+            while let Ok(call) = i_prot.read_message_begin() {
+                i_prot.skip(TType::Struct).unwrap();
+                i_prot.read_message_end().unwrap();
+                calls += 1;
+
+                let reply = TMessageIdentifier::new(
+                    call.name,
+                    TMessageType::Reply,
+                    call.sequence_number + offset,
+                );
+                let status = ExtensionStatus::new(
+                    ExtensionCode::ExtSuccess as i32,
+                    String::from("OK"),
+                    None,
+                );
+                o_prot.write_message_begin(&reply).unwrap();
+                o_prot
+                    .write_struct_begin(&TStructIdentifier::new("Extension_ping_result"))
+                    .unwrap();
+                o_prot
+                    .write_field_begin(&TFieldIdentifier::new("result_value", TType::Struct, 0))
+                    .unwrap();
+                status.write_to_out_protocol(&mut o_prot).unwrap();
+                o_prot.write_field_end().unwrap();
+                o_prot.write_field_stop().unwrap();
+                o_prot.write_struct_end().unwrap();
+                o_prot.write_message_end().unwrap();
+                o_prot.flush().unwrap();
+            }
+            calls
+        })
+    }
+
+    #[test]
+    fn reply_with_another_seqid_is_a_desync() {
+        let (client_end, server_end) = UnixStream::pair().unwrap();
+        let server = answer_pings(server_end, 1);
+
+        let mut client = OsqueryClientBuilder::new("").build_stream(client_end);
+        match client.ping() {
+            Err(OsqueryError::DesyncedConnection {
+                phase: Phase::Ping, ..
+            }) => {}
+            res => panic!("expected a desynced connection, got {:?}", res),
+        }
+        assert!(client.needs_reconnect());
+
+        drop(client);
+        assert_eq!(server.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn call_after_a_desync_is_refused() {
+        let (client_end, server_end) = UnixStream::pair().unwrap();
+        let server = answer_pings(server_end, 1);
+
+        let mut client = OsqueryClientBuilder::new("").build_stream(client_end);
+        assert!(client.ping().is_err());
+        // The reply to this call could only be matched against the wrong
+        // call, so it must not be sent at all.
+        match client.ping() {
+            Err(OsqueryError::DesyncedConnection {
+                phase: Phase::Ping, ..
+            }) => {}
+            res => panic!("expected a desynced connection, got {:?}", res),
+        }
+
+        drop(client);
+        assert_eq!(server.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn reply_with_the_call_seqid_is_accepted() {
+        let (client_end, server_end) = UnixStream::pair().unwrap();
+        let server = answer_pings(server_end, 0);
+
+        let mut client = OsqueryClientBuilder::new("").build_stream(client_end);
+        assert!(client.ping().unwrap());
+        assert!(client.ping().unwrap());
+        assert!(!client.needs_reconnect());
+
+        drop(client);
+        assert_eq!(server.join().unwrap(), 2);
+    }
+}
@@ -1,23 +1,150 @@
 extern crate thrift;
 
+use crate::osquery::backoff::Backoff;
+use crate::osquery::builder::OsqueryClientBuilder;
+use crate::osquery::error::{is_desync, OsqueryError, Phase};
+#[cfg(feature = "health-endpoint")]
+use crate::osquery::health_endpoint::HealthEndpoint;
+use crate::osquery::keepalive::Keepalive;
+use crate::osquery::lease::Lease;
 use crate::osquery::osquery;
+use crate::osquery::protocol::{
+    finish_lazy_query, raw_string_bytes, read_row, read_row_cells, send_lazy_query, skip_rows,
+    CheckedInputProtocol, ManagerClient, SequencedClient,
+};
+use crate::osquery::transport::{peer_uid, set_buffer_size, CountingReader, DeadlineReader};
 
+use log::{debug, info, log, warn, Level};
 use osquery::*;
 use std::collections::BTreeMap;
-use std::error::Error;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::os::unix::net::UnixStream;
-use thrift::protocol::{TBinaryInputProtocol, TBinaryOutputProtocol};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use thrift::protocol::TBinaryOutputProtocol;
+use thrift::TransportErrorKind;
+
+/// The upper bound for the delay before the first registration retry.
+const REGISTER_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The upper bound the delay between registration retries grows to.
+const REGISTER_MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// How often a sleeping client checks whether it was cancelled.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The query returning the decorations merged by `query_decorated`.
+const DECORATIONS_QUERY: &str = "SELECT * FROM osquery_info";
+
+/// The prefix for the decoration columns merged by `query_decorated`.
+const DECORATION_PREFIX: &str = "decorations_";
 
 /// The osquery client struct which holds a thrift client to communicate
 /// with the osquery extension manager socket.
 pub struct OsqueryClient {
     /// The client object which is used to communicate with the osquery
     /// extensions manager socket.
-    client: Box<dyn TExtensionManagerSyncClient>,
+    client: Box<dyn ManagerClient>,
 
     /// The uuid of the extension which is sent by the osquery extension
     /// manager when the plugin is registered.
     uuid: i64,
+
+    /// The name the extension was registered with.
+    name: String,
+
+    /// The settings the client was built with.
+    config: OsqueryClientBuilder,
+
+    /// The number of bytes read from the socket for the most recent call.
+    bytes_read: Arc<AtomicUsize>,
+
+    /// When the first bytes of the response to the most recent call arrived.
+    first_read: Arc<Mutex<Option<Instant>>>,
+
+    /// A handle on the socket, used to adjust its timeouts. This is `None`
+    /// when the client was built on a stream that isn't a unix socket.
+    socket: Option<UnixStream>,
+
+    /// The deadline for the call in progress, if it has one.
+    deadline: Arc<Mutex<Option<Instant>>>,
+
+    /// Set while strings in responses are read as raw bytes.
+    raw_strings: Arc<AtomicBool>,
+
+    /// The thrift sequence id of the most recent call.
+    seqid: Arc<AtomicI32>,
+
+    /// The thrift sequence id to send with the next call, if chosen by the
+    /// caller.
+    next_seqid: Arc<Mutex<Option<i32>>>,
+
+    /// The id of the most recent call.
+    request_id: u64,
+
+    /// The phase of the most recent call.
+    phase: Phase,
+
+    /// Set when a call was aborted part way and the connection can no longer
+    /// be trusted to be in sync with the extension manager.
+    needs_reconnect: bool,
+
+    /// Set when the extension manager closed the connection.
+    disconnected: bool,
+
+    /// The background pings, if enabled.
+    keepalive: Option<Keepalive>,
+
+    /// The lease on the registration, if enabled.
+    lease: Option<Lease>,
+
+    /// Set once the prelude was sent over the connection.
+    prelude_sent: bool,
+
+    /// How long the most recent registration took.
+    last_registration_duration: Option<Duration>,
+
+    /// The HTTP health endpoint, if enabled.
+    #[cfg(feature = "health-endpoint")]
+    health: Option<HealthEndpoint>,
+}
+
+/// What a client registered with the extension manager.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registration {
+    /// The name the extension was registered with.
+    pub name: String,
+
+    /// The uuid the extension manager assigned to the extension.
+    pub uuid: ExtensionRouteUUID,
+
+    /// The SDK version the extension was registered with.
+    pub sdk_version: String,
+
+    /// The path of the extension manager socket, which is empty for a client
+    /// built over a stream.
+    pub socket_path: String,
+}
+
+/// The state of the connection between a client and the extension manager.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The extension manager closed the connection.
+    Disconnected,
+
+    /// The client is connected but no extension is registered.
+    Connected,
+
+    /// The client is connected and registered an extension with the uuid.
+    Registered { uuid: ExtensionRouteUUID },
+
+    /// A call failed part way and left the connection out of sync with the
+    /// extension manager.
+    Broken,
 }
 
 impl OsqueryClient {
@@ -25,29 +152,204 @@ impl OsqueryClient {
     /// the thrift RPC. The function accepts a file path, without the
     /// protocol.
     ///
+    /// If the connection is refused because of the socket permissions, the
+    /// returned error carries the current mode and owner of the socket file.
+    /// A path that exists but isn't a socket, such as the osquery database
+    /// directory, is reported as `OsqueryError::NotASocket`.
+    ///
     /// # Arguments
     ///
     /// * `name` - A string that holds the socket file path
     ///
     /// # Examples
     ///
-    /// ```
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
     /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
-    /// client.register_plugin("plugin_name");
+    /// client.register_extension("plugin_name").unwrap();
     /// let _ = client.ping();
     /// ```
     ///
-    pub fn new(socket_file: &str) -> Option<Self> {
-        let socket_tx = UnixStream::connect(socket_file).unwrap();
-        let socket_rx = socket_tx.try_clone().unwrap();
+    pub fn new(socket_file: &str) -> Result<Self, OsqueryError> {
+        OsqueryClientBuilder::new(socket_file).build()
+    }
 
-        let in_proto = TBinaryInputProtocol::new(socket_tx, true);
-        let out_proto = TBinaryOutputProtocol::new(socket_rx, true);
+    /// Returns a client for running queries against osquery without ever
+    /// registering an extension, the way `osqueryi` style tools inspect a
+    /// running osquery without leaving a trace in `osquery_extensions`.
+    ///
+    /// A client in inspect mode can `query`, in all its forms, `ping` and
+    /// read `extensions`, `options` and the columns of a query. The
+    /// `register_extension` calls, and anything built on them such as
+    /// `tail_logs`, return `OsqueryError::InspectMode` without contacting
+    /// osquery, and `deregister_extension` returns
+    /// `OsqueryError::NotRegistered`. See `OsqueryClientBuilder::inspect`
+    /// for an inspecting client with other settings.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::inspect("/var/osquery/osquery.em").unwrap();
+    /// let rows = client.query("SELECT * FROM osquery_info").unwrap();
+    /// ```
+    pub fn inspect(socket_file: &str) -> Result<Self, OsqueryError> {
+        OsqueryClientBuilder::new(socket_file).inspect(true).build()
+    }
+
+    /// Returns a builder for a client connecting to the given socket file,
+    /// for when the defaults used by `new` need to be changed.
+    pub fn builder(socket_file: &str) -> OsqueryClientBuilder {
+        OsqueryClientBuilder::new(socket_file)
+    }
+
+    /// Returns a new client talking to the extension manager over an already
+    /// connected stream, such as a forwarded socket or an in-memory pipe,
+    /// with the default settings. See `OsqueryClientBuilder::build_stream`
+    /// to change them.
+    ///
+    /// Socket timeouts can't be set on an arbitrary stream, so a deadline
+    /// given to `query_deadline` is only checked between reads.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let stream = std::net::TcpStream::connect("127.0.0.1:9000").unwrap();
+    /// let mut client = OsqueryClient::from_stream(stream);
+    /// let _ = client.ping();
+    /// ```
+    pub fn from_stream<S>(stream: S) -> Self
+    where
+        S: io::Read + io::Write + Send + 'static,
+    {
+        OsqueryClientBuilder::new("").build_stream(stream)
+    }
+
+    /// Connects to the socket and sets up the client as configured by the
+    /// builder. The client's settings hold the path it connected to.
+    pub(crate) fn connect(builder: &OsqueryClientBuilder) -> Result<Self, OsqueryError> {
+        let mut config = builder.clone();
+        config.socket_file = builder.resolve_socket_file();
+        let socket_file = config.socket_file.as_str();
+        if let Ok(meta) = fs::metadata(socket_file) {
+            if !meta.file_type().is_socket() {
+                return Err(OsqueryError::NotASocket(String::from(socket_file)));
+            }
+        }
 
-        Some(OsqueryClient {
-            client: Box::new(ExtensionManagerSyncClient::new(in_proto, out_proto)),
+        let socket_tx = UnixStream::connect(socket_file)
+            .map_err(|e| OsqueryError::Connect(connect_error(socket_file, e)))?;
+        if let Some(expected) = config.peer_uid {
+            match peer_uid(&socket_tx).map_err(OsqueryError::Connect)? {
+                Some(actual) if actual != expected => {
+                    return Err(OsqueryError::PeerUid { expected, actual })
+                }
+                Some(_) => {}
+                None => warn!(
+                    "Can't read the peer credentials of {} on this platform, not verifying its uid",
+                    socket_file
+                ),
+            }
+        }
+        // The buffer sizes are only a hint, so failing to set them is fine.
+        if let Some(size) = config.recv_buffer_size {
+            let _ = set_buffer_size(&socket_tx, libc::SO_RCVBUF, size);
+        }
+        if let Some(size) = config.send_buffer_size {
+            let _ = set_buffer_size(&socket_tx, libc::SO_SNDBUF, size);
+        }
+
+        let socket_rx = socket_tx.try_clone().map_err(OsqueryError::Connect)?;
+        let socket = socket_tx.try_clone().map_err(OsqueryError::Connect)?;
+
+        let mut client = Self::with_transport(&config, socket_tx, socket_rx, Some(socket));
+        client.keepalive = config
+            .keepalive
+            .map(|interval| Keepalive::start(&config, interval));
+        client.lease = config.registration_ttl.map(|ttl| {
+            let kept = client
+                .keepalive
+                .as_ref()
+                .map(Keepalive::shared_registration);
+            Lease::start(&config, ttl, kept)
+        });
+        #[cfg(feature = "health-endpoint")]
+        {
+            client.health = match config.health_address {
+                Some(address) => Some(
+                    HealthEndpoint::start(&config, address)
+                        .map_err(OsqueryError::io(Phase::Connect))?,
+                ),
+                None => None,
+            };
+        }
+        Ok(client)
+    }
+
+    /// Sets up the client over the given read and write sides of a stream.
+    /// The socket, if any, is the unix socket behind the stream and is used
+    /// to adjust its timeouts.
+    pub(crate) fn with_transport<R, W>(
+        builder: &OsqueryClientBuilder,
+        reader: R,
+        writer: W,
+        socket: Option<UnixStream>,
+    ) -> Self
+    where
+        R: io::Read + Send + 'static,
+        W: io::Write + Send + 'static,
+    {
+        let bytes_read = Arc::new(AtomicUsize::new(0));
+        let first_read = Arc::new(Mutex::new(None));
+        let deadline = Arc::new(Mutex::new(None));
+        let reader = DeadlineReader::new(
+            reader,
+            socket.as_ref().and_then(|socket| socket.try_clone().ok()),
+            deadline.clone(),
+            builder.shutdown.clone(),
+        );
+        let reader = io::BufReader::with_capacity(builder.read_buffer_capacity, reader);
+        let raw_strings = Arc::new(AtomicBool::new(false));
+        let in_proto = CheckedInputProtocol::new(
+            CountingReader::new(reader, bytes_read.clone(), first_read.clone()),
+            builder.strict,
+            raw_strings.clone(),
+            builder.utf8_lossy,
+        );
+        let writer = io::BufWriter::with_capacity(builder.write_buffer_capacity, writer);
+        let out_proto = TBinaryOutputProtocol::new(writer, builder.strict);
+        let seqid = Arc::new(AtomicI32::new(0));
+        let next_seqid = Arc::new(Mutex::new(None));
+
+        OsqueryClient {
+            client: Box::new(SequencedClient::new(
+                in_proto,
+                out_proto,
+                seqid.clone(),
+                next_seqid.clone(),
+            )),
             uuid: 0i64,
-        })
+            name: String::new(),
+            config: builder.clone(),
+            bytes_read,
+            first_read,
+            socket,
+            deadline,
+            raw_strings,
+            seqid,
+            next_seqid,
+            request_id: 0,
+            phase: Phase::Connect,
+            needs_reconnect: false,
+            disconnected: false,
+            keepalive: None,
+            lease: None,
+            prelude_sent: false,
+            last_registration_duration: None,
+            #[cfg(feature = "health-endpoint")]
+            health: None,
+        }
     }
 
     /// Registers the plugin with the osquery extension manager with the name
@@ -58,84 +360,1476 @@ impl OsqueryClient {
     /// # Arguments
     ///
     /// * `name` - The name of the extension
-    pub fn register_extension(&mut self, name: &str) {
-        let info = osquery::InternalExtensionInfo::new(
-            String::from(name),
-            String::from("0.0.1"),
-            String::from("0.0.0"),
-            String::from("0.0.0"),
-        );
+    pub fn register_extension(&mut self, name: &str) -> Result<(), OsqueryError> {
+        self.check_not_inspecting()?;
+        self.check_name_available(name)?;
+        let info = self.extension_info(name);
 
         let registry = osquery::ExtensionRegistry::default();
-        let res = self.client.as_mut().register_extension(info, registry);
+        self.begin_call("register_extension")?;
+        let started = Instant::now();
+        let res = self
+            .client
+            .as_mut()
+            .register_extension(info, registry.clone());
+        let res = self.end_call(res);
+        self.last_registration_duration = Some(started.elapsed());
 
         match res {
             Err(e) => {
-                eprintln!("Failed to register extension {:?}", e);
+                log!(
+                    self.log_level(Level::Warn),
+                    "Failed to register extension {}",
+                    e
+                );
+                Err(e)
             }
             Ok(ext_status) => {
-                println!("Registered plugin {}", name);
-                self.uuid = ext_status.uuid.unwrap();
+                let uuid = registered_uuid(ext_status)?;
+                self.set_registered(name, uuid, &registry);
+                log!(self.log_level(Level::Info), "Registered plugin {}", name);
+                Ok(())
+            }
+        }
+    }
+
+    /// Registers the extension with the routes in `registry`, so that
+    /// osquery can route plugin calls to it. Unlike `register_extension`,
+    /// a non-success status from the extension manager is returned as an
+    /// error. On success the uuid of the extension is returned.
+    ///
+    /// The registry is checked against the size limit set with
+    /// `OsqueryClientBuilder::max_registry_size` before it is sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the extension
+    /// * `registry` - The plugins provided by the extension
+    pub fn register_extension_with_registry(
+        &mut self,
+        name: &str,
+        registry: ExtensionRegistry,
+    ) -> Result<ExtensionRouteUUID, OsqueryError> {
+        self.check_not_inspecting()?;
+        if let Some(limit) = self.config.max_registry_size {
+            check_registry_size(&registry, limit)?;
+        }
+        self.check_name_available(name)?;
+
+        let info = self.extension_info(name);
+
+        self.begin_call("register_extension")?;
+        let started = Instant::now();
+        let res = self
+            .client
+            .as_mut()
+            .register_extension(info, registry.clone());
+        let status = self.end_call(res);
+        self.last_registration_duration = Some(started.elapsed());
+        let status = status?;
+        if status.code != Some(ExtensionCode::ExtSuccess as i32) {
+            return Err(status_error(status, Phase::Register));
+        }
+
+        let uuid = registered_uuid(status)?;
+        self.set_registered(name, uuid, &registry);
+        log!(self.log_level(Level::Info), "Registered plugin {}", name);
+        Ok(self.uuid)
+    }
+
+    /// Registers the extension like `register_extension`, retrying for as
+    /// long as `deadline` allows while the extension manager reports a
+    /// transient failure. This covers a freshly started osqueryd that
+    /// rejects registrations until it has finished initializing.
+    ///
+    /// A status of `ExtFailed` is treated as transient and retried with an
+    /// exponential backoff with full jitter, while `ExtFatal` is permanent
+    /// and returned right away. Transport errors are not retried either.
+    /// Setting the cancel flag of the client aborts the retries with
+    /// `OsqueryError::Cancelled`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the extension
+    /// * `deadline` - The maximum time to keep retrying for
+    pub fn register_extension_retry(
+        &mut self,
+        name: &str,
+        deadline: Duration,
+    ) -> Result<(), OsqueryError> {
+        self.check_not_inspecting()?;
+        self.check_name_available(name)?;
+        let started = Instant::now();
+        let mut backoff = Backoff::new(
+            REGISTER_INITIAL_BACKOFF,
+            REGISTER_MAX_BACKOFF,
+            self.config.backoff_seed,
+        );
+
+        loop {
+            self.check_cancelled(Phase::Register)?;
+            let info = self.extension_info(name);
+
+            self.begin_call("register_extension")?;
+            let res = self
+                .client
+                .as_mut()
+                .register_extension(info, osquery::ExtensionRegistry::default());
+            let status = self.end_call(res);
+            self.last_registration_duration = Some(started.elapsed());
+            let status = status?;
+
+            let code = status.code.unwrap_or(ExtensionCode::ExtFatal as i32);
+            if code == ExtensionCode::ExtSuccess as i32 {
+                let uuid = registered_uuid(status)?;
+                self.set_registered(name, uuid, &osquery::ExtensionRegistry::default());
+                log!(self.log_level(Level::Info), "Registered plugin {}", name);
+                return Ok(());
             }
+
+            let elapsed = started.elapsed();
+            if code != ExtensionCode::ExtFailed as i32 || elapsed >= deadline {
+                return Err(status_error(status, Phase::Register));
+            }
+
+            self.sleep(
+                backoff.next_delay().min(deadline - elapsed),
+                Phase::Register,
+            )?;
+        }
+    }
+
+    /// Registers the extension like `register_extension`, but first
+    /// deregisters any extension already registered with the same name, so
+    /// a restarted extension can reclaim its identity.
+    ///
+    /// There is no locking between the lookup and the registration, so if
+    /// several instances of the same extension race, the last one to
+    /// register wins and the others are left with stale registrations.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the extension
+    pub fn register_extension_replace(&mut self, name: &str) -> Result<(), OsqueryError> {
+        self.check_not_inspecting()?;
+        let stale: Vec<ExtensionRouteUUID> = self
+            .extensions()?
+            .into_iter()
+            .filter(|(_, info)| info.name.as_deref() == Some(name))
+            .map(|(uuid, _)| uuid)
+            .collect();
+
+        for uuid in stale {
+            self.begin_call("deregister_extension")?;
+            let res = self.client.as_mut().deregister_extension(uuid);
+            self.end_call(res)?;
+        }
+
+        self.register_extension(name)
+    }
+
+    /// Returns the extensions currently registered with the osquery
+    /// extension manager, keyed by their uuid.
+    pub fn extensions(&mut self) -> Result<InternalExtensionList, OsqueryError> {
+        self.begin_call("extensions")?;
+        let res = self.client.as_mut().extensions();
+        self.end_call(res)
+    }
+
+    /// Returns the plugins osquery recorded for the registered extension,
+    /// keyed by registry and plugin name, so that they can be compared with
+    /// the registry that was sent. A plugin that is missing was not accepted.
+    /// osquery only records the names of the plugins, so the routes of every
+    /// plugin are left empty. The registry is empty if the extension isn't
+    /// registered, or osquery no longer lists it.
+    pub fn registered_routes(&mut self) -> Result<ExtensionRegistry, OsqueryError> {
+        let mut registry = ExtensionRegistry::new();
+        if self.uuid == 0 || !self.extensions()?.contains_key(&self.uuid) {
+            return Ok(registry);
+        }
+
+        let rows = self.query(&format!(
+            "SELECT registry, name FROM osquery_registry WHERE owner_uuid = {}",
+            self.uuid
+        ))?;
+        for mut row in rows {
+            let name = row.remove("name").unwrap_or_default();
+            registry
+                .entry(row.remove("registry").unwrap_or_default())
+                .or_default()
+                .insert(name, ExtensionPluginResponse::new());
         }
+        Ok(registry)
+    }
+
+    /// Returns the flags osquery is running with, keyed by their name.
+    pub fn options(&mut self) -> Result<InternalOptionList, OsqueryError> {
+        self.begin_call("options")?;
+        let res = self.client.as_mut().options();
+        self.end_call(res)
     }
 
     /// Ping the osquery extension manager. This can be used to check the
     /// health of the connection.
-    pub fn ping(&mut self) -> Result<bool, Box<dyn Error>> {
+    pub fn ping(&mut self) -> Result<bool, OsqueryError> {
+        self.begin_call("ping")?;
         let res = self.client.as_mut().ping();
+        let res = self.end_call(res);
         match res {
             Err(e) => {
-                eprintln!("Failed to ping the server: {:?}", e);
-                Err(Box::new(e))
+                log!(
+                    self.log_level(Level::Warn),
+                    "Failed to ping the server: {}",
+                    e
+                );
+                Err(e)
             }
             _ => Ok(true),
         }
     }
 
-    /// Deregisters the extension from the osquery extension manager.
-    pub fn deregister_extension(&mut self) -> Result<bool, Box<dyn Error>> {
+    /// Pings the extension manager like `ping`, but gives up after
+    /// `timeout`.
+    pub(crate) fn ping_timeout(&mut self, timeout: Duration) -> Result<bool, OsqueryError> {
+        self.with_timeout(timeout, |client| client.ping())
+    }
+
+    /// Makes the calls in `f`, giving up on them once `timeout` passed.
+    pub(crate) fn with_timeout<T>(
+        &mut self,
+        timeout: Duration,
+        f: impl FnOnce(&mut Self) -> Result<T, OsqueryError>,
+    ) -> Result<T, OsqueryError> {
+        let deadline = Instant::now() + timeout;
+        if let Some(socket) = &self.socket {
+            socket
+                .set_write_timeout(Some(timeout))
+                .map_err(OsqueryError::io(self.phase))?;
+        }
+        *self.deadline.lock().unwrap() = Some(deadline);
+        let res = f(self);
+        *self.deadline.lock().unwrap() = None;
+        // The phase is that of the last call `f` made.
+        self.clear_timeouts(self.phase)?;
+        res
+    }
+
+    /// Deregisters the extension from the osquery extension manager, or
+    /// returns `OsqueryError::NotRegistered` if no extension is registered.
+    pub fn deregister_extension(&mut self) -> Result<bool, OsqueryError> {
+        if self.uuid == 0 {
+            return Err(OsqueryError::NotRegistered {
+                phase: Phase::Deregister,
+            });
+        }
+
+        self.begin_call("deregister_extension")?;
         let res = self.client.as_mut().deregister_extension(self.uuid);
-        match res {
+        match self.end_call(res) {
             Err(e) => {
-                eprintln!("Failed to deregister: {:?}", e);
-                Err(Box::new(e))
+                log!(self.log_level(Level::Warn), "Failed to deregister: {}", e);
+                Err(e)
+            }
+            _ => {
+                self.set_uuid(0);
+                Ok(true)
             }
-            _ => Ok(true),
         }
     }
 
     /// Given a query string, run the query against the connected osquery
     /// extension manager instance.
     ///
+    /// Values that aren't valid UTF-8 fail the whole query with a protocol
+    /// error, use `query_bytes` for tables with binary columns. A response
+    /// without rows is an empty result when the status is a success and an
+    /// `OsqueryError::Status` otherwise.
+    ///
     /// # Arguments
     ///
     /// * `query` - A osquery compatible query string
     ///
     /// # Examples
     ///
-    /// ```
-    /// let mut client = OsqueryClient::new("~/.osquery/shell.em");
-    /// client.register("dummy");
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("~/.osquery/shell.em").unwrap();
+    /// client.register_extension("dummy").unwrap();
     /// let res = client.query("SELECT * FROM osquery_info");
     /// match res {
     ///     Ok(r) => println!("Response: {:#?}", r),
     ///     _ => println!("Failed to query")
     /// };
     /// ```
-    pub fn query(&mut self, query: &str) -> Result<Vec<BTreeMap<String, String>>, Box<dyn Error>> {
-        let res = self.client.as_mut().query(String::from(query));
+    pub fn query(&mut self, query: &str) -> Result<Vec<BTreeMap<String, String>>, OsqueryError> {
+        let res = self.send_query(query);
         match res {
-            Err(e) => Err(Box::new(e)),
+            Err(e) => Err(e),
 
             Ok(r) => {
-                // FIXME: make sure the errors are returned when the
-                // extension code is a success.
-                //if r.status.unwrap().code.unwrap() == ExtensionCode::ExtSuccess as i32 {
-                //    return Ok(r.response.unwrap());
-                //}
-                Ok(r.response.unwrap())
+                if let Some(warning) = r.status.as_ref().and_then(status_warning) {
+                    warn!(
+                        "[request {}] Query returned a warning: {}",
+                        self.request_id, warning
+                    );
+                }
+
+                // Some osquery versions leave out the response list for a
+                // statement without rows, which the generated code reads as
+                // an empty list. Either is only an empty result if the status
+                // says the query succeeded.
+                let rows = r.response.unwrap_or_default();
+                let status = r.status.unwrap_or_default();
+                if rows.is_empty()
+                    && status.code.unwrap_or(ExtensionCode::ExtSuccess as i32)
+                        != ExtensionCode::ExtSuccess as i32
+                {
+                    return Err(status_error(status, Phase::Query));
+                }
+                Ok(rows)
             }
         }
     }
+
+    /// Runs the query like `query`, but returns every value as raw bytes
+    /// instead of a string. This is for tables with BLOB columns or values
+    /// that may not be valid UTF-8, which `query` can't return. Column names
+    /// are still returned as strings, with invalid UTF-8 replaced.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    pub fn query_bytes(
+        &mut self,
+        query: &str,
+    ) -> Result<Vec<BTreeMap<String, Vec<u8>>>, OsqueryError> {
+        self.raw_strings.store(true, Ordering::Relaxed);
+        let res = self.send_query(query);
+        self.raw_strings.store(false, Ordering::Relaxed);
+
+        let res = res?;
+        let status = res.status.unwrap_or_default();
+        if status.code.unwrap_or(ExtensionCode::ExtSuccess as i32)
+            != ExtensionCode::ExtSuccess as i32
+        {
+            let message = status.message.as_deref().map(raw_string_bytes);
+            return Err(status_error(
+                ExtensionStatus {
+                    message: message.map(|m| String::from_utf8_lossy(&m).into_owned()),
+                    ..status
+                },
+                Phase::Query,
+            ));
+        }
+
+        Ok(res
+            .response
+            .unwrap_or_default()
+            .into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .map(|(column, value)| {
+                        let column =
+                            String::from_utf8_lossy(&raw_string_bytes(&column)).into_owned();
+                        (column, raw_string_bytes(&value))
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    /// Runs the query like `query`, but returns `OsqueryError::NoRows` when
+    /// it returns no rows, for queries that are expected to match something.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    pub fn query_expect_nonempty(
+        &mut self,
+        query: &str,
+    ) -> Result<ExtensionPluginResponse, OsqueryError> {
+        let rows = self.query(query)?;
+        if rows.is_empty() {
+            return Err(OsqueryError::NoRows);
+        }
+        Ok(rows)
+    }
+
+    /// Runs a query expected to return a single row and checks that its
+    /// `column` is `expected`, for assertion style checks in tests and
+    /// health checks. Returns `OsqueryError::NoRows` or
+    /// `OsqueryError::TooManyRows` when the query doesn't return exactly one
+    /// row, `OsqueryError::MissingColumn` when the row has no such column
+    /// and `OsqueryError::ValueMismatch` when the value is different.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    /// * `column` - The column to check
+    /// * `expected` - The value the column should have
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// client
+    ///     .query_assert_eq("SELECT watcher FROM osquery_info", "watcher", "-1")
+    ///     .unwrap();
+    /// ```
+    pub fn query_assert_eq(
+        &mut self,
+        query: &str,
+        column: &str,
+        expected: &str,
+    ) -> Result<(), OsqueryError> {
+        let mut rows = self.query(query)?;
+        let row = match rows.len() {
+            0 => return Err(OsqueryError::NoRows),
+            1 => rows.remove(0),
+            n => return Err(OsqueryError::TooManyRows(n)),
+        };
+
+        let actual = row
+            .get(column)
+            .ok_or_else(|| OsqueryError::MissingColumn(String::from(column)))?;
+        if actual != expected {
+            return Err(OsqueryError::ValueMismatch {
+                column: String::from(column),
+                expected: String::from(expected),
+                actual: actual.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Runs the query like `query`, but stores the rows in `out` instead of
+    /// a new vector, so a caller polling the same query in a loop can keep
+    /// reusing the storage. `out` is cleared on entry and only populated if
+    /// the query succeeds.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    /// * `out` - The vector to store the rows in
+    pub fn query_into(
+        &mut self,
+        query: &str,
+        out: &mut Vec<BTreeMap<String, String>>,
+    ) -> Result<(), OsqueryError> {
+        out.clear();
+        out.extend(self.query(query)?);
+        Ok(())
+    }
+
+    /// Runs the query like `query`, but also returns the message osquery
+    /// attached to a successful status, such as a deprecation notice for the
+    /// queried table. A non-success status is returned as an error.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("~/.osquery/shell.em").unwrap();
+    /// let (rows, warning) = client.query_with_warnings("SELECT * FROM osquery_info").unwrap();
+    /// if let Some(warning) = warning {
+    ///     eprintln!("osquery warned: {}", warning);
+    /// }
+    /// ```
+    pub fn query_with_warnings(
+        &mut self,
+        query: &str,
+    ) -> Result<(ExtensionPluginResponse, Option<String>), OsqueryError> {
+        let res = self.send_query(query)?;
+        let status = res.status.unwrap_or_default();
+
+        if status.code.unwrap_or(ExtensionCode::ExtSuccess as i32)
+            != ExtensionCode::ExtSuccess as i32
+        {
+            return Err(status_error(status, Phase::Query));
+        }
+
+        let warning = status_warning(&status);
+        Ok((res.response.unwrap_or_default(), warning))
+    }
+
+    /// Runs the query like `query_with_warnings`, but aborts it if the whole
+    /// call, including reading the full response, doesn't complete before
+    /// `deadline`. This is stronger than a socket read timeout, which a peer
+    /// sending the response a few bytes at a time would never trip.
+    ///
+    /// An aborted call leaves a partial response on the socket, so the
+    /// client is marked as needing a reconnect, see `needs_reconnect`.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    /// * `deadline` - The instant by which the call has to complete
+    pub fn query_deadline(
+        &mut self,
+        query: &str,
+        deadline: Instant,
+    ) -> Result<ExtensionPluginResponse, OsqueryError> {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.as_nanos() == 0 {
+            return Err(OsqueryError::Timeout {
+                phase: Phase::Query,
+            });
+        }
+
+        let io = OsqueryError::io(Phase::Query);
+        if let Some(socket) = &self.socket {
+            socket.set_write_timeout(Some(remaining)).map_err(io)?;
+        }
+        *self.deadline.lock().unwrap() = Some(deadline);
+        let res = self.query_with_warnings(query);
+        *self.deadline.lock().unwrap() = None;
+        self.clear_timeouts(Phase::Query)?;
+
+        match res {
+            Ok((rows, _)) => Ok(rows),
+            Err(OsqueryError::Thrift { .. }) | Err(OsqueryError::Protocol { .. })
+                if Instant::now() >= deadline =>
+            {
+                self.needs_reconnect = true;
+                Err(OsqueryError::Timeout {
+                    phase: Phase::Query,
+                })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns whether a call was aborted part way, in which case the
+    /// connection is out of sync with the extension manager and the client
+    /// has to be recreated before it can be used again, as every further
+    /// call fails with `OsqueryError::DesyncedConnection`. This is also the
+    /// case once the extension manager closed the connection.
+    pub fn needs_reconnect(&self) -> bool {
+        self.needs_reconnect || self.disconnected
+    }
+
+    /// Returns where the client is in its lifecycle, which callers can use
+    /// to decide when to reconnect or register again.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// client.register_extension("plugin_name").unwrap();
+    /// if let ConnectionState::Registered { uuid } = client.connection_state() {
+    ///     println!("registered as {}", uuid);
+    /// }
+    /// ```
+    pub fn connection_state(&self) -> ConnectionState {
+        if self.disconnected {
+            ConnectionState::Disconnected
+        } else if self.needs_reconnect {
+            ConnectionState::Broken
+        } else if self.uuid != 0 {
+            ConnectionState::Registered { uuid: self.uuid }
+        } else {
+            ConnectionState::Connected
+        }
+    }
+
+    /// Runs the query and merges the current osquery decorations into every
+    /// returned row, the same way osquery decorates the results it logs.
+    /// The decorations are read from `osquery_info` and every decoration
+    /// column is prefixed with `decorations_` so it can't collide with a
+    /// column of the query. Should the query itself return a column with a
+    /// prefixed name, the value from the query is kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    pub fn query_decorated(
+        &mut self,
+        query: &str,
+    ) -> Result<ExtensionPluginResponse, OsqueryError> {
+        let (rows, _) = self.query_with_warnings(query)?;
+        if rows.is_empty() {
+            return Ok(rows);
+        }
+
+        let (info, _) = self.query_with_warnings(DECORATIONS_QUERY)?;
+        let decorations: Vec<(String, String)> = info
+            .into_iter()
+            .next()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(column, value)| (format!("{}{}", DECORATION_PREFIX, column), value))
+            .collect();
+
+        Ok(rows
+            .into_iter()
+            .map(|mut row| {
+                for (column, value) in &decorations {
+                    row.entry(column.clone()).or_insert_with(|| value.clone());
+                }
+                row
+            })
+            .collect())
+    }
+
+    /// Runs a single page of the given query, skipping the first `offset`
+    /// rows and returning at most `limit` rows. The returned flag is `true`
+    /// when more rows are likely available after this page, which is worked
+    /// out by fetching one row more than requested.
+    ///
+    /// Queries that already carry their own `LIMIT` or `OFFSET` are wrapped
+    /// in a subquery so that the page is taken from their result.
+    ///
+    /// # Arguments
+    ///
+    /// * `base_query` - A osquery compatible query string
+    /// * `offset` - The number of rows to skip
+    /// * `limit` - The maximum number of rows in the page
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("~/.osquery/shell.em").unwrap();
+    /// let (rows, more) = client.query_page("SELECT * FROM processes", 0, 50).unwrap();
+    /// ```
+    pub fn query_page(
+        &mut self,
+        base_query: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(ExtensionPluginResponse, bool), OsqueryError> {
+        let mut rows = self.query(&page_query(base_query, offset, limit))?;
+        let more = rows.len() > limit;
+        rows.truncate(limit);
+        Ok((rows, more))
+    }
+
+    /// Returns when the first bytes of the response to the most recent call
+    /// arrived, if any did.
+    pub(crate) fn last_first_read(&self) -> Option<Instant> {
+        *self.first_read.lock().unwrap()
+    }
+
+    /// Returns the number of bytes read from the socket for the response of
+    /// the most recent call. The counter is reset at the start of every call.
+    pub fn last_response_bytes(&self) -> usize {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    /// Returns the settings the client was built with.
+    pub fn config(&self) -> &OsqueryClientBuilder {
+        &self.config
+    }
+
+    /// Returns what the client registered, or `None` if no extension is
+    /// registered.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// client.register_extension("plugin_name").unwrap();
+    /// if let Some(registration) = client.registration() {
+    ///     println!("{} is registered as {}", registration.name, registration.uuid);
+    /// }
+    /// ```
+    pub fn registration(&self) -> Option<Registration> {
+        if self.uuid == 0 {
+            return None;
+        }
+        Some(Registration {
+            name: self.name.clone(),
+            uuid: self.uuid,
+            sdk_version: self.config.sdk_version.clone(),
+            socket_path: self.config.socket_file.clone(),
+        })
+    }
+
+    /// Returns how long the most recent registration took, from sending the
+    /// request to reading the answer, whether or not the extension manager
+    /// accepted it. For `register_extension_retry` this spans all attempts
+    /// and the waits between them, so a slow-starting osquery shows up as a
+    /// long registration. `None` is returned before the first registration.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// client.register_extension("plugin_name").unwrap();
+    /// println!("registered in {:?}", client.last_registration_duration().unwrap());
+    /// ```
+    pub fn last_registration_duration(&self) -> Option<Duration> {
+        self.last_registration_duration
+    }
+
+    /// Returns the osquery SDK version the client registers extensions with.
+    pub fn sdk_version(&self) -> &str {
+        &self.config.sdk_version
+    }
+
+    /// Returns the id of the most recent call made by this client. Every call
+    /// gets the next id, starting from 1, and the id is part of the log
+    /// output for the call so log lines can be matched to their responses.
+    pub fn last_request_id(&self) -> u64 {
+        self.request_id
+    }
+
+    /// Asks the extension manager for the columns of a query, one row per
+    /// column mapping its name to its type.
+    pub(crate) fn get_query_columns_rows(
+        &mut self,
+        query: &str,
+    ) -> Result<ExtensionPluginResponse, OsqueryError> {
+        self.check_registered()?;
+        self.begin_call("get_query_columns")?;
+        let res = self.client.as_mut().get_query_columns(String::from(query));
+        let res = self.end_call(res)?;
+
+        let status = res.status.unwrap_or_default();
+        if status.code.unwrap_or(ExtensionCode::ExtSuccess as i32)
+            != ExtensionCode::ExtSuccess as i32
+        {
+            return Err(status_error(status, Phase::Query));
+        }
+        Ok(res.response.unwrap_or_default())
+    }
+
+    /// Returns the thrift sequence id sent with the most recent call.
+    pub fn last_seqid(&self) -> i32 {
+        self.seqid.load(Ordering::Relaxed)
+    }
+
+    /// Sets the thrift sequence id sent with the next call, after which the
+    /// ids go up by one again from there. By default the first call has the
+    /// id 1. Every response has to carry the id of its call, and a response
+    /// with another id fails the call with `OsqueryError::DesyncedConnection`.
+    pub fn set_next_seqid(&mut self, seqid: i32) {
+        *self.next_seqid.lock().unwrap() = Some(seqid);
+    }
+
+    /// Sends the query to the extension manager, logging it as a slow query
+    /// if it takes longer than the configured threshold. In dry run mode the
+    /// query is only logged and answered with an empty result.
+    fn send_query(&mut self, query: &str) -> Result<ExtensionResponse, OsqueryError> {
+        self.check_registered()?;
+        if !self.config.dry_run {
+            self.send_prelude()?;
+        }
+
+        self.begin_call("query")?;
+        if self.config.dry_run {
+            info!("[request {}] Dry run query: {}", self.request_id, query);
+            let status = ExtensionStatus::new(ExtensionCode::ExtSuccess as i32, None, None);
+            return Ok(ExtensionResponse::new(
+                status,
+                ExtensionPluginResponse::new(),
+            ));
+        }
+
+        let started = Instant::now();
+        let res = self.client.as_mut().query(String::from(query));
+        let res = self.end_call(res);
+
+        let elapsed = started.elapsed();
+        if let Some(threshold) = self.config.slow_query_threshold {
+            if elapsed > threshold {
+                warn!(
+                    "[request {}] Slow query took {:?}: {}",
+                    self.request_id, elapsed, query
+                );
+            }
+        }
+
+        res
+    }
+
+    /// Sends the prelude statements if they weren't sent over the connection
+    /// yet. A statement osquery rejects is only logged.
+    fn send_prelude(&mut self) -> Result<(), OsqueryError> {
+        if self.prelude_sent {
+            return Ok(());
+        }
+
+        for statement in self.config.prelude.clone() {
+            self.begin_call("query")?;
+            let res = self.client.as_mut().query(statement.clone());
+            let status = self.end_call(res)?.status.unwrap_or_default();
+            if status.code.unwrap_or(ExtensionCode::ExtSuccess as i32)
+                != ExtensionCode::ExtSuccess as i32
+            {
+                warn!(
+                    "[request {}] Prelude statement {:?} was rejected: {}",
+                    self.request_id,
+                    statement,
+                    status.message.unwrap_or_default()
+                );
+            }
+        }
+        self.prelude_sent = true;
+        Ok(())
+    }
+
+    /// Returns the extension info to register an extension named `name`
+    /// with, as configured by the builder.
+    fn extension_info(&self, name: &str) -> InternalExtensionInfo {
+        InternalExtensionInfo::new(
+            String::from(name),
+            self.config.extension_version.clone(),
+            self.config.sdk_version.clone(),
+            self.config.min_sdk_version.clone(),
+        )
+    }
+
+    /// Records the name and uuid the extension is registered with, and the
+    /// routes it registered, for the keepalive to register it again.
+    fn set_registered(
+        &mut self,
+        name: &str,
+        uuid: ExtensionRouteUUID,
+        registry: &ExtensionRegistry,
+    ) {
+        self.name = String::from(name);
+        self.set_uuid(uuid);
+        if let Some(keepalive) = &self.keepalive {
+            keepalive.set_registration(name, uuid, registry);
+        }
+    }
+
+    /// Forgets the extension registered by the client without
+    /// deregistering it, so it stays registered once the client is dropped.
+    pub(crate) fn forget_registration(&mut self) {
+        self.set_uuid(0);
+    }
+
+    /// Deregisters the extension registered as `uuid` by another client.
+    pub(crate) fn deregister_uuid(&mut self, uuid: ExtensionRouteUUID) -> Result<(), OsqueryError> {
+        self.begin_call("deregister_extension")?;
+        let res = self.client.as_mut().deregister_extension(uuid);
+        self.end_call(res).map(|_| ())
+    }
+
+    /// Renews the lease on the registration, see
+    /// `OsqueryClientBuilder::registration_ttl`. Every call through the
+    /// client does so too, so this is only needed while the application
+    /// has nothing else to ask osquery. Nothing happens without a lease, or
+    /// once it ran out.
+    pub fn renew_registration(&self) {
+        if let Some(lease) = &self.lease {
+            lease.renew();
+        }
+    }
+
+    /// Drops the registration if its lease ran out, as the extension was
+    /// deregistered then.
+    fn adopt_lease_expiry(&mut self) {
+        if self.uuid != 0 && self.lease.as_ref().is_some_and(Lease::expired) {
+            warn!(
+                "The lease of extension {} ran out, it is no longer registered",
+                self.uuid
+            );
+            self.forget_registration();
+        }
+    }
+
+    /// Takes on the uuid the keepalive registered the extension again as,
+    /// if it did.
+    fn adopt_reregistration(&mut self) {
+        let uuid = self.keepalive.as_ref().and_then(Keepalive::registered_uuid);
+        if let Some(uuid) = uuid {
+            if uuid != self.uuid && self.uuid != 0 {
+                self.set_uuid(uuid);
+            }
+        }
+    }
+
+    /// Records the uuid the extension is registered as, or 0 if none.
+    fn set_uuid(&mut self, uuid: ExtensionRouteUUID) {
+        self.uuid = uuid;
+        if let Some(lease) = &self.lease {
+            lease.set_uuid(uuid);
+        }
+        if uuid == 0 {
+            if let Some(keepalive) = &self.keepalive {
+                keepalive.clear_registration();
+            }
+        }
+        #[cfg(feature = "health-endpoint")]
+        if let Some(health) = &self.health {
+            health.set_uuid(uuid);
+        }
+    }
+
+    /// Sends a query whose rows are then read one at a time with
+    /// `read_lazy_row`, returning how many there are. `None` is returned
+    /// when the reply had no rows to read, in which case it was read whole.
+    pub(crate) fn start_lazy_query(&mut self, query: &str) -> Result<Option<usize>, OsqueryError> {
+        self.check_registered()?;
+        if !self.config.dry_run {
+            self.send_prelude()?;
+        }
+
+        self.begin_call("query")?;
+        if self.config.dry_run {
+            info!("[request {}] Dry run query: {}", self.request_id, query);
+            return Ok(None);
+        }
+
+        let res = send_lazy_query(self.client.as_mut(), query);
+        let (status, size) = self.end_call(res)?;
+        let status = status.unwrap_or_default();
+        if status.code.unwrap_or(ExtensionCode::ExtSuccess as i32)
+            != ExtensionCode::ExtSuccess as i32
+        {
+            // The rows still have to be read off the connection.
+            if let Some(size) = size {
+                let res = skip_rows(self.client.as_mut(), size)
+                    .and_then(|_| finish_lazy_query(self.client.as_mut()));
+                self.end_call(res)?;
+            }
+            return Err(status_error(status, Phase::Query));
+        }
+        Ok(size.map(|size| size as usize))
+    }
+
+    /// Skips over the next `count` rows of the query started with
+    /// `start_lazy_query` without decoding them.
+    pub(crate) fn skip_lazy_rows(&mut self, count: usize) -> Result<(), OsqueryError> {
+        let res = skip_rows(self.client.as_mut(), count as i32);
+        self.end_call(res)
+    }
+
+    /// Reads the next row of the query started with `start_lazy_query`.
+    pub(crate) fn read_lazy_row(&mut self) -> Result<BTreeMap<String, String>, OsqueryError> {
+        let res = read_row(self.client.as_mut());
+        self.end_call(res)
+    }
+
+    /// Reads the next row of the query started with `start_lazy_query`,
+    /// handing every column and value to `cell` instead of collecting them.
+    pub(crate) fn read_lazy_row_cells(
+        &mut self,
+        cell: &mut dyn FnMut(String, String),
+    ) -> Result<(), OsqueryError> {
+        let res = read_row_cells(self.client.as_mut(), cell);
+        self.end_call(res)
+    }
+
+    /// Reads the rest of the reply once all rows of the query started with
+    /// `start_lazy_query` were read.
+    pub(crate) fn finish_lazy_query(&mut self) -> Result<(), OsqueryError> {
+        let res = finish_lazy_query(self.client.as_mut());
+        match self.end_call(res)? {
+            Some(status)
+                if status.code.unwrap_or(ExtensionCode::ExtSuccess as i32)
+                    != ExtensionCode::ExtSuccess as i32 =>
+            {
+                Err(status_error(status, Phase::Query))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Marks the connection as needing a reconnect after a reply was left
+    /// partially read.
+    pub(crate) fn abandon_call(&mut self) {
+        self.needs_reconnect = true;
+    }
+
+    /// Returns `OsqueryError::NotRegistered` if the client is configured to
+    /// only query once registered and no extension is registered yet.
+    fn check_registered(&self) -> Result<(), OsqueryError> {
+        if self.config.require_registration && self.uuid == 0 {
+            return Err(OsqueryError::NotRegistered {
+                phase: Phase::Query,
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns `OsqueryError::InspectMode` if the client is in inspect mode.
+    pub(crate) fn check_not_inspecting(&self) -> Result<(), OsqueryError> {
+        if self.config.inspect {
+            return Err(OsqueryError::InspectMode);
+        }
+        Ok(())
+    }
+
+    /// Returns `OsqueryError::NameInUse` if the builder asked to check for
+    /// a registered extension named `name` and there is one.
+    fn check_name_available(&mut self, name: &str) -> Result<(), OsqueryError> {
+        if !self.config.check_name_in_use {
+            return Ok(());
+        }
+        if self
+            .extensions()?
+            .values()
+            .any(|info| info.name.as_deref() == Some(name))
+        {
+            return Err(OsqueryError::NameInUse(String::from(name)));
+        }
+        Ok(())
+    }
+
+    /// Clears the socket timeouts a call with a deadline set.
+    fn clear_timeouts(&self, phase: Phase) -> Result<(), OsqueryError> {
+        if let Some(socket) = &self.socket {
+            let io = OsqueryError::io(phase);
+            socket
+                .set_read_timeout(None)
+                .and_then(|_| socket.set_write_timeout(None))
+                .map_err(io)?;
+        }
+        Ok(())
+    }
+
+    /// Returns `OsqueryError::Cancelled` in the phase if the cancel or
+    /// shutdown flag is set.
+    fn check_cancelled(&self, phase: Phase) -> Result<(), OsqueryError> {
+        let cancelled = self
+            .config
+            .cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed));
+        if cancelled || self.is_shut_down() {
+            return Err(OsqueryError::Cancelled { phase });
+        }
+        Ok(())
+    }
+
+    /// Returns whether the shutdown flag is set.
+    fn is_shut_down(&self) -> bool {
+        self.config
+            .shutdown
+            .as_ref()
+            .is_some_and(|shutdown| shutdown.load(Ordering::Relaxed))
+    }
+
+    /// Sleeps for `duration`, waking up regularly to check the cancel flag
+    /// so that a cancellation aborts the sleep promptly, with an error in
+    /// the phase.
+    pub(crate) fn sleep(&self, duration: Duration, phase: Phase) -> Result<(), OsqueryError> {
+        let until = Instant::now() + duration;
+        loop {
+            self.check_cancelled(phase)?;
+            let remaining = until.saturating_duration_since(Instant::now());
+            if remaining.as_nanos() == 0 {
+                return Ok(());
+            }
+            thread::sleep(remaining.min(CANCEL_POLL_INTERVAL));
+        }
+    }
+
+    /// Returns the level the client logs its own messages at, which is
+    /// `level` unless the client is quiet, in which case it is debug.
+    fn log_level(&self, level: Level) -> Level {
+        if self.config.quiet {
+            Level::Debug
+        } else {
+            level
+        }
+    }
+
+    /// Sets up the bookkeeping for a new call to the extension manager, or
+    /// returns `OsqueryError::Cancelled` if the shutdown flag is set and the
+    /// call isn't deregistering. A connection an earlier call left out of
+    /// sync is refused with `OsqueryError::DesyncedConnection`, as the next
+    /// reply on it may belong to that call.
+    fn begin_call(&mut self, method: &str) -> Result<(), OsqueryError> {
+        let phase = match method {
+            "register_extension" => Phase::Register,
+            "deregister_extension" => Phase::Deregister,
+            "ping" => Phase::Ping,
+            _ => Phase::Query,
+        };
+        if phase != Phase::Deregister && self.is_shut_down() {
+            return Err(OsqueryError::Cancelled { phase });
+        }
+        if self.needs_reconnect {
+            return Err(OsqueryError::DesyncedConnection {
+                phase,
+                message: String::from(
+                    "an earlier call was aborted, the client has to be reconnected",
+                ),
+            });
+        }
+        self.adopt_reregistration();
+        self.adopt_lease_expiry();
+        self.renew_registration();
+        self.phase = phase;
+        self.request_id += 1;
+        self.bytes_read.store(0, Ordering::Relaxed);
+        *self.first_read.lock().unwrap() = None;
+        debug!("[request {}] {}", self.request_id, method);
+        Ok(())
+    }
+
+    /// Records what a failed call says about the connection. The transport
+    /// reporting the socket as closed means the extension manager hung up,
+    /// while any other transport error may have left a partial message on
+    /// the socket. A response to a different call means that already
+    /// happened. The error is returned with the phase of the call.
+    fn end_call<T>(&mut self, res: thrift::Result<T>) -> Result<T, OsqueryError> {
+        match &res {
+            Err(thrift::Error::Transport(e)) => match e.kind {
+                TransportErrorKind::NotOpen | TransportErrorKind::EndOfFile => {
+                    self.disconnected = true
+                }
+                _ => self.needs_reconnect = true,
+            },
+            Err(thrift::Error::Application(e)) if is_desync(e) => self.needs_reconnect = true,
+            _ => {}
+        }
+        // A call abandoned for the shutdown flag leaves its reply unread.
+        if res.is_err() && self.phase != Phase::Deregister && self.is_shut_down() {
+            self.needs_reconnect = true;
+            return Err(OsqueryError::Cancelled { phase: self.phase });
+        }
+        let phase = self.phase;
+        res.map_err(|e| OsqueryError::from_thrift(e, phase))
+    }
+}
+
+impl Drop for OsqueryClient {
+    /// Stops the keepalive, lease and health endpoint, if any, and then
+    /// deregisters the extension if one is still registered over a healthy
+    /// connection.
+    fn drop(&mut self) {
+        if let Some(keepalive) = self.keepalive.as_mut() {
+            keepalive.stop();
+        }
+        self.adopt_reregistration();
+        self.keepalive = None;
+        if let Some(lease) = self.lease.as_mut() {
+            lease.stop();
+        }
+        self.adopt_lease_expiry();
+        self.lease = None;
+        #[cfg(feature = "health-endpoint")]
+        if let Some(mut health) = self.health.take() {
+            health.stop();
+        }
+
+        if self.uuid != 0 && !self.needs_reconnect() {
+            let _ = self.deregister_extension();
+        }
+    }
+}
+
+/// Enriches a permission denied error with the mode and ownership of the
+/// socket file, which is usually all an operator needs to fix the setup.
+/// Any other error, or a failure to stat the socket, is returned as is.
+fn connect_error(socket_file: &str, err: io::Error) -> io::Error {
+    if err.kind() != io::ErrorKind::PermissionDenied {
+        return err;
+    }
+
+    match fs::metadata(socket_file) {
+        Ok(meta) => io::Error::new(
+            err.kind(),
+            format!(
+                "{}: socket {} has mode {:o} and is owned by uid {} gid {}, \
+                 the current user likely needs to be in the osquery group",
+                err,
+                socket_file,
+                meta.mode() & 0o7777,
+                meta.uid(),
+                meta.gid()
+            ),
+        ),
+        Err(_) => err,
+    }
+}
+
+/// Returns `OsqueryError::RegistryTooLarge` if the registry takes more than
+/// `limit` bytes in the thrift binary encoding.
+fn check_registry_size(registry: &ExtensionRegistry, limit: usize) -> Result<(), OsqueryError> {
+    // A map is prefixed by its key and value types and its size, a list by
+    // its element type and size and a string by its length.
+    const MAP_HEADER: usize = 6;
+    const LIST_HEADER: usize = 5;
+    const STRING_HEADER: usize = 4;
+
+    let mut size = MAP_HEADER;
+    let mut largest: Option<(String, usize)> = None;
+    for (name, items) in registry {
+        size += STRING_HEADER + name.len() + MAP_HEADER;
+        for (item, routes) in items {
+            let routes_size: usize = LIST_HEADER
+                + routes
+                    .iter()
+                    .map(|route| {
+                        MAP_HEADER
+                            + route
+                                .iter()
+                                .map(|(k, v)| 2 * STRING_HEADER + k.len() + v.len())
+                                .sum::<usize>()
+                    })
+                    .sum::<usize>();
+            size += STRING_HEADER + item.len() + routes_size;
+
+            if largest.as_ref().is_none_or(|(_, s)| routes_size > *s) {
+                largest = Some((format!("{}/{}", name, item), routes_size));
+            }
+        }
+    }
+
+    if size > limit {
+        return Err(OsqueryError::RegistryTooLarge {
+            size,
+            limit,
+            largest: largest.map(|(name, _)| name),
+        });
+    }
+    Ok(())
+}
+
+/// Returns the uuid of a successful registration.
+fn registered_uuid(status: ExtensionStatus) -> Result<ExtensionRouteUUID, OsqueryError> {
+    status.uuid.ok_or_else(|| OsqueryError::Protocol {
+        phase: Phase::Register,
+        message: String::from("registration status is missing the uuid"),
+    })
+}
+
+/// Returns the error for a call in the phase answered with a non-success
+/// status.
+fn status_error(status: ExtensionStatus, phase: Phase) -> OsqueryError {
+    OsqueryError::Status {
+        phase,
+        code: status.code.unwrap_or(ExtensionCode::ExtFatal as i32),
+        message: status.message.unwrap_or_default(),
+    }
+}
+
+/// Returns the message of a successful status if it carries anything other
+/// than the plain "OK" osquery sends when there is nothing to report.
+fn status_warning(status: &ExtensionStatus) -> Option<String> {
+    if status.code.unwrap_or(ExtensionCode::ExtSuccess as i32) != ExtensionCode::ExtSuccess as i32 {
+        return None;
+    }
+
+    match status.message.as_deref() {
+        None | Some("") | Some("OK") => None,
+        Some(message) => Some(String::from(message)),
+    }
+}
+
+/// The largest integer SQLite reads as one, rather than as a real that
+/// `LIMIT` and `OFFSET` reject.
+const MAX_SQL_INTEGER: usize = i64::MAX as usize;
+
+/// Builds the query for a page of `base_query`, asking for one extra row
+/// so the caller can tell if another page follows. The limit and offset are
+/// clamped to what SQLite accepts, which no result comes close to. The base
+/// query goes on lines of its own, so that a trailing `--` comment doesn't
+/// comment out the paging.
+fn page_query(base_query: &str, offset: usize, limit: usize) -> String {
+    let base = base_query.trim().trim_end_matches(';');
+    let limit = limit.saturating_add(1).min(MAX_SQL_INTEGER);
+    let offset = offset.min(MAX_SQL_INTEGER);
+    let paged = base
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .any(|word| word.eq_ignore_ascii_case("limit") || word.eq_ignore_ascii_case("offset"));
+
+    if paged {
+        format!(
+            "SELECT * FROM (\n{}\n) LIMIT {} OFFSET {}",
+            base, limit, offset
+        )
+    } else {
+        format!("{}\nLIMIT {} OFFSET {}", base, limit, offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osquery::fake::{test_socket, FakeExtensionManager, RunningFakeExtensionManager};
+
+    fn status(code: ExtensionCode, message: &str) -> ExtensionStatus {
+        ExtensionStatus::new(code as i32, String::from(message), None)
+    }
+
+    /// Answers `sql` with one row holding invalid UTF-8, embedded NUL bytes
+    /// and a plain value.
+    fn binary_manager(name: &str, sql: &str) -> RunningFakeExtensionManager {
+        let mut row = BTreeMap::new();
+        row.insert(String::from("invalid"), vec![0x66, 0x6f, 0xff, 0xfe, 0x80]);
+        row.insert(String::from("nul"), b"a\0b\0".to_vec());
+        row.insert(String::from("text"), b"plain".to_vec());
+        FakeExtensionManager::new()
+            .query_response_bytes(sql, vec![row])
+            .start(&test_socket(name))
+            .unwrap()
+    }
+
+    #[test]
+    fn query_bytes_returns_binary_values_unchanged() {
+        let manager = binary_manager("query-bytes", "SELECT * FROM blobs");
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+
+        let rows = client.query_bytes("SELECT * FROM blobs").unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["invalid"], vec![0x66, 0x6f, 0xff, 0xfe, 0x80]);
+        assert_eq!(rows[0]["nul"], b"a\0b\0".to_vec());
+        assert_eq!(rows[0]["text"], b"plain".to_vec());
+
+        // Strings are decoded again once the bytes were read.
+        assert!(client.ping().unwrap());
+    }
+
+    #[test]
+    fn query_rejects_invalid_utf8() {
+        let manager = binary_manager("query-invalid-utf8", "SELECT * FROM blobs");
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+
+        match client.query("SELECT * FROM blobs") {
+            Err(OsqueryError::Protocol {
+                phase: Phase::Query,
+                ..
+            }) => {}
+            res => panic!("expected a protocol error, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn query_keeps_embedded_nul_bytes() {
+        let mut row = BTreeMap::new();
+        row.insert(String::from("nul"), b"a\0b".to_vec());
+        let manager = FakeExtensionManager::new()
+            .query_response_bytes("SELECT nul FROM blobs", vec![row])
+            .start(&test_socket("query-nul"))
+            .unwrap();
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+
+        let rows = client.query("SELECT nul FROM blobs").unwrap();
+        assert_eq!(rows[0]["nul"], "a\0b");
+    }
+
+    #[test]
+    fn query_without_response_and_success_status_is_empty() {
+        let reply = ExtensionResponse::new(status(ExtensionCode::ExtSuccess, "OK"), None);
+        let manager = FakeExtensionManager::new()
+            .query_reply("DELETE FROM t", reply)
+            .start(&test_socket("query-none-success"))
+            .unwrap();
+
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+        assert_eq!(client.query("DELETE FROM t").unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn query_without_response_and_failure_status_is_an_error() {
+        let reply = ExtensionResponse::new(status(ExtensionCode::ExtFailed, "no such table"), None);
+        let manager = FakeExtensionManager::new()
+            .query_reply("SELECT * FROM t", reply)
+            .start(&test_socket("query-none-failure"))
+            .unwrap();
+
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+        match client.query("SELECT * FROM t") {
+            Err(OsqueryError::Status { code, message, .. }) => {
+                assert_eq!(code, ExtensionCode::ExtFailed as i32);
+                assert_eq!(message, "no such table");
+            }
+            res => panic!("expected a status error, got {:?}", res),
+        }
+    }
+
+    fn numbered(count: usize) -> ExtensionPluginResponse {
+        (0..count)
+            .map(|n| {
+                let mut row = BTreeMap::new();
+                row.insert(String::from("n"), n.to_string());
+                row
+            })
+            .collect()
+    }
+
+    #[test]
+    fn last_page_has_no_more_rows() {
+        // Five rows in pages of two, as osquery would answer each page.
+        let manager = FakeExtensionManager::new()
+            .query_response("SELECT n FROM t\nLIMIT 3 OFFSET 2", numbered(3))
+            .query_response("SELECT n FROM t\nLIMIT 3 OFFSET 4", numbered(1))
+            .start(&test_socket("page-last"))
+            .unwrap();
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+
+        let (rows, more) = client.query_page("SELECT n FROM t", 2, 2).unwrap();
+        assert_eq!((rows.len(), more), (2, true));
+        let (rows, more) = client.query_page("SELECT n FROM t", 4, 2).unwrap();
+        assert_eq!((rows.len(), more), (1, false));
+    }
+
+    #[test]
+    fn page_ending_on_the_last_row_has_no_more_rows() {
+        // Four rows in pages of two, the second page ending on the last row.
+        let manager = FakeExtensionManager::new()
+            .query_response("SELECT n FROM t\nLIMIT 3 OFFSET 2", numbered(2))
+            .start(&test_socket("page-exact"))
+            .unwrap();
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+
+        let (rows, more) = client.query_page("SELECT n FROM t", 2, 2).unwrap();
+        assert_eq!((rows.len(), more), (2, false));
+    }
+
+    #[test]
+    fn page_query_clamps_to_sql_integers() {
+        assert_eq!(
+            page_query("SELECT n FROM t;", usize::MAX, usize::MAX),
+            "SELECT n FROM t\nLIMIT 9223372036854775807 OFFSET 9223372036854775807"
+        );
+        assert_eq!(
+            page_query("SELECT n FROM t", 0, MAX_SQL_INTEGER),
+            "SELECT n FROM t\nLIMIT 9223372036854775807 OFFSET 0"
+        );
+    }
+
+    #[test]
+    fn page_query_wraps_queries_with_their_own_limit() {
+        for base in &[
+            "SELECT n FROM t LIMIT 10",
+            "SELECT n FROM t LIMIT(10)",
+            "SELECT n FROM t\nLIMIT\t5",
+            "SELECT n FROM t ORDER BY n OFFSET 1",
+        ] {
+            assert_eq!(
+                page_query(base, 5, 2),
+                format!("SELECT * FROM (\n{}\n) LIMIT 3 OFFSET 5", base)
+            );
+        }
+
+        // Part of a longer name isn't a keyword.
+        assert_eq!(
+            page_query("SELECT rate_limit FROM t", 0, 2),
+            "SELECT rate_limit FROM t\nLIMIT 3 OFFSET 0"
+        );
+    }
+
+    #[test]
+    fn page_query_survives_a_trailing_comment() {
+        let manager = FakeExtensionManager::new()
+            .query_response(
+                "SELECT n FROM t -- every row\nLIMIT 3 OFFSET 0",
+                numbered(3),
+            )
+            .start(&test_socket("page-comment"))
+            .unwrap();
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+
+        let (rows, more) = client
+            .query_page("SELECT n FROM t -- every row", 0, 2)
+            .unwrap();
+        assert_eq!((rows.len(), more), (2, true));
+    }
 }
@@ -0,0 +1,208 @@
+use crate::osquery::error::OsqueryError;
+use crate::osquery::osquery::ExtensionPluginResponse;
+use crate::osquery::register_extension::OsqueryClient;
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+
+/// A query result stored in a single contiguous buffer. Column names are
+/// stored once for the whole result and every cell is a span of the
+/// buffer, so a wide result is held in a handful of allocations instead of
+/// a `String` per cell and column name.
+///
+/// The rows are handed out as maps borrowing from the result set.
+#[derive(Debug, Clone, Default)]
+pub struct ResultSet {
+    /// The column names and cell values of every row.
+    buffer: String,
+
+    /// The span of each distinct column name in the buffer.
+    columns: Vec<Range<usize>>,
+
+    /// Every row as a list of column index and value span pairs.
+    rows: Vec<Vec<(usize, Range<usize>)>>,
+}
+
+impl ResultSet {
+    /// Packs decoded rows into a result set.
+    pub fn from_rows(rows: ExtensionPluginResponse) -> Self {
+        let mut packer = Packer::default();
+        for row in rows {
+            packer.start_row(row.len());
+            for (column, value) in row {
+                packer.push_cell(column, &value);
+            }
+        }
+        packer.finish()
+    }
+
+    /// Returns the number of rows.
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// Returns whether the result has no rows.
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Returns the row at `idx`, with its column names and values borrowed
+    /// from the result set.
+    pub fn row(&self, idx: usize) -> Option<BTreeMap<&str, &str>> {
+        self.rows.get(idx).map(|cells| {
+            cells
+                .iter()
+                .map(|(column, span)| {
+                    (
+                        &self.buffer[self.columns[*column].clone()],
+                        &self.buffer[span.clone()],
+                    )
+                })
+                .collect()
+        })
+    }
+
+    /// Returns an iterator over the rows, with their column names and values
+    /// borrowed from the result set.
+    pub fn rows(&self) -> impl Iterator<Item = BTreeMap<&str, &str>> + '_ {
+        (0..self.rows.len()).filter_map(move |idx| self.row(idx))
+    }
+
+    fn push(&mut self, s: &str) -> Range<usize> {
+        let start = self.buffer.len();
+        self.buffer.push_str(s);
+        start..self.buffer.len()
+    }
+}
+
+/// Packs rows into a result set one cell at a time.
+#[derive(Default)]
+struct Packer {
+    set: ResultSet,
+
+    /// The index of every column name seen so far in `set.columns`.
+    column_index: HashMap<String, usize>,
+}
+
+impl Packer {
+    fn start_row(&mut self, capacity: usize) {
+        self.set.rows.push(Vec::with_capacity(capacity));
+    }
+
+    /// Adds a cell to the row started last.
+    fn push_cell(&mut self, column: String, value: &str) {
+        let idx = match self.column_index.get(&column) {
+            Some(idx) => *idx,
+            None => {
+                let span = self.set.push(&column);
+                self.set.columns.push(span);
+                let idx = self.set.columns.len() - 1;
+                self.column_index.insert(column, idx);
+                idx
+            }
+        };
+        let span = self.set.push(value);
+        if let Some(cells) = self.set.rows.last_mut() {
+            cells.push((idx, span));
+        }
+    }
+
+    fn finish(mut self) -> ResultSet {
+        self.set.buffer.shrink_to_fit();
+        self.set
+    }
+}
+
+impl OsqueryClient {
+    /// Runs the query and returns the result packed in a `ResultSet`.
+    ///
+    /// The rows are packed as they are read off the connection, like
+    /// `query_lazy` decodes them, so that the whole result is never held as
+    /// owned strings at once: only the cell being decoded is. A non-success
+    /// status fails the query like `query_with_warnings` does.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// let result = client.query_borrowed("SELECT * FROM processes").unwrap();
+    /// for row in result.rows() {
+    ///     println!("{:?}", row.get("name"));
+    /// }
+    /// ```
+    pub fn query_borrowed(&mut self, query: &str) -> Result<ResultSet, OsqueryError> {
+        let size = match self.start_lazy_query(query)? {
+            Some(size) => size,
+            None => return Ok(ResultSet::default()),
+        };
+
+        let mut packer = Packer::default();
+        for _ in 0..size {
+            packer.start_row(0);
+            let res = self.read_lazy_row_cells(&mut |column, value| {
+                packer.push_cell(column, &value);
+            });
+            if let Err(e) = res {
+                self.abandon_call();
+                return Err(e);
+            }
+        }
+        self.finish_lazy_query()?;
+        Ok(packer.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::osquery::error::OsqueryError;
+    use crate::osquery::fake::{test_socket, FakeExtensionManager};
+    use crate::osquery::OsqueryClient;
+
+    use std::collections::BTreeMap;
+
+    fn row(cells: &[(&str, &str)]) -> BTreeMap<String, String> {
+        cells
+            .iter()
+            .map(|(column, value)| (String::from(*column), String::from(*value)))
+            .collect()
+    }
+
+    #[test]
+    fn query_borrowed_packs_the_rows_as_read() {
+        let rows = vec![
+            row(&[("name", "init"), ("pid", "1")]),
+            row(&[("name", "sshd"), ("pid", "42"), ("user", "root")]),
+        ];
+        let manager = FakeExtensionManager::new()
+            .query_response("SELECT * FROM processes", rows.clone())
+            .start(&test_socket("result-set"))
+            .unwrap();
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+
+        let result = client.query_borrowed("SELECT * FROM processes").unwrap();
+        assert_eq!(result.len(), 2);
+        for (packed, row) in result.rows().zip(&rows) {
+            let expected: BTreeMap<&str, &str> = row
+                .iter()
+                .map(|(column, value)| (column.as_str(), value.as_str()))
+                .collect();
+            assert_eq!(packed, expected);
+        }
+
+        // A failed query is read to its end as well, leaving the connection
+        // usable.
+        match client.query_borrowed("SELECT 1") {
+            Err(OsqueryError::Status { .. }) => {}
+            res => panic!("expected a status error, got {:?}", res),
+        }
+        assert!(!client.needs_reconnect());
+        assert_eq!(
+            client
+                .query_borrowed("SELECT * FROM processes")
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+}
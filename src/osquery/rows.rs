@@ -0,0 +1,564 @@
+use crate::osquery::columns::ColumnType;
+use crate::osquery::error::OsqueryError;
+use crate::osquery::register_extension::OsqueryClient;
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+use std::ops::{Deref, DerefMut};
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// A single row of a query result, as a map of column name to value.
+///
+/// Rows compare and hash by all their columns, so they can be put in a
+/// `HashSet` or `BTreeSet` to take the difference or intersection of two
+/// results. The row derefs to the underlying map, so columns are read the
+/// same way as on a plain result row.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use osquery_rs::osquery::*;
+/// # use osquery_rs::osquery::rows::ResultRow;
+/// # use std::collections::HashSet;
+/// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+/// let before: HashSet<ResultRow> = client
+///     .query("SELECT pid, name FROM processes")
+///     .unwrap()
+///     .into_iter()
+///     .map(ResultRow::from)
+///     .collect();
+/// let after: HashSet<ResultRow> = client
+///     .query("SELECT pid, name FROM processes")
+///     .unwrap()
+///     .into_iter()
+///     .map(ResultRow::from)
+///     .collect();
+/// for row in after.difference(&before) {
+///     println!("started: {:?}", row.get("name"));
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ResultRow(BTreeMap<String, String>);
+
+impl ResultRow {
+    /// Returns the underlying map of column name to value.
+    pub fn into_inner(self) -> BTreeMap<String, String> {
+        self.0
+    }
+}
+
+impl From<BTreeMap<String, String>> for ResultRow {
+    fn from(row: BTreeMap<String, String>) -> Self {
+        ResultRow(row)
+    }
+}
+
+impl From<ResultRow> for BTreeMap<String, String> {
+    fn from(row: ResultRow) -> Self {
+        row.0
+    }
+}
+
+impl Deref for ResultRow {
+    type Target = BTreeMap<String, String>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for ResultRow {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// How `query_keyed` handles rows that share a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateKeys {
+    /// Fail with `OsqueryError::DuplicateKey`.
+    Error,
+
+    /// Keep the last row with the key.
+    LastWins,
+}
+
+/// Returns a hash of a result set that doesn't depend on the order of the
+/// rows, since osquery doesn't guarantee any ordering without an
+/// `ORDER BY`. Every row is hashed on its own and the row hashes are
+/// combined with a wrapping sum, which unlike a XOR doesn't let duplicate
+/// rows cancel each other out.
+///
+/// The hash is FNV-1a based so it stays the same across processes and Rust
+/// versions, and can be stored to compare against later.
+pub fn result_hash(rows: &[BTreeMap<String, String>]) -> u64 {
+    rows.iter().map(row_hash).fold(
+        fnv(FNV_OFFSET_BASIS, &(rows.len() as u64).to_be_bytes()),
+        |acc, h| acc.wrapping_add(h),
+    )
+}
+
+/// Hashes the columns of a row in name order. Every name and value is
+/// followed by a distinct separator byte, which can't occur in UTF-8 text,
+/// so shifting characters between adjacent cells changes the hash.
+fn row_hash(row: &BTreeMap<String, String>) -> u64 {
+    row.iter().fold(FNV_OFFSET_BASIS, |h, (column, value)| {
+        let h = fnv(h, column.as_bytes());
+        let h = fnv(h, &[0xff]);
+        let h = fnv(h, value.as_bytes());
+        fnv(h, &[0xfe])
+    })
+}
+
+/// Compares two values of a column, as numbers if the column is numeric.
+/// Values that aren't numbers, such as the empty string osquery returns for
+/// a missing value, come before all numbers and compare as text.
+fn compare_values(a: &str, b: &str, numeric: bool) -> Ordering {
+    if !numeric {
+        return a.cmp(b);
+    }
+    match (a.trim().parse::<f64>(), b.trim().parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.total_cmp(&b),
+        (Ok(_), Err(_)) => Ordering::Greater,
+        (Err(_), Ok(_)) => Ordering::Less,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+fn fnv(mut h: u64, bytes: &[u8]) -> u64 {
+    for b in bytes {
+        h ^= u64::from(*b);
+        h = h.wrapping_mul(FNV_PRIME);
+    }
+    h
+}
+
+impl OsqueryClient {
+    /// Runs the query and returns a hash of its result, see `result_hash`.
+    /// Comparing the hash between polls tells whether the result changed
+    /// without having to keep the previous rows around.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    pub fn query_hash(&mut self, query: &str) -> Result<u64, OsqueryError> {
+        Ok(result_hash(&self.query(query)?))
+    }
+
+    /// Runs the query and drops every row that is an exact duplicate of an
+    /// earlier one, keeping the rest in the order osquery returned them.
+    /// Rows are compared by all their columns, as `ResultRow` does, which
+    /// helps with joins and evented tables that can return the same row
+    /// more than once. A `SELECT DISTINCT` does the same in osquery when the
+    /// SQL can be changed.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// let rows = client
+    ///     .query_dedup("SELECT path FROM process_events")
+    ///     .unwrap();
+    /// ```
+    pub fn query_dedup(
+        &mut self,
+        query: &str,
+    ) -> Result<Vec<BTreeMap<String, String>>, OsqueryError> {
+        let mut seen = HashSet::new();
+        Ok(self
+            .query(query)?
+            .into_iter()
+            .map(ResultRow::from)
+            .filter(|row| seen.insert(row.clone()))
+            .map(ResultRow::into_inner)
+            .collect())
+    }
+
+    /// Runs the query and indexes the rows by the value of `key_column`.
+    /// Rows sharing a key are handled as `duplicates` says, and a row
+    /// without the key column is returned as `OsqueryError::MissingColumn`.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    /// * `key_column` - The column to index the rows by
+    /// * `duplicates` - What to do with rows sharing a key
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// # use osquery_rs::osquery::rows::DuplicateKeys;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// let processes = client
+    ///     .query_keyed("SELECT * FROM processes", "pid", DuplicateKeys::Error)
+    ///     .unwrap();
+    /// println!("{:?}", processes.get("1"));
+    /// ```
+    pub fn query_keyed(
+        &mut self,
+        query: &str,
+        key_column: &str,
+        duplicates: DuplicateKeys,
+    ) -> Result<BTreeMap<String, BTreeMap<String, String>>, OsqueryError> {
+        let mut keyed = BTreeMap::new();
+        for row in self.query(query)? {
+            let key = row
+                .get(key_column)
+                .cloned()
+                .ok_or_else(|| OsqueryError::MissingColumn(String::from(key_column)))?;
+
+            if duplicates == DuplicateKeys::Error && keyed.contains_key(&key) {
+                return Err(OsqueryError::DuplicateKey(key));
+            }
+            keyed.insert(key, row);
+        }
+        Ok(keyed)
+    }
+
+    /// Runs the query and partitions the rows by the value of `group_by`,
+    /// keeping every row of a group in the order osquery returned them.
+    /// Unlike a `GROUP BY` in the query, no row is folded into another. Rows
+    /// without the column are grouped under the empty string.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    /// * `group_by` - The column to group the rows by
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// let by_user = client
+    ///     .query_grouped("SELECT pid, name, uid FROM processes", "uid")
+    ///     .unwrap();
+    /// for (uid, processes) in &by_user {
+    ///     println!("{}: {} processes", uid, processes.len());
+    /// }
+    /// ```
+    pub fn query_grouped(
+        &mut self,
+        query: &str,
+        group_by: &str,
+    ) -> Result<BTreeMap<String, Vec<BTreeMap<String, String>>>, OsqueryError> {
+        let mut groups: BTreeMap<String, Vec<BTreeMap<String, String>>> = BTreeMap::new();
+        for row in self.query(query)? {
+            let key = row.get(group_by).cloned().unwrap_or_default();
+            groups.entry(key).or_default().push(row);
+        }
+        Ok(groups)
+    }
+
+    /// Runs the query and sorts the rows by the columns in `by`, the first
+    /// column first. Columns `get_query_columns` reports as numeric are
+    /// compared as numbers, so `10` comes after `9`, and other columns as
+    /// text. Rows that compare equal keep the order osquery returned them
+    /// in, and a row without a column compares as if it were empty.
+    ///
+    /// Putting an `ORDER BY` in the query is preferable when the SQL can be
+    /// changed, as it saves asking osquery for the columns first.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    /// * `by` - The columns to sort the rows by
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// let rows = client
+    ///     .query_sorted("SELECT pid, name FROM processes", &["name", "pid"])
+    ///     .unwrap();
+    /// ```
+    pub fn query_sorted(
+        &mut self,
+        query: &str,
+        by: &[&str],
+    ) -> Result<Vec<BTreeMap<String, String>>, OsqueryError> {
+        let types: BTreeMap<String, String> = self.get_query_columns(query)?.into_iter().collect();
+        let numeric: Vec<bool> = by
+            .iter()
+            .map(|column| {
+                types
+                    .get(*column)
+                    .and_then(|type_| type_.parse::<ColumnType>().ok())
+                    .is_some_and(|type_| type_.is_numeric())
+            })
+            .collect();
+
+        let mut rows = self.query(query)?;
+        rows.sort_by(|a, b| {
+            by.iter()
+                .zip(&numeric)
+                .map(|(column, numeric)| {
+                    let a = a.get(*column).map_or("", String::as_str);
+                    let b = b.get(*column).map_or("", String::as_str);
+                    compare_values(a, b, *numeric)
+                })
+                .find(|ordering| *ordering != Ordering::Equal)
+                .unwrap_or(Ordering::Equal)
+        });
+        Ok(rows)
+    }
+
+    /// Runs the query and returns its result by column rather than by row:
+    /// every column name maps to the values of that column, in the order
+    /// osquery returned the rows. The columns are those of all the rows, and
+    /// a row without one of them gets an empty string for it, so every
+    /// column has one value per row.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// let columns = client
+    ///     .query_columnar("SELECT name, resident_size FROM processes")
+    ///     .unwrap();
+    /// let total: u64 = columns["resident_size"]
+    ///     .iter()
+    ///     .filter_map(|size| size.parse::<u64>().ok())
+    ///     .sum();
+    /// ```
+    pub fn query_columnar(
+        &mut self,
+        query: &str,
+    ) -> Result<BTreeMap<String, Vec<String>>, OsqueryError> {
+        let rows = self.query(query)?;
+        let mut columns: BTreeMap<String, Vec<String>> = rows
+            .iter()
+            .flat_map(|row| row.keys())
+            .map(|column| (column.clone(), Vec::with_capacity(rows.len())))
+            .collect();
+
+        for mut row in rows {
+            for (column, values) in columns.iter_mut() {
+                values.push(row.remove(column).unwrap_or_default());
+            }
+        }
+        Ok(columns)
+    }
+
+    /// Runs the query and returns every row as its columns in the order the
+    /// query selects them, as read with `get_query_columns`.
+    ///
+    /// osquery returns every row as a map of column name to value, so when a
+    /// query selects two columns with the same name, such as a `JOIN` of
+    /// two tables with a `name` column, only one of the values reaches the
+    /// client. `query` silently keeps that value, while this returns
+    /// `OsqueryError::DuplicateColumn` instead, before running the query.
+    /// Aliasing the columns with `AS` keeps both values.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// let rows = client
+    ///     .query_ordered("SELECT p.name, u.username FROM processes p JOIN users u USING (uid)")
+    ///     .unwrap();
+    /// ```
+    pub fn query_ordered(
+        &mut self,
+        query: &str,
+    ) -> Result<Vec<Vec<(String, String)>>, OsqueryError> {
+        let mut columns: Vec<String> = Vec::new();
+        for (column, _) in self.get_query_columns(query)? {
+            if columns.contains(&column) {
+                return Err(OsqueryError::DuplicateColumn(column));
+            }
+            columns.push(column);
+        }
+
+        Ok(self
+            .query(query)?
+            .into_iter()
+            .map(|mut row| {
+                columns
+                    .iter()
+                    .map(|column| {
+                        let value = row.remove(column).unwrap_or_default();
+                        (column.clone(), value)
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osquery::fake::{test_socket, FakeExtensionManager, RunningFakeExtensionManager};
+    use crate::osquery::osquery::ExtensionPluginResponse;
+
+    const QUERY: &str = "SELECT * FROM t";
+
+    fn row(cells: &[(&str, &str)]) -> BTreeMap<String, String> {
+        cells
+            .iter()
+            .map(|(column, value)| (String::from(*column), String::from(*value)))
+            .collect()
+    }
+
+    fn manager(name: &str, rows: ExtensionPluginResponse) -> RunningFakeExtensionManager {
+        FakeExtensionManager::new()
+            .query_response(QUERY, rows)
+            .start(&test_socket(name))
+            .unwrap()
+    }
+
+    fn client(manager: &RunningFakeExtensionManager) -> OsqueryClient {
+        OsqueryClient::new(manager.socket_path()).unwrap()
+    }
+
+    #[test]
+    fn result_hash_ignores_the_order_of_the_rows() {
+        let a = row(&[("name", "init"), ("pid", "1")]);
+        let b = row(&[("name", "sshd"), ("pid", "42")]);
+        let c = row(&[("name", "cron"), ("pid", "7")]);
+        let hash = result_hash(&[a.clone(), b.clone(), c.clone()]);
+        assert_eq!(hash, result_hash(&[c.clone(), a.clone(), b.clone()]));
+        assert_eq!(hash, result_hash(&[b.clone(), c.clone(), a.clone()]));
+
+        let changed = row(&[("name", "sshd"), ("pid", "43")]);
+        assert_ne!(hash, result_hash(&[a.clone(), changed, c.clone()]));
+
+        // Duplicates don't cancel out, and cells don't bleed into each other.
+        assert_ne!(result_hash(&[a.clone(), a.clone()]), result_hash(&[]));
+        assert_ne!(
+            result_hash(&[row(&[("ab", "c")])]),
+            result_hash(&[row(&[("a", "bc")])])
+        );
+    }
+
+    #[test]
+    fn query_hash_is_the_hash_of_the_result() {
+        let rows = vec![row(&[("pid", "1")]), row(&[("pid", "2")])];
+        let manager = manager("rows-hash", rows.clone());
+        let mut client = client(&manager);
+        let hash = client.query_hash(QUERY).unwrap();
+        assert_eq!(hash, result_hash(&rows));
+
+        manager.set_query_response(QUERY, rows.into_iter().rev().collect());
+        assert_eq!(client.query_hash(QUERY).unwrap(), hash);
+
+        manager.set_query_response(QUERY, vec![row(&[("pid", "1")]), row(&[("pid", "3")])]);
+        assert_ne!(client.query_hash(QUERY).unwrap(), hash);
+    }
+
+    #[test]
+    fn query_keyed_handles_duplicate_keys_as_asked() {
+        let rows = vec![
+            row(&[("pid", "1"), ("name", "init")]),
+            row(&[("pid", "1"), ("name", "systemd")]),
+            row(&[("pid", "2"), ("name", "kthreadd")]),
+        ];
+        let manager = manager("rows-keyed", rows);
+        let mut client = client(&manager);
+
+        match client.query_keyed(QUERY, "pid", DuplicateKeys::Error) {
+            Err(OsqueryError::DuplicateKey(key)) => assert_eq!(key, "1"),
+            res => panic!("expected a duplicate key, got {:?}", res),
+        }
+
+        let keyed = client
+            .query_keyed(QUERY, "pid", DuplicateKeys::LastWins)
+            .unwrap();
+        assert_eq!(keyed.len(), 2);
+        assert_eq!(keyed["1"]["name"], "systemd");
+        assert_eq!(keyed["2"]["name"], "kthreadd");
+
+        match client.query_keyed(QUERY, "uid", DuplicateKeys::LastWins) {
+            Err(OsqueryError::MissingColumn(column)) => assert_eq!(column, "uid"),
+            res => panic!("expected a missing column, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn query_sorted_compares_numeric_columns_as_numbers() {
+        let rows = vec![
+            row(&[("pid", "10"), ("name", "b")]),
+            row(&[("pid", "9"), ("name", "b")]),
+            row(&[("pid", ""), ("name", "c")]),
+            row(&[("pid", "100"), ("name", "a")]),
+        ];
+        let manager = FakeExtensionManager::new()
+            .query_response(QUERY, rows)
+            .query_columns(QUERY, &[("pid", "BIGINT"), ("name", "TEXT")])
+            .start(&test_socket("rows-sorted"))
+            .unwrap();
+        let mut client = client(&manager);
+
+        let pids = |rows: Vec<BTreeMap<String, String>>| -> Vec<String> {
+            rows.into_iter()
+                .map(|mut row| row.remove("pid").unwrap())
+                .collect()
+        };
+        assert_eq!(
+            pids(client.query_sorted(QUERY, &["pid"]).unwrap()),
+            vec!["", "9", "10", "100"]
+        );
+        // Equal names keep the order osquery returned them in.
+        assert_eq!(
+            pids(client.query_sorted(QUERY, &["name"]).unwrap()),
+            vec!["100", "10", "9", ""]
+        );
+        assert_eq!(
+            pids(client.query_sorted(QUERY, &["name", "pid"]).unwrap()),
+            vec!["100", "9", "10", ""]
+        );
+    }
+
+    #[test]
+    fn query_columnar_pads_missing_cells() {
+        let rows = vec![
+            row(&[("pid", "1"), ("name", "init")]),
+            row(&[("pid", "2"), ("user", "root")]),
+        ];
+        let manager = manager("rows-columnar", rows);
+        let mut client = client(&manager);
+
+        let columns = client.query_columnar(QUERY).unwrap();
+        assert_eq!(columns.len(), 3);
+        assert_eq!(columns["pid"], vec!["1", "2"]);
+        assert_eq!(columns["name"], vec!["init", ""]);
+        assert_eq!(columns["user"], vec!["", "root"]);
+    }
+
+    #[test]
+    fn query_dedup_keeps_the_first_of_each_row() {
+        let rows = vec![
+            row(&[("path", "/bin/a")]),
+            row(&[("path", "/bin/b")]),
+            row(&[("path", "/bin/a")]),
+            row(&[("path", "/bin/a"), ("pid", "1")]),
+        ];
+        let manager = manager("rows-dedup", rows.clone());
+        let mut client = client(&manager);
+
+        assert_eq!(
+            client.query_dedup(QUERY).unwrap(),
+            vec![rows[0].clone(), rows[1].clone(), rows[3].clone()]
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use crate::osquery::error::{OsqueryError, Phase};
+use crate::osquery::osquery::ExtensionPluginResponse;
+use crate::osquery::register_extension::OsqueryClient;
+
+use std::time::{Duration, Instant};
+
+/// The most samples room is made for up front, so that a huge count
+/// doesn't allocate before the first sample is taken.
+const MAX_PREALLOCATED_SAMPLES: usize = 1024;
+
+/// A snapshot taken by `OsqueryClient::sample`: when the query was sent,
+/// and its rows or the error it failed with.
+pub type Sample = (Instant, Result<ExtensionPluginResponse, OsqueryError>);
+
+impl OsqueryClient {
+    /// Runs the query `count` times, `interval` apart, and returns every
+    /// snapshot with the time it was taken, for watching a value over a
+    /// short window without a scheduler. The interval is kept between the
+    /// starts of the queries, so a slow query doesn't make the samples
+    /// drift, and a query running longer than the interval is followed
+    /// right away by the next one.
+    ///
+    /// A failed query is recorded in its sample and the run goes on. The
+    /// cancel flag of the client is checked before every sample, including
+    /// the first, and during the waits between them, and a cancellation
+    /// ends the run with `OsqueryError::Cancelled`. An interval so long that
+    /// the next sample would be due past what `Instant` can represent ends
+    /// the run after the samples taken so far.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    /// * `interval` - The time between the starts of two samples
+    /// * `count` - The number of samples to take
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// # use std::time::Duration;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// let samples = client
+    ///     .sample("SELECT average FROM load_average WHERE period = '1m'", Duration::from_secs(1), 10)
+    ///     .unwrap();
+    /// for (taken, rows) in samples {
+    ///     println!("{:?}: {:?}", taken, rows);
+    /// }
+    /// ```
+    pub fn sample(
+        &mut self,
+        query: &str,
+        interval: Duration,
+        count: usize,
+    ) -> Result<Vec<Sample>, OsqueryError> {
+        let mut samples = Vec::with_capacity(count.min(MAX_PREALLOCATED_SAMPLES));
+        let mut next = Instant::now();
+        for _ in 0..count {
+            self.sleep(next.saturating_duration_since(Instant::now()), Phase::Query)?;
+
+            let taken = Instant::now();
+            samples.push((taken, self.query(query)));
+            next = match next.checked_add(interval) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+        Ok(samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osquery::builder::OsqueryClientBuilder;
+    use crate::osquery::fake::{test_socket, FakeExtensionManager};
+
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    const QUERY: &str = "SELECT 1";
+
+    #[test]
+    fn cancellation_is_checked_before_the_first_sample() {
+        let manager = FakeExtensionManager::new()
+            .query_response(QUERY, ExtensionPluginResponse::new())
+            .start(&test_socket("sample-cancel"))
+            .unwrap();
+        let mut client = OsqueryClientBuilder::new(manager.socket_path())
+            .cancel_flag(Arc::new(AtomicBool::new(true)))
+            .build()
+            .unwrap();
+
+        match client.sample(QUERY, Duration::from_millis(1), 3) {
+            Err(OsqueryError::Cancelled { .. }) => {}
+            res => panic!("expected a cancellation, got {:?}", res),
+        }
+        assert!(manager.queries().is_empty());
+    }
+
+    #[test]
+    fn an_unrepresentable_interval_ends_the_run() {
+        let manager = FakeExtensionManager::new()
+            .query_response(QUERY, ExtensionPluginResponse::new())
+            .start(&test_socket("sample-overflow"))
+            .unwrap();
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+
+        let samples = client.sample(QUERY, Duration::MAX, usize::MAX).unwrap();
+        assert_eq!(samples.len(), 1);
+        assert!(samples[0].1.is_ok());
+    }
+}
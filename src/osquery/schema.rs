@@ -0,0 +1,272 @@
+use crate::osquery::error::{OsqueryError, Phase};
+use crate::osquery::osquery::ExtensionPluginResponse;
+use crate::osquery::register_extension::OsqueryClient;
+use crate::osquery::sql::{quote_ident, unquoted_ident};
+
+const TABLES_QUERY: &str = "SELECT name FROM osquery_registry WHERE registry = 'table'";
+
+/// Used when the registry can't be read, which older osquery versions don't
+/// support.
+const LEGACY_TABLES_QUERY: &str = "SELECT name FROM sqlite_master WHERE type = 'table'";
+
+impl OsqueryClient {
+    /// Returns the names of all tables the connected osquery exposes, sorted
+    /// by name. The tables are read from the osquery registry, falling back
+    /// to the SQLite schema on osquery versions without a usable registry
+    /// table, which either reject the query with a status or return no rows.
+    /// Any other failure, like a broken connection, is returned as is.
+    pub fn list_tables(&mut self) -> Result<Vec<String>, OsqueryError> {
+        let rows = match self.query_with_warnings(TABLES_QUERY) {
+            Ok((rows, _)) if !rows.is_empty() => rows,
+            Ok(_) | Err(OsqueryError::Status { .. }) => {
+                self.query_with_warnings(LEGACY_TABLES_QUERY)?.0
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut tables: Vec<String> = rows
+            .into_iter()
+            .filter_map(|mut row| row.remove("name"))
+            .collect();
+        tables.sort();
+        tables.dedup();
+        Ok(tables)
+    }
+
+    /// Returns the number of distinct values in a column of a table. Both
+    /// names are quoted into the query, so any name works, but a double
+    /// quote in a name has to be doubled, as `sql::escape_identifier` does.
+    /// A name with a lone double quote is rejected with
+    /// `OsqueryError::InvalidIdentifier`.
+    ///
+    /// # Arguments
+    ///
+    /// * `table` - The name of the table
+    /// * `column` - The name of the column in the table
+    pub fn column_cardinality(&mut self, table: &str, column: &str) -> Result<i64, OsqueryError> {
+        let table = quote_ident(table)?;
+        let column = quote_ident(column)?;
+        self.query(&format!(
+            "SELECT count(DISTINCT {}) AS cardinality FROM {}",
+            column, table
+        ))?
+        .into_iter()
+        .next()
+        .and_then(|row| row.get("cardinality").and_then(|n| n.parse().ok()))
+        .ok_or_else(|| OsqueryError::Protocol {
+            phase: Phase::Query,
+            message: String::from("missing distinct count"),
+        })
+    }
+
+    /// Selects only the given columns from a table, optionally filtered by a
+    /// `WHERE` clause, so wide tables don't send columns that aren't used.
+    /// The table and column names are quoted like for
+    /// `column_cardinality`, and at least one column has to be given. The
+    /// `WHERE` clause is inserted as is, so it must not be built from
+    /// untrusted input.
+    ///
+    /// The returned rows only hold the requested columns.
+    ///
+    /// # Arguments
+    ///
+    /// * `table` - The name of the table
+    /// * `columns` - The columns to select
+    /// * `where_clause` - The condition rows have to match, without `WHERE`
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// let rows = client
+    ///     .query_columns("processes", &["pid", "name"], Some("uid = 0"))
+    ///     .unwrap();
+    /// ```
+    pub fn query_columns(
+        &mut self,
+        table: &str,
+        columns: &[&str],
+        where_clause: Option<&str>,
+    ) -> Result<ExtensionPluginResponse, OsqueryError> {
+        let table = quote_ident(table)?;
+        let projection = columns
+            .iter()
+            .map(|column| quote_ident(column))
+            .collect::<Result<Vec<_>, _>>()?
+            .join(", ");
+        if projection.is_empty() {
+            return Err(OsqueryError::InvalidIdentifier(String::new()));
+        }
+
+        let mut query = format!("SELECT {} FROM {}", projection, table);
+        if let Some(where_clause) = where_clause {
+            query.push_str(" WHERE ");
+            query.push_str(where_clause);
+        }
+
+        let columns: Vec<String> = columns.iter().map(|c| unquoted_ident(c)).collect();
+        let mut rows = self.query(&query)?;
+        for row in rows.iter_mut() {
+            row.retain(|column, _| columns.contains(column));
+        }
+        Ok(rows)
+    }
+
+    /// Returns the columns the query would return, in order, along with the
+    /// type osquery reports for each, without running the query.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A osquery compatible query string
+    pub fn get_query_columns(
+        &mut self,
+        query: &str,
+    ) -> Result<Vec<(String, String)>, OsqueryError> {
+        // Every row holds a single column, mapping its name to its type.
+        Ok(self
+            .get_query_columns_rows(query)?
+            .into_iter()
+            .flat_map(|row| row.into_iter())
+            .collect())
+    }
+
+    /// Checks that the query returns exactly the `expected` columns, in any
+    /// order, and returns `OsqueryError::ColumnMismatch` listing the missing
+    /// and unexpected columns otherwise. The columns are read with
+    /// `get_query_columns`, so the query isn't run.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// client
+    ///     .assert_columns("SELECT pid, name FROM processes", &["pid", "name"])
+    ///     .unwrap();
+    /// ```
+    pub fn assert_columns(&mut self, query: &str, expected: &[&str]) -> Result<(), OsqueryError> {
+        self.compare_columns(query, expected, true)
+    }
+
+    /// Checks that the query returns at least the `expected` columns, like
+    /// `assert_columns`, but allows it to return others as well.
+    pub fn assert_columns_subset(
+        &mut self,
+        query: &str,
+        expected: &[&str],
+    ) -> Result<(), OsqueryError> {
+        self.compare_columns(query, expected, false)
+    }
+
+    fn compare_columns(
+        &mut self,
+        query: &str,
+        expected: &[&str],
+        exact: bool,
+    ) -> Result<(), OsqueryError> {
+        let actual: Vec<String> = self
+            .get_query_columns(query)?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+
+        let missing: Vec<String> = expected
+            .iter()
+            .filter(|column| !actual.iter().any(|name| name == *column))
+            .map(|column| String::from(*column))
+            .collect();
+        let unexpected: Vec<String> = if exact {
+            actual
+                .into_iter()
+                .filter(|name| !expected.contains(&name.as_str()))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        if missing.is_empty() && unexpected.is_empty() {
+            return Ok(());
+        }
+        Err(OsqueryError::ColumnMismatch {
+            missing,
+            unexpected,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osquery::fake::{test_socket, FakeExtensionManager};
+
+    use std::collections::BTreeMap;
+
+    fn names(names: &[&str]) -> ExtensionPluginResponse {
+        names
+            .iter()
+            .map(|name| {
+                let mut row = BTreeMap::new();
+                row.insert(String::from("name"), String::from(*name));
+                row
+            })
+            .collect()
+    }
+
+    #[test]
+    fn list_tables_reads_the_registry() {
+        let manager = FakeExtensionManager::new()
+            .query_response(TABLES_QUERY, names(&["users", "processes"]))
+            .query_response(LEGACY_TABLES_QUERY, names(&["legacy"]))
+            .start(&test_socket("tables-registry"))
+            .unwrap();
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+
+        assert_eq!(client.list_tables().unwrap(), vec!["processes", "users"]);
+        assert_eq!(manager.queries(), vec![TABLES_QUERY]);
+    }
+
+    #[test]
+    fn list_tables_falls_back_on_a_status_error() {
+        // Without a canned response, the fake rejects the registry query
+        // with a failed status.
+        let manager = FakeExtensionManager::new()
+            .query_response(LEGACY_TABLES_QUERY, names(&["legacy"]))
+            .start(&test_socket("tables-status"))
+            .unwrap();
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+
+        assert_eq!(client.list_tables().unwrap(), vec!["legacy"]);
+    }
+
+    #[test]
+    fn list_tables_falls_back_on_an_empty_registry() {
+        let manager = FakeExtensionManager::new()
+            .query_response(TABLES_QUERY, names(&[]))
+            .query_response(LEGACY_TABLES_QUERY, names(&["legacy"]))
+            .start(&test_socket("tables-empty"))
+            .unwrap();
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+
+        assert_eq!(client.list_tables().unwrap(), vec!["legacy"]);
+    }
+
+    #[test]
+    fn list_tables_returns_other_errors() {
+        let manager = FakeExtensionManager::new()
+            .query_response_bytes(
+                TABLES_QUERY,
+                vec![BTreeMap::from([(String::from("name"), vec![0xff])])],
+            )
+            .query_response(LEGACY_TABLES_QUERY, names(&["legacy"]))
+            .start(&test_socket("tables-protocol"))
+            .unwrap();
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+
+        match client.list_tables() {
+            Err(OsqueryError::Protocol { .. }) => {}
+            res => panic!("expected a protocol error, got {:?}", res),
+        }
+        assert_eq!(manager.queries(), vec![TABLES_QUERY]);
+    }
+}
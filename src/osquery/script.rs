@@ -0,0 +1,260 @@
+use crate::osquery::error::{OsqueryError, Phase};
+use crate::osquery::osquery::ExtensionPluginResponse;
+use crate::osquery::register_extension::OsqueryClient;
+
+use std::fs;
+use std::path::Path;
+
+impl OsqueryClient {
+    /// Runs every statement in a file of SQL, such as a `.sql` file of
+    /// queries kept by an operator, and returns each statement along with
+    /// its rows. See `split_statements` for how the file is split.
+    ///
+    /// The statements run in order and the first one to fail stops the run
+    /// with its error.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the file of SQL statements
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// for (statement, rows) in client.query_file("queries.sql".as_ref()).unwrap() {
+    ///     println!("{}: {} rows", statement, rows.len());
+    /// }
+    /// ```
+    pub fn query_file(
+        &mut self,
+        path: &Path,
+    ) -> Result<Vec<(String, ExtensionPluginResponse)>, OsqueryError> {
+        let sql = fs::read_to_string(path).map_err(OsqueryError::io(Phase::Query))?;
+        split_statements(&sql)
+            .into_iter()
+            .map(|statement| {
+                let rows = self.query(&statement)?;
+                Ok((statement, rows))
+            })
+            .collect()
+    }
+
+    /// Runs every statement, carrying on past the ones that fail, and
+    /// returns a report of how each one went.
+    ///
+    /// # Arguments
+    ///
+    /// * `statements` - The osquery compatible queries to run, in order
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// let report = client.query_batch(&["SELECT * FROM uptime", "SELECT * FROM nope"]);
+    /// for (idx, e) in report.failures() {
+    ///     eprintln!("statement {} failed: {}", idx, e);
+    /// }
+    /// ```
+    pub fn query_batch<S: AsRef<str>>(&mut self, statements: &[S]) -> BatchReport {
+        let results = statements
+            .iter()
+            .map(|statement| {
+                let statement = statement.as_ref();
+                (String::from(statement), self.query(statement))
+            })
+            .collect();
+        BatchReport { results }
+    }
+
+    /// Runs every statement in a file of SQL like `query_file`, but carries
+    /// on past the statements that fail and returns a report of how each
+    /// one went. Only failing to read the file is returned as an error.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the file of SQL statements
+    pub fn query_file_report(&mut self, path: &Path) -> Result<BatchReport, OsqueryError> {
+        let sql = fs::read_to_string(path).map_err(OsqueryError::io(Phase::Query))?;
+        Ok(self.query_batch(&split_statements(&sql)))
+    }
+}
+
+/// How each statement of a batch went, as returned by `query_batch` and
+/// `query_file_report`.
+#[derive(Debug)]
+pub struct BatchReport {
+    results: Vec<(String, Result<ExtensionPluginResponse, OsqueryError>)>,
+}
+
+impl BatchReport {
+    /// Returns whether every statement succeeded.
+    pub fn all_ok(&self) -> bool {
+        self.results.iter().all(|(_, res)| res.is_ok())
+    }
+
+    /// Returns the index of every statement that failed, along with its
+    /// error.
+    pub fn failures(&self) -> Vec<(usize, &OsqueryError)> {
+        self.results
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, (_, res))| res.as_ref().err().map(|e| (idx, e)))
+            .collect()
+    }
+
+    /// Returns every statement along with its result, in the order they ran.
+    pub fn results(&self) -> &[(String, Result<ExtensionPluginResponse, OsqueryError>)] {
+        &self.results
+    }
+
+    /// Returns every statement along with its result, in the order they ran.
+    pub fn into_results(self) -> Vec<(String, Result<ExtensionPluginResponse, OsqueryError>)> {
+        self.results
+    }
+}
+
+/// Splits SQL text into its statements on the semicolons ending them.
+///
+/// Semicolons inside string literals, quoted identifiers and comments don't
+/// end a statement. Quotes are escaped by doubling them, the way SQLite
+/// does. Statements are trimmed, and empty statements, including those that
+/// only hold comments, are dropped.
+///
+/// # Examples
+///
+/// ```
+/// # use osquery_rs::osquery::script::split_statements;
+/// let statements = split_statements("SELECT ';'; -- done;\nSELECT 2;");
+/// assert_eq!(statements, vec!["SELECT ';'", "-- done;\nSELECT 2"]);
+/// ```
+pub fn split_statements(sql: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut has_code = false;
+    let mut chars = sql.char_indices().peekable();
+
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '\'' | '"' | '`' | '[' => {
+                let close = if c == '[' { ']' } else { c };
+                // A doubled quote is an escaped quote rather than the end of
+                // the literal, which the loop handles as two literals.
+                for (_, c) in chars.by_ref() {
+                    if c == close {
+                        break;
+                    }
+                }
+                has_code = true;
+            }
+            '-' if chars.peek().map(|(_, c)| *c) == Some('-') => {
+                for (_, c) in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek().map(|(_, c)| *c) == Some('*') => {
+                chars.next();
+                let mut prev = ' ';
+                for (_, c) in chars.by_ref() {
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+            }
+            ';' => {
+                if has_code {
+                    statements.push(String::from(sql[start..idx].trim()));
+                }
+                start = idx + 1;
+                has_code = false;
+            }
+            c if !c.is_whitespace() => has_code = true,
+            _ => {}
+        }
+    }
+
+    if has_code {
+        statements.push(String::from(sql[start..].trim()));
+    }
+    statements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osquery::fake::{test_socket, FakeExtensionManager};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn doubled_quotes_stay_in_the_literal() {
+        assert_eq!(
+            split_statements("SELECT 'it''s;'; SELECT 2"),
+            vec!["SELECT 'it''s;'", "SELECT 2"]
+        );
+    }
+
+    #[test]
+    fn quoted_identifiers_hold_semicolons() {
+        assert_eq!(
+            split_statements(r#"SELECT "a;b" FROM t; SELECT `c;d`; SELECT [e;f]"#),
+            vec![r#"SELECT "a;b" FROM t"#, "SELECT `c;d`", "SELECT [e;f]"]
+        );
+    }
+
+    #[test]
+    fn comments_hold_semicolons() {
+        assert_eq!(
+            split_statements("SELECT /* a; b */ 1; SELECT 2 -- c; d\n; SELECT 3"),
+            vec!["SELECT /* a; b */ 1", "SELECT 2 -- c; d", "SELECT 3"]
+        );
+    }
+
+    #[test]
+    fn unterminated_literal_runs_to_the_end() {
+        assert_eq!(
+            split_statements("SELECT 1; SELECT 'open; SELECT 2"),
+            vec!["SELECT 1", "SELECT 'open; SELECT 2"]
+        );
+    }
+
+    #[test]
+    fn empty_and_comment_only_statements_are_dropped() {
+        assert_eq!(
+            split_statements(";; -- only a comment;\n; /* and; another */ ;\nSELECT 1;\n\n"),
+            vec!["SELECT 1"]
+        );
+        assert!(split_statements("").is_empty());
+        assert!(split_statements("  \n-- nothing\n").is_empty());
+    }
+
+    #[test]
+    fn batch_report_lists_the_failures_by_index() {
+        let row = BTreeMap::from([(String::from("total_seconds"), String::from("10"))]);
+        let manager = FakeExtensionManager::new()
+            .query_response("SELECT * FROM uptime", vec![row])
+            .query_response("SELECT 1", vec![])
+            .start(&test_socket("script-batch"))
+            .unwrap();
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+
+        let report =
+            client.query_batch(&["SELECT * FROM uptime", "SELECT * FROM nope", "SELECT 1"]);
+        assert!(!report.all_ok());
+        let failed: Vec<usize> = report.failures().into_iter().map(|(idx, _)| idx).collect();
+        assert_eq!(failed, vec![1]);
+        let statements: Vec<&str> = report.results().iter().map(|(s, _)| s.as_str()).collect();
+        assert_eq!(
+            statements,
+            vec!["SELECT * FROM uptime", "SELECT * FROM nope", "SELECT 1"]
+        );
+        assert_eq!(report.results()[0].1.as_ref().unwrap().len(), 1);
+
+        let report = client.query_batch(&["SELECT 1", "SELECT * FROM uptime"]);
+        assert!(report.all_ok());
+        assert!(report.failures().is_empty());
+    }
+}
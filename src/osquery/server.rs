@@ -0,0 +1,791 @@
+use crate::osquery::builder::OsqueryClientBuilder;
+use crate::osquery::error::{OsqueryError, Phase};
+use crate::osquery::keepalive::sleep_unless_stopped;
+use crate::osquery::osquery::*;
+use crate::osquery::register_extension::{ConnectionState, OsqueryClient};
+
+use log::warn;
+use std::fs;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use thrift::protocol::{TBinaryInputProtocol, TBinaryOutputProtocol};
+use thrift::server::TProcessor;
+
+/// A single extension registered with the osquery extension manager that
+/// serves the calls osquery routes to it.
+///
+/// osquery connects back to the extension on a socket derived from the
+/// manager socket and the uuid of the registration, `<socket>.<uuid>`.
+/// Every connection on that socket is served on its own thread by the
+/// given handler.
+pub struct ExtensionServer {
+    /// The name the extension was registered with.
+    name: String,
+
+    /// The registration and the accept loop serving it, shared with the
+    /// registration watcher.
+    state: Arc<Mutex<ServerState>>,
+
+    /// The routes the extension registers with.
+    registry: ExtensionRegistry,
+
+    /// The processor serving the calls osquery makes.
+    processor: Arc<dyn TProcessor + Send + Sync>,
+
+    /// The thread keeping the extension registered, if one was started.
+    watcher: Option<Watcher>,
+
+    /// What happens when osquery shuts down.
+    shutdown: Arc<Shutdown>,
+}
+
+/// The callback for osquery shutting down and the server to stop then.
+struct Shutdown {
+    /// Set once the shutdown was handled.
+    fired: AtomicBool,
+
+    /// Called before the server is stopped.
+    callback: Mutex<Option<Box<dyn Fn() + Send>>>,
+
+    /// The server stopped on shutdown, if it is still around.
+    state: Mutex<Weak<Mutex<ServerState>>>,
+}
+
+/// Wraps a handler to notice osquery asking the extension to shut down.
+struct ShutdownHandler<H> {
+    inner: H,
+    shutdown: Arc<Shutdown>,
+}
+
+/// The registration of a server and the accept loop serving it.
+struct ServerState {
+    /// The client holding the registration with the extension manager.
+    client: OsqueryClient,
+
+    /// The uuid of the registration.
+    uuid: ExtensionRouteUUID,
+
+    /// The path of the socket osquery connects to.
+    socket_path: String,
+
+    /// The accept loop, unless the server is stopped or registering again
+    /// failed.
+    accept: Option<AcceptLoop>,
+
+    /// Set once the server is stopped.
+    stopped: bool,
+}
+
+/// An accept loop running on its own thread.
+struct AcceptLoop {
+    /// Set to end the accept loop.
+    stopped: Arc<AtomicBool>,
+
+    /// The thread running the accept loop.
+    thread: JoinHandle<()>,
+}
+
+/// A thread running in the background until stopped.
+struct Watcher {
+    stopped: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl ExtensionServer {
+    /// Registers an extension with the extension manager listening on
+    /// `socket_file` and starts serving it in the background, connecting
+    /// with the default settings. See `start_with` to connect otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `socket_file` - The path of the extension manager socket
+    /// * `name` - The name of the extension
+    /// * `registry` - The plugins provided by the extension
+    /// * `handler` - The handler for the calls osquery makes
+    pub fn start<H>(
+        socket_file: &str,
+        name: &str,
+        registry: ExtensionRegistry,
+        handler: H,
+    ) -> Result<Self, OsqueryError>
+    where
+        H: ExtensionSyncHandler + Send + Sync + 'static,
+    {
+        Self::start_with(
+            &OsqueryClientBuilder::new(socket_file),
+            name,
+            registry,
+            handler,
+        )
+    }
+
+    /// Registers an extension like `start`, connecting to the extension
+    /// manager with the settings of `builder`, such as `strict`,
+    /// `verify_peer_uid` or its timeouts. The connections osquery makes to
+    /// the extension are served with the same framing. The keepalive,
+    /// registration lease and health endpoint of the builder are left out,
+    /// as `keep_registered` keeps the registration instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `builder` - The settings to connect to the extension manager with
+    /// * `name` - The name of the extension
+    /// * `registry` - The plugins provided by the extension
+    /// * `handler` - The handler for the calls osquery makes
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::builder::OsqueryClientBuilder;
+    /// # use osquery_rs::osquery::plugin::Plugins;
+    /// # use osquery_rs::osquery::server::ExtensionServer;
+    /// # let plugins = Plugins::new();
+    /// let builder = OsqueryClientBuilder::new("/tmp/osquery.sock").verify_peer_uid(0);
+    /// let registry = plugins.registry();
+    /// let server = ExtensionServer::start_with(&builder, "example", registry, plugins).unwrap();
+    /// ```
+    pub fn start_with<H>(
+        builder: &OsqueryClientBuilder,
+        name: &str,
+        registry: ExtensionRegistry,
+        handler: H,
+    ) -> Result<Self, OsqueryError>
+    where
+        H: ExtensionSyncHandler + Send + Sync + 'static,
+    {
+        let mut client = builder.for_helper().build()?;
+        let shutdown = Arc::new(Shutdown {
+            fired: AtomicBool::new(false),
+            callback: Mutex::new(None),
+            state: Mutex::new(Weak::new()),
+        });
+        let processor: Arc<dyn TProcessor + Send + Sync> =
+            Arc::new(ExtensionSyncProcessor::new(ShutdownHandler {
+                inner: handler,
+                shutdown: shutdown.clone(),
+            }));
+        let (uuid, socket_path, accept) = listen(&mut client, name, &registry, &processor)?;
+
+        let state = Arc::new(Mutex::new(ServerState {
+            client,
+            uuid,
+            socket_path,
+            accept: Some(accept),
+            stopped: false,
+        }));
+        *shutdown.state.lock().unwrap() = Arc::downgrade(&state);
+
+        Ok(ExtensionServer {
+            name: String::from(name),
+            state,
+            registry,
+            processor,
+            watcher: None,
+            shutdown,
+        })
+    }
+
+    /// Returns the name the extension was registered with.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the uuid assigned to the extension by the extension manager.
+    pub fn uuid(&self) -> ExtensionRouteUUID {
+        self.state.lock().unwrap().uuid
+    }
+
+    /// Returns the path of the socket osquery connects to.
+    pub fn socket_path(&self) -> String {
+        self.state.lock().unwrap().socket_path.clone()
+    }
+
+    /// Calls `callback` when osquery shuts down, so the extension can flush
+    /// what it buffered, such as the logs held by a logger plugin. osquery
+    /// shutting down is noticed either by its call asking the extension to
+    /// shut down, or, with `keep_registered`, by the extension manager
+    /// closing the connection. The callback is called once, before the
+    /// server is stopped as with `stop`, which is all that happens on
+    /// shutdown without a callback.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::plugin::Plugins;
+    /// # use osquery_rs::osquery::server::ExtensionServer;
+    /// # let plugins = Plugins::new();
+    /// let registry = plugins.registry();
+    /// let server =
+    ///     ExtensionServer::start("/tmp/osquery.sock", "example", registry, plugins).unwrap();
+    /// server.on_shutdown(|| println!("osquery is shutting down"));
+    /// ```
+    pub fn on_shutdown<F>(&self, callback: F)
+    where
+        F: Fn() + Send + 'static,
+    {
+        *self.shutdown.callback.lock().unwrap() = Some(Box::new(callback));
+    }
+
+    /// Checks every `interval` that the extension is still registered, and
+    /// registers it again with the same name and routes if osquery dropped
+    /// it, for example while reloading its config. The new registration
+    /// comes with a new uuid and socket, and `on_reregister` is called with
+    /// the new uuid once the extension is served again, so the caller can
+    /// reinitialize any state tied to the registration.
+    ///
+    /// A failed check or registration is logged and retried on the next
+    /// interval. Calling this again replaces the previous watcher.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// # use osquery_rs::osquery::plugin::Plugins;
+    /// # use osquery_rs::osquery::server::ExtensionServer;
+    /// # use std::time::Duration;
+    /// # let plugins = Plugins::new();
+    /// let registry = plugins.registry();
+    /// let mut server =
+    ///     ExtensionServer::start("/tmp/osquery.sock", "example", registry, plugins).unwrap();
+    /// server.keep_registered(Duration::from_secs(10), |uuid| {
+    ///     println!("registered again as {}", uuid);
+    /// });
+    /// ```
+    pub fn keep_registered<F>(&mut self, interval: Duration, on_reregister: F)
+    where
+        F: Fn(ExtensionRouteUUID) + Send + 'static,
+    {
+        self.stop_watcher();
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let watch_stopped = stopped.clone();
+        let state = self.state.clone();
+        let name = self.name.clone();
+        let registry = self.registry.clone();
+        let processor = self.processor.clone();
+        let shutdown = self.shutdown.clone();
+        let thread = thread::spawn(move || {
+            while sleep_unless_stopped(interval, &watch_stopped) {
+                let mut state = state.lock().unwrap();
+                if state.stopped {
+                    break;
+                }
+
+                match reregister(&mut state, &name, &registry, &processor) {
+                    Ok(Some(uuid)) => {
+                        drop(state);
+                        on_reregister(uuid);
+                    }
+                    Ok(None) => {}
+                    Err(_) if state.client.connection_state() == ConnectionState::Disconnected => {
+                        // The extension manager went away with osquery.
+                        drop(state);
+                        shutdown.fire();
+                        break;
+                    }
+                    Err(e) => warn!("Failed to keep extension {} registered: {:?}", name, e),
+                }
+            }
+        });
+
+        self.watcher = Some(Watcher { stopped, thread });
+    }
+
+    /// Stops accepting connections from osquery, removes the extension
+    /// socket and deregisters the extension. Stopping an already stopped
+    /// server does nothing.
+    pub fn stop(&mut self) -> Result<(), OsqueryError> {
+        self.stop_watcher();
+
+        stop_state(&mut self.state.lock().unwrap())
+    }
+
+    fn stop_watcher(&mut self) {
+        if let Some(watcher) = self.watcher.take() {
+            watcher.stopped.store(true, Ordering::SeqCst);
+            let _ = watcher.thread.join();
+        }
+    }
+}
+
+impl Drop for ExtensionServer {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+impl Shutdown {
+    /// Calls the callback and stops the server, the first time only.
+    fn fire(&self) {
+        if self.fired.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        if let Some(callback) = self.callback.lock().unwrap().as_ref() {
+            callback();
+        }
+        let state = self.state.lock().unwrap().upgrade();
+        if let Some(state) = state {
+            let _ = stop_state(&mut state.lock().unwrap());
+        }
+    }
+}
+
+impl<H: ExtensionSyncHandler> ExtensionSyncHandler for ShutdownHandler<H> {
+    fn handle_ping(&self) -> thrift::Result<ExtensionStatus> {
+        self.inner.handle_ping()
+    }
+
+    fn handle_call(
+        &self,
+        registry: String,
+        item: String,
+        request: ExtensionPluginRequest,
+    ) -> thrift::Result<ExtensionResponse> {
+        self.inner.handle_call(registry, item, request)
+    }
+
+    fn handle_shutdown(&self) -> thrift::Result<()> {
+        let res = self.inner.handle_shutdown();
+        self.shutdown.fire();
+        res
+    }
+}
+
+impl AcceptLoop {
+    /// Ends the accept loop and removes its socket.
+    fn stop(self, socket_path: &str) {
+        // The accept loop only checks the flag between connections, so
+        // wake it up with a connection of our own.
+        self.stopped.store(true, Ordering::SeqCst);
+        let _ = UnixStream::connect(socket_path);
+        let _ = self.thread.join();
+        let _ = fs::remove_file(socket_path);
+    }
+}
+
+/// Stops accepting connections and deregisters the extension, unless the
+/// server is already stopped.
+fn stop_state(state: &mut ServerState) -> Result<(), OsqueryError> {
+    if state.stopped {
+        return Ok(());
+    }
+
+    state.stopped = true;
+    if let Some(accept) = state.accept.take() {
+        accept.stop(&state.socket_path);
+    }
+    state.client.deregister_extension()?;
+    Ok(())
+}
+
+/// Registers the extension and starts serving it on the socket for the
+/// new registration. Returns the uuid, the socket path and the accept loop.
+fn listen(
+    client: &mut OsqueryClient,
+    name: &str,
+    registry: &ExtensionRegistry,
+    processor: &Arc<dyn TProcessor + Send + Sync>,
+) -> Result<(ExtensionRouteUUID, String, AcceptLoop), OsqueryError> {
+    let uuid = client.register_extension_with_registry(name, registry.clone())?;
+
+    let socket_path = format!("{}.{}", client.config().socket_file, uuid);
+    let _ = fs::remove_file(&socket_path);
+    let listener = match UnixListener::bind(&socket_path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            let _ = client.deregister_extension();
+            return Err(OsqueryError::Io {
+                phase: Phase::Register,
+                error: e,
+            });
+        }
+    };
+
+    let stopped = Arc::new(AtomicBool::new(false));
+    let accept_stopped = stopped.clone();
+    let processor = processor.clone();
+    let strict = client.config().strict;
+    let thread = thread::spawn(move || serve(listener, processor, accept_stopped, strict));
+    Ok((uuid, socket_path, AcceptLoop { stopped, thread }))
+}
+
+/// Registers the extension again if the extension manager no longer lists
+/// it, or if registering it again failed before, moving the accept loop to
+/// the socket of the new registration.
+/// Returns the new uuid if the extension was registered again.
+fn reregister(
+    state: &mut ServerState,
+    name: &str,
+    registry: &ExtensionRegistry,
+    processor: &Arc<dyn TProcessor + Send + Sync>,
+) -> Result<Option<ExtensionRouteUUID>, OsqueryError> {
+    if state.accept.is_some() && state.client.extensions()?.contains_key(&state.uuid) {
+        return Ok(None);
+    }
+
+    // The old socket goes first, as the new registration may well get the
+    // same uuid and with it the same socket path.
+    if let Some(old) = state.accept.take() {
+        old.stop(&state.socket_path);
+    }
+
+    let (uuid, socket_path, accept) = listen(&mut state.client, name, registry, processor)?;
+    state.accept = Some(accept);
+    state.uuid = uuid;
+    state.socket_path = socket_path;
+    Ok(Some(uuid))
+}
+
+/// Hosts several extensions from one process. Each extension has its own
+/// registration and socket, but they are all started and stopped together.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use osquery_rs::osquery::server::ExtensionHost;
+/// # use osquery_rs::osquery::*;
+/// # struct FirstHandler;
+/// # struct SecondHandler;
+/// # impl ExtensionSyncHandler for FirstHandler {
+/// #     fn handle_ping(&self) -> thrift::Result<ExtensionStatus> { unimplemented!() }
+/// #     fn handle_call(&self, _: String, _: String, _: ExtensionPluginRequest)
+/// #         -> thrift::Result<ExtensionResponse> { unimplemented!() }
+/// #     fn handle_shutdown(&self) -> thrift::Result<()> { unimplemented!() }
+/// # }
+/// # impl ExtensionSyncHandler for SecondHandler {
+/// #     fn handle_ping(&self) -> thrift::Result<ExtensionStatus> { unimplemented!() }
+/// #     fn handle_call(&self, _: String, _: String, _: ExtensionPluginRequest)
+/// #         -> thrift::Result<ExtensionResponse> { unimplemented!() }
+/// #     fn handle_shutdown(&self) -> thrift::Result<()> { unimplemented!() }
+/// # }
+/// let mut host = ExtensionHost::new("/tmp/osquery.sock");
+/// host.add_extension("first", ExtensionRegistry::default(), FirstHandler)
+///     .add_extension("second", ExtensionRegistry::default(), SecondHandler);
+///
+/// for (name, e) in host.run() {
+///     eprintln!("Failed to start {}: {:?}", name, e);
+/// }
+/// ```
+pub struct ExtensionHost {
+    /// The settings to connect to the extension manager with.
+    builder: OsqueryClientBuilder,
+
+    /// The extensions added since the last `run`.
+    pending: Vec<PendingExtension>,
+
+    /// The extensions that are registered and being served.
+    servers: Vec<ExtensionServer>,
+}
+
+/// Starts an extension given the settings to connect to the extension
+/// manager with.
+type StartFn = Box<dyn FnOnce(&OsqueryClientBuilder) -> Result<ExtensionServer, OsqueryError>>;
+
+/// An extension that has been added to a host but not started yet.
+struct PendingExtension {
+    name: String,
+    start: StartFn,
+}
+
+impl ExtensionHost {
+    /// Returns a new host for extensions registering with the extension
+    /// manager listening on `socket_file`, connecting with the default
+    /// settings.
+    pub fn new(socket_file: &str) -> Self {
+        Self::with_builder(OsqueryClientBuilder::new(socket_file))
+    }
+
+    /// Returns a new host for extensions registering with the extension
+    /// manager `builder` connects to, with its settings, as for
+    /// `ExtensionServer::start_with`.
+    pub fn with_builder(builder: OsqueryClientBuilder) -> Self {
+        ExtensionHost {
+            builder,
+            pending: Vec::new(),
+            servers: Vec::new(),
+        }
+    }
+
+    /// Adds an extension to be started on the next call to `run`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the extension
+    /// * `registry` - The plugins provided by the extension
+    /// * `handler` - The handler for the calls osquery makes
+    pub fn add_extension<H>(
+        &mut self,
+        name: &str,
+        registry: ExtensionRegistry,
+        handler: H,
+    ) -> &mut Self
+    where
+        H: ExtensionSyncHandler + Send + Sync + 'static,
+    {
+        let owned_name = String::from(name);
+        self.pending.push(PendingExtension {
+            name: owned_name.clone(),
+            start: Box::new(move |builder| {
+                ExtensionServer::start_with(builder, &owned_name, registry, handler)
+            }),
+        });
+        self
+    }
+
+    /// Registers and starts serving every extension added since the last
+    /// call. An extension that fails to start is reported in the returned
+    /// list along with its error, and does not affect the others.
+    pub fn run(&mut self) -> Vec<(String, OsqueryError)> {
+        let mut failures = Vec::new();
+        for pending in self.pending.drain(..) {
+            match (pending.start)(&self.builder) {
+                Ok(server) => self.servers.push(server),
+                Err(e) => failures.push((pending.name, e)),
+            }
+        }
+        failures
+    }
+
+    /// Returns the extensions that are currently being served.
+    pub fn servers(&self) -> &[ExtensionServer] {
+        &self.servers
+    }
+
+    /// Stops every running extension. All extensions are stopped even if
+    /// some fail to deregister, in which case the last error is returned.
+    pub fn shutdown(&mut self) -> Result<(), OsqueryError> {
+        let mut result = Ok(());
+        for mut server in self.servers.drain(..) {
+            if let Err(e) = server.stop() {
+                warn!("Failed to stop extension {}: {:?}", server.name(), e);
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+impl Drop for ExtensionHost {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+/// Accepts connections from osquery until the server is stopped, serving
+/// each one on its own thread with the binary protocol, strict or not.
+pub(crate) fn serve(
+    listener: UnixListener,
+    processor: Arc<dyn TProcessor + Send + Sync>,
+    stopped: Arc<AtomicBool>,
+    strict: bool,
+) {
+    for stream in listener.incoming() {
+        if stopped.load(Ordering::SeqCst) {
+            break;
+        }
+
+        match stream {
+            Ok(stream) => {
+                let processor = processor.clone();
+                thread::spawn(move || serve_connection(stream, processor.as_ref(), strict));
+            }
+            Err(e) => warn!("Failed to accept connection: {:?}", e),
+        }
+    }
+}
+
+/// Processes calls on a single connection until osquery closes it.
+fn serve_connection(stream: UnixStream, processor: &dyn TProcessor, strict: bool) {
+    let socket_tx = match stream.try_clone() {
+        Ok(socket_tx) => socket_tx,
+        Err(e) => {
+            warn!("Failed to serve connection: {:?}", e);
+            return;
+        }
+    };
+
+    let mut in_proto = TBinaryInputProtocol::new(stream, strict);
+    let mut out_proto = TBinaryOutputProtocol::new(socket_tx, strict);
+    while processor.process(&mut in_proto, &mut out_proto).is_ok() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osquery::columns::{ColumnDefinition, ColumnType};
+    use crate::osquery::fake::{test_socket, FakeExtensionManager};
+    use crate::osquery::plugin::{
+        ConfigPlugin, LoggerPlugin, Plugins, TablePlugin, CONFIG_REGISTRY, LOGGER_REGISTRY,
+        TABLE_REGISTRY,
+    };
+
+    use std::collections::BTreeMap;
+
+    struct Table;
+
+    impl TablePlugin for Table {
+        fn name(&self) -> String {
+            String::from("mixed_table")
+        }
+
+        fn columns(&self) -> Vec<ColumnDefinition> {
+            vec![ColumnDefinition::new("value", ColumnType::Text)]
+        }
+
+        fn generate(
+            &self,
+            _request: &ExtensionPluginRequest,
+        ) -> Result<ExtensionPluginResponse, String> {
+            let mut row = BTreeMap::new();
+            row.insert(String::from("value"), String::from("from the table"));
+            Ok(vec![row])
+        }
+    }
+
+    struct Config;
+
+    impl ConfigPlugin for Config {
+        fn name(&self) -> String {
+            String::from("mixed_config")
+        }
+
+        fn generate_config(&self) -> Result<BTreeMap<String, String>, String> {
+            let mut config = BTreeMap::new();
+            config.insert(String::from("main"), String::from("{\"options\":{}}"));
+            Ok(config)
+        }
+    }
+
+    struct Logger(Arc<Mutex<Vec<String>>>);
+
+    impl LoggerPlugin for Logger {
+        fn name(&self) -> String {
+            String::from("mixed_logger")
+        }
+
+        fn log_string(&self, s: &str) -> Result<(), String> {
+            self.0.lock().unwrap().push(String::from(s));
+            Ok(())
+        }
+    }
+
+    fn request(pairs: &[(&str, &str)]) -> ExtensionPluginRequest {
+        pairs
+            .iter()
+            .map(|(key, value)| (String::from(*key), String::from(*value)))
+            .collect()
+    }
+
+    #[test]
+    fn routes_calls_to_table_config_and_logger_plugins() {
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let plugins = Plugins::new()
+            .add_table(Table)
+            .add_config(Config)
+            .add_logger(Logger(logged.clone()));
+        let registry = plugins.registry();
+
+        let manager = FakeExtensionManager::new()
+            .start(&test_socket("mixed-plugins"))
+            .unwrap();
+        let mut server =
+            ExtensionServer::start(manager.socket_path(), "mixed", registry, plugins).unwrap();
+
+        let registered = manager.registry(server.uuid()).unwrap();
+        assert!(registered[TABLE_REGISTRY].contains_key("mixed_table"));
+        assert!(registered[CONFIG_REGISTRY].contains_key("mixed_config"));
+        assert!(registered[LOGGER_REGISTRY].contains_key("mixed_logger"));
+
+        // Call the extension the way osquery does, over its own socket.
+        let stream = UnixStream::connect(server.socket_path()).unwrap();
+        let mut osquery = ExtensionSyncClient::new(
+            TBinaryInputProtocol::new(stream.try_clone().unwrap(), true),
+            TBinaryOutputProtocol::new(stream, true),
+        );
+
+        let table = osquery
+            .call(
+                String::from(TABLE_REGISTRY),
+                String::from("mixed_table"),
+                request(&[("action", "generate")]),
+            )
+            .unwrap();
+        assert_eq!(table.status.unwrap().code, Some(0));
+        assert_eq!(table.response.unwrap()[0]["value"], "from the table");
+
+        let config = osquery
+            .call(
+                String::from(CONFIG_REGISTRY),
+                String::from("mixed_config"),
+                request(&[("action", "genConfig")]),
+            )
+            .unwrap();
+        assert_eq!(config.status.unwrap().code, Some(0));
+        assert_eq!(config.response.unwrap()[0]["main"], "{\"options\":{}}");
+
+        let logger = osquery
+            .call(
+                String::from(LOGGER_REGISTRY),
+                String::from("mixed_logger"),
+                request(&[("string", "{\"name\":\"pack\"}")]),
+            )
+            .unwrap();
+        assert_eq!(logger.status.unwrap().code, Some(0));
+        assert_eq!(*logged.lock().unwrap(), vec!["{\"name\":\"pack\"}"]);
+
+        // An item is only found in its own registry.
+        let misrouted = osquery
+            .call(
+                String::from(CONFIG_REGISTRY),
+                String::from("mixed_table"),
+                request(&[("action", "genConfig")]),
+            )
+            .unwrap();
+        assert_eq!(
+            misrouted.status.unwrap().code,
+            Some(ExtensionCode::ExtFailed as i32)
+        );
+
+        server.stop().unwrap();
+        assert!(manager.extensions().is_empty());
+    }
+
+    #[test]
+    fn start_with_connects_with_the_settings_of_the_builder() {
+        let manager = FakeExtensionManager::new()
+            .strict(false)
+            .start(&test_socket("server-builder"))
+            .unwrap();
+
+        // The default settings expect strict framing, and can't read the
+        // replies of the manager.
+        assert!(ExtensionServer::start(
+            manager.socket_path(),
+            "strict",
+            Plugins::new().registry(),
+            Plugins::new()
+        )
+        .is_err());
+
+        let builder = OsqueryClientBuilder::new(manager.socket_path()).strict(false);
+        let mut server =
+            ExtensionServer::start_with(&builder, "lax", Plugins::new().registry(), Plugins::new())
+                .unwrap();
+        assert!(manager.extensions().contains_key(&server.uuid()));
+        server.stop().unwrap();
+
+        let mut host = ExtensionHost::with_builder(builder);
+        host.add_extension("hosted", Plugins::new().registry(), Plugins::new());
+        assert!(host.run().is_empty());
+        assert_eq!(host.servers().len(), 1);
+        host.shutdown().unwrap();
+        assert!(!manager
+            .extensions()
+            .values()
+            .any(|info| info.name.as_deref() != Some("strict")));
+    }
+}
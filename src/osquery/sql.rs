@@ -0,0 +1,57 @@
+use crate::osquery::error::OsqueryError;
+
+/// Quotes a table or column name for use in SQL, wrapping it in double
+/// quotes and doubling any double quote in it, the way SQLite escapes
+/// identifiers. Any name is safe to interpolate once quoted, and a quoted
+/// name only ever refers to a table or column, never to a keyword.
+///
+/// # Examples
+///
+/// ```
+/// # use osquery_rs::osquery::sql::escape_identifier;
+/// assert_eq!(escape_identifier("processes"), "\"processes\"");
+/// assert_eq!(escape_identifier("my \"table\""), "\"my \"\"table\"\"\"");
+/// ```
+pub fn escape_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Quotes a value as an SQL string literal, wrapping it in single quotes
+/// and doubling any single quote in it.
+///
+/// # Examples
+///
+/// ```
+/// # use osquery_rs::osquery::sql::escape_literal;
+/// assert_eq!(escape_literal("/etc/passwd"), "'/etc/passwd'");
+/// assert_eq!(escape_literal("it's"), "'it''s'");
+/// ```
+pub fn escape_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Quotes a table or column name given to a helper that builds SQL from
+/// it. A double quote in the name has to be escaped by doubling it, as in
+/// `escape_identifier`, so that a name can't end the quoting early. Names
+/// with a lone double quote, empty names and names with a NUL character
+/// are rejected with `OsqueryError::InvalidIdentifier`.
+pub(crate) fn quote_ident(name: &str) -> Result<String, OsqueryError> {
+    let invalid = || OsqueryError::InvalidIdentifier(String::from(name));
+    if name.is_empty() || name.contains('\0') {
+        return Err(invalid());
+    }
+
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        if c == '"' && chars.next() != Some('"') {
+            return Err(invalid());
+        }
+    }
+    Ok(format!("\"{}\"", name))
+}
+
+/// Returns the name a quoted identifier given to `quote_ident` refers to,
+/// with its escaped double quotes unescaped.
+pub(crate) fn unquoted_ident(name: &str) -> String {
+    name.replace("\"\"", "\"")
+}
@@ -0,0 +1,353 @@
+use std::collections::BTreeMap;
+
+/// Returns the status logs in `logs` with a severity of at least `min`,
+/// as a JSON array. Logs without a readable severity are kept. `None` is
+/// returned if `logs` isn't a JSON array, in which case it can't be
+/// filtered.
+///
+/// osquery sends status logs as a JSON array of objects, each holding the
+/// severity as an integer under the `s` key, for example
+/// `[{"s":1,"f":"events.cpp","i":42,"m":"message"}]`. The array is only
+/// scanned as far as needed to find the severities, rather than parsed.
+pub(crate) fn filter_status_logs(logs: &str, min: i64) -> Option<String> {
+    let kept: Vec<&str> = split_array(logs)?
+        .into_iter()
+        .filter(|log| severity(log).is_none_or(|s| s >= min))
+        .collect();
+    Some(format!("[{}]", kept.join(",")))
+}
+
+/// Merges `decorations` into the `decorations` object of a log record,
+/// adding the object if the record has none. A decoration the record
+/// already has keeps the value in the record. `None` is returned if the
+/// record isn't a JSON object, or its decorations aren't one.
+pub(crate) fn decorate_record(
+    record: &str,
+    decorations: &BTreeMap<String, String>,
+) -> Option<String> {
+    let record = record.trim();
+    let members = split_object(record)?;
+    match members.iter().find(|(key, _)| *key == "decorations") {
+        Some((_, existing)) => {
+            let existing_keys: Vec<&str> = split_object(existing)?
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect();
+            let added: Vec<String> = decorations
+                .iter()
+                .filter(|(key, _)| !existing_keys.contains(&escape_json(key).as_str()))
+                .map(|(key, value)| json_member(key, value))
+                .collect();
+            if added.is_empty() {
+                return Some(String::from(record));
+            }
+
+            // Insert before the closing brace of the decorations.
+            let end = offset_in(record, existing) + existing.len() - 1;
+            let separator = if existing_keys.is_empty() { "" } else { "," };
+            Some(format!(
+                "{}{}{}{}",
+                &record[..end],
+                separator,
+                added.join(","),
+                &record[end..]
+            ))
+        }
+        None => {
+            let added: Vec<String> = decorations
+                .iter()
+                .map(|(key, value)| json_member(key, value))
+                .collect();
+            let end = record.len() - 1;
+            let separator = if members.is_empty() { "" } else { "," };
+            Some(format!(
+                "{}{}\"decorations\":{{{}}}}}",
+                &record[..end],
+                separator,
+                added.join(",")
+            ))
+        }
+    }
+}
+
+/// Decorates every status log in a JSON array of them like
+/// `decorate_record`. `None` is returned if `logs` isn't a JSON array.
+/// Logs that can't be decorated are kept as they are.
+pub(crate) fn decorate_status_logs(
+    logs: &str,
+    decorations: &BTreeMap<String, String>,
+) -> Option<String> {
+    let decorated: Vec<String> = split_array(logs)?
+        .into_iter()
+        .map(|log| decorate_record(log, decorations).unwrap_or_else(|| String::from(log)))
+        .collect();
+    Some(format!("[{}]", decorated.join(",")))
+}
+
+/// Returns a JSON object member with a string value.
+fn json_member(key: &str, value: &str) -> String {
+    format!("\"{}\":\"{}\"", escape_json(key), escape_json(value))
+}
+
+/// Returns where `part`, a slice of `whole`, starts in it.
+fn offset_in(whole: &str, part: &str) -> usize {
+    part.as_ptr() as usize - whole.as_ptr() as usize
+}
+
+/// Splits a JSON object into its members, as the text of every key, still
+/// escaped and without its quotes, and the text of its value.
+fn split_object(json: &str) -> Option<Vec<(&str, &str)>> {
+    let inner = json.trim().strip_prefix('{')?.strip_suffix('}')?;
+    split_elements(inner)?
+        .into_iter()
+        .map(|member| {
+            let rest = member.strip_prefix('"')?;
+            let end = string_end(rest)?;
+            let value = rest[end + 1..].trim_start().strip_prefix(':')?.trim();
+            Some((&rest[..end], value))
+        })
+        .collect()
+}
+
+/// Returns where the JSON string starting at `s`, after its opening quote,
+/// ends.
+fn string_end(s: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (idx, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Some(idx),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Splits a JSON array into the text of its elements.
+fn split_array(json: &str) -> Option<Vec<&str>> {
+    let inner = json.trim().strip_prefix('[')?.strip_suffix(']')?;
+    split_elements(inner)
+}
+
+/// Splits the inside of a JSON array or object on the commas between its
+/// elements.
+fn split_elements(inner: &str) -> Option<Vec<&str>> {
+    let mut elements = Vec::new();
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (idx, c) in inner.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match c {
+            '"' => in_string = true,
+            '[' | '{' => depth += 1,
+            ']' | '}' => depth = depth.checked_sub(1)?,
+            ',' if depth == 0 => {
+                elements.push(inner[start..idx].trim());
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+
+    if depth != 0 || in_string {
+        return None;
+    }
+
+    let last = inner[start..].trim();
+    if !last.is_empty() || !elements.is_empty() {
+        elements.push(last);
+    }
+    Some(elements)
+}
+
+/// Returns the integer under the `s` key of a JSON object.
+fn severity(object: &str) -> Option<i64> {
+    let inner = object.trim().strip_prefix('{')?;
+
+    let mut depth = 0usize;
+    let mut chars = inner.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '"' => {
+                let start = idx + 1;
+                let mut end = start;
+                let mut escaped = false;
+                for (idx, c) in chars.by_ref() {
+                    match c {
+                        _ if escaped => escaped = false,
+                        '\\' => escaped = true,
+                        '"' => {
+                            end = idx;
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+
+                let rest = inner[end + 1..].trim_start();
+                if depth == 0 && &inner[start..end] == "s" && rest.starts_with(':') {
+                    let value = rest[1..].trim_start();
+                    let len = value
+                        .find(|c: char| !(c.is_ascii_digit() || c == '-'))
+                        .unwrap_or(value.len());
+                    return value[..len].parse().ok();
+                }
+            }
+            '[' | '{' => depth += 1,
+            ']' | '}' => depth = depth.checked_sub(1)?,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Escapes a string for use inside a JSON string.
+pub(crate) fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_below_the_minimum_severity() {
+        let logs = r#"[{"s":0,"m":"info"},{"s":1,"m":"warning"},{"s":2,"m":"error"}]"#;
+        assert_eq!(
+            filter_status_logs(logs, 1).unwrap(),
+            r#"[{"s":1,"m":"warning"},{"s":2,"m":"error"}]"#
+        );
+    }
+
+    #[test]
+    fn ignores_nested_severity_keys() {
+        let logs = r#"[{"d":{"s":2},"s":0,"m":"info"},{"x":[{"s":0}],"s":2}]"#;
+        assert_eq!(
+            filter_status_logs(logs, 1).unwrap(),
+            r#"[{"x":[{"s":0}],"s":2}]"#
+        );
+    }
+
+    #[test]
+    fn skips_escaped_quotes_in_messages() {
+        let logs = r#"[{"m":"a \"s\":2, b","s":0},{"m":"\\","s":1}]"#;
+        assert_eq!(
+            filter_status_logs(logs, 1).unwrap(),
+            r#"[{"m":"\\","s":1}]"#
+        );
+    }
+
+    #[test]
+    fn keeps_logs_without_a_severity() {
+        let logs = r#"[{"m":"no severity"},{"s":"high"},{"s":0}]"#;
+        assert_eq!(
+            filter_status_logs(logs, 1).unwrap(),
+            r#"[{"m":"no severity"},{"s":"high"}]"#
+        );
+    }
+
+    #[test]
+    fn rejects_anything_but_an_array() {
+        assert_eq!(filter_status_logs(r#"{"s":2}"#, 1), None);
+        assert_eq!(filter_status_logs("not json", 1), None);
+        assert_eq!(filter_status_logs(r#"[{"s":2}"#, 1), None);
+        assert_eq!(filter_status_logs(r#"[{"m":"open}]"#, 1), None);
+    }
+
+    #[test]
+    fn filters_an_empty_array() {
+        assert_eq!(filter_status_logs("[]", 1).unwrap(), "[]");
+        assert_eq!(filter_status_logs(" [ ] ", 1).unwrap(), "[]");
+    }
+
+    fn decorations(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (String::from(*key), String::from(*value)))
+            .collect()
+    }
+
+    #[test]
+    fn adds_decorations_to_a_record_without_any() {
+        let added = decorations(&[("host", "web-1"), ("site", "a")]);
+        assert_eq!(
+            decorate_record(r#"{"name":"q","action":"added"}"#, &added).unwrap(),
+            r#"{"name":"q","action":"added","decorations":{"host":"web-1","site":"a"}}"#
+        );
+    }
+
+    #[test]
+    fn adds_decorations_to_an_empty_record() {
+        let added = decorations(&[("host", "web-1")]);
+        assert_eq!(
+            decorate_record("{}", &added).unwrap(),
+            r#"{"decorations":{"host":"web-1"}}"#
+        );
+        assert_eq!(
+            decorate_record(r#"{"decorations":{}}"#, &added).unwrap(),
+            r#"{"decorations":{"host":"web-1"}}"#
+        );
+    }
+
+    #[test]
+    fn keeps_the_decorations_a_record_already_has() {
+        let added = decorations(&[("host", "web-1"), ("site", "a")]);
+        assert_eq!(
+            decorate_record(r#"{"decorations":{"host":"db-1"},"name":"q"}"#, &added).unwrap(),
+            r#"{"decorations":{"host":"db-1","site":"a"},"name":"q"}"#
+        );
+
+        let only_host = decorations(&[("host", "web-1")]);
+        assert_eq!(
+            decorate_record(r#"{"decorations":{"host":"db-1"}}"#, &only_host).unwrap(),
+            r#"{"decorations":{"host":"db-1"}}"#
+        );
+    }
+
+    #[test]
+    fn compares_and_writes_escaped_keys() {
+        let added = decorations(&[("a\"b", "new"), ("c\\d", "x\ny")]);
+        assert_eq!(
+            decorate_record(r#"{"decorations":{"a\"b":"old"}}"#, &added).unwrap(),
+            r#"{"decorations":{"a\"b":"old","c\\d":"x\u000ay"}}"#
+        );
+    }
+
+    #[test]
+    fn rejects_records_and_decorations_that_are_not_objects() {
+        let added = decorations(&[("host", "web-1")]);
+        assert_eq!(decorate_record(r#"["host"]"#, &added), None);
+        assert_eq!(decorate_record(r#"{"decorations":"host"}"#, &added), None);
+        assert_eq!(decorate_record(r#"{"decorations":["host"]}"#, &added), None);
+    }
+
+    #[test]
+    fn keeps_status_logs_that_cannot_be_decorated() {
+        let added = decorations(&[("host", "web-1")]);
+        assert_eq!(
+            decorate_status_logs(r#"[{"s":0},{"decorations":1}]"#, &added).unwrap(),
+            r#"[{"s":0,"decorations":{"host":"web-1"}},{"decorations":1}]"#
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use crate::osquery::error::OsqueryError;
+use crate::osquery::OsqueryClient;
+
+use std::collections::BTreeMap;
+
+const PROCESS_INFO_QUERY: &str = "SELECT p.pid, p.name, p.path, p.cmdline, p.uid, u.username \
+     FROM processes p LEFT JOIN users u ON p.uid = u.uid";
+
+/// A process running on the host along with the user that owns it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessInfo {
+    /// The process id.
+    pub pid: i64,
+
+    /// The name of the process.
+    pub name: String,
+
+    /// The path to the executed binary, if known.
+    pub path: Option<String>,
+
+    /// The complete argv of the process, if known.
+    pub cmdline: Option<String>,
+
+    /// The uid of the user that owns the process.
+    pub uid: Option<i64>,
+
+    /// The name of the owning user, if it could be resolved from the
+    /// users table.
+    pub username: Option<String>,
+}
+
+impl ProcessInfo {
+    /// Builds a `ProcessInfo` from a single result row. Columns that are
+    /// missing or empty are mapped to `None`, since osquery reports `NULL`
+    /// values as empty strings. A missing pid is returned as
+    /// `OsqueryError::MissingColumn` and one that isn't a number as
+    /// `OsqueryError::Deserialize`.
+    pub fn from_row(row: &BTreeMap<String, String>) -> Result<Self, OsqueryError> {
+        let pid =
+            column(row, "pid").ok_or_else(|| OsqueryError::MissingColumn(String::from("pid")))?;
+        let pid = pid
+            .parse()
+            .map_err(|_| OsqueryError::Deserialize(format!("invalid pid {:?}", pid)))?;
+
+        Ok(ProcessInfo {
+            pid,
+            name: column(row, "name").unwrap_or_default(),
+            path: column(row, "path"),
+            cmdline: column(row, "cmdline"),
+            uid: column(row, "uid").and_then(|uid| uid.parse().ok()),
+            username: column(row, "username"),
+        })
+    }
+}
+
+/// Returns every running process joined with the user that owns it. The
+/// owning user is resolved with a `LEFT JOIN` so processes whose uid has
+/// no entry in the users table are still returned with no username.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use osquery_rs::osquery::*;
+/// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+/// for process in tables::process_info(&mut client).unwrap() {
+///     println!("{} {:?}", process.pid, process.username);
+/// }
+/// ```
+pub fn process_info(client: &mut OsqueryClient) -> Result<Vec<ProcessInfo>, OsqueryError> {
+    client
+        .query(PROCESS_INFO_QUERY)?
+        .iter()
+        .map(ProcessInfo::from_row)
+        .collect()
+}
+
+/// A row of the osquery `processes` table, with its resource usage.
+/// Columns that not every platform has are `None` where they are missing.
+#[cfg(feature = "convenience-tables")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Process {
+    /// The process id.
+    pub pid: i64,
+
+    /// The name of the process.
+    pub name: String,
+
+    /// The path to the executed binary, if known.
+    pub path: Option<String>,
+
+    /// The complete argv of the process, if known.
+    pub cmdline: Option<String>,
+
+    /// The process id of the parent.
+    pub parent: Option<i64>,
+
+    /// The uid of the user that owns the process.
+    pub uid: Option<i64>,
+
+    /// The bytes of private memory in use.
+    pub resident_size: Option<i64>,
+
+    /// The total virtual memory size, in bytes.
+    pub total_size: Option<i64>,
+
+    /// The CPU time spent in user space, in milliseconds.
+    pub user_time: Option<i64>,
+
+    /// The CPU time spent in kernel space, in milliseconds.
+    pub system_time: Option<i64>,
+
+    /// The number of threads of the process.
+    pub threads: Option<i64>,
+
+    /// The start time of the process, in seconds since the unix epoch.
+    pub start_time: Option<i64>,
+}
+
+#[cfg(feature = "convenience-tables")]
+impl Process {
+    /// Builds a `Process` from a row of the `processes` table. Missing or
+    /// empty columns are mapped to `None`, like for `ProcessInfo`.
+    pub fn from_row(row: &BTreeMap<String, String>) -> Result<Self, OsqueryError> {
+        let pid =
+            column(row, "pid").ok_or_else(|| OsqueryError::MissingColumn(String::from("pid")))?;
+        let pid = pid
+            .parse()
+            .map_err(|_| OsqueryError::Deserialize(format!("invalid pid {:?}", pid)))?;
+
+        Ok(Process {
+            pid,
+            name: column(row, "name").unwrap_or_default(),
+            path: column(row, "path"),
+            cmdline: column(row, "cmdline"),
+            parent: number(row, "parent"),
+            uid: number(row, "uid"),
+            resident_size: number(row, "resident_size"),
+            total_size: number(row, "total_size"),
+            user_time: number(row, "user_time"),
+            system_time: number(row, "system_time"),
+            threads: number(row, "threads"),
+            start_time: number(row, "start_time"),
+        })
+    }
+}
+
+#[cfg(feature = "convenience-tables")]
+impl OsqueryClient {
+    /// Returns the processes running on the host, optionally only those
+    /// matching `filter`. The filter is used as the `WHERE` clause of the
+    /// query as is, so it must not be built from untrusted input.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// for process in client.processes(Some("uid = 0")).unwrap() {
+    ///     println!("{} {} {:?}", process.pid, process.name, process.resident_size);
+    /// }
+    /// ```
+    pub fn processes(&mut self, filter: Option<&str>) -> Result<Vec<Process>, OsqueryError> {
+        let query = match filter {
+            Some(filter) => format!("SELECT * FROM processes WHERE {}", filter),
+            None => String::from("SELECT * FROM processes"),
+        };
+
+        self.query(&query)?.iter().map(Process::from_row).collect()
+    }
+}
+
+#[cfg(feature = "convenience-tables")]
+fn number(row: &BTreeMap<String, String>, name: &str) -> Option<i64> {
+    column(row, name).and_then(|value| value.parse().ok())
+}
+
+fn column(row: &BTreeMap<String, String>, name: &str) -> Option<String> {
+    match row.get(name) {
+        Some(value) if !value.is_empty() => Some(value.clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn process_info_rejects_a_bad_pid() {
+        let mut row = BTreeMap::new();
+        row.insert(String::from("name"), String::from("init"));
+        match ProcessInfo::from_row(&row) {
+            Err(OsqueryError::MissingColumn(column)) => assert_eq!(column, "pid"),
+            res => panic!("expected a missing column, got {:?}", res),
+        }
+
+        row.insert(String::from("pid"), String::from("one"));
+        match ProcessInfo::from_row(&row) {
+            Err(OsqueryError::Deserialize(_)) => {}
+            res => panic!("expected a deserialize error, got {:?}", res),
+        }
+
+        row.insert(String::from("pid"), String::from("1"));
+        assert_eq!(ProcessInfo::from_row(&row).unwrap().pid, 1);
+    }
+}
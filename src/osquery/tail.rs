@@ -0,0 +1,188 @@
+use crate::osquery::error::OsqueryError;
+use crate::osquery::plugin::{LoggerPlugin, Plugins};
+use crate::osquery::register_extension::OsqueryClient;
+use crate::osquery::server::ExtensionServer;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+/// A log osquery sent to a logger plugin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogEntry {
+    /// A query result, serialized as JSON.
+    Result(String),
+
+    /// A snapshot query result, serialized as JSON.
+    Snapshot(String),
+
+    /// A batch of status logs, as a JSON array.
+    Status(String),
+}
+
+/// A live feed of the logs osquery sends to a logger plugin, returned by
+/// `OsqueryClient::tail_logs`. The logger is deregistered when the tail is
+/// dropped.
+pub struct LogTail {
+    server: ExtensionServer,
+    receiver: Receiver<LogEntry>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl LogTail {
+    /// Returns the receiving end of the feed.
+    pub fn receiver(&self) -> &Receiver<LogEntry> {
+        &self.receiver
+    }
+
+    /// Returns how many logs were dropped so far because the feed was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Deregisters the logger and ends the feed.
+    pub fn stop(mut self) -> Result<(), OsqueryError> {
+        self.server.stop()
+    }
+}
+
+/// A logger plugin passing every log on to a channel.
+struct ChannelLogger {
+    name: String,
+    sender: Mutex<SyncSender<LogEntry>>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl ChannelLogger {
+    fn send(&self, entry: LogEntry) -> Result<(), String> {
+        match self.sender.lock().unwrap().try_send(entry) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            // Nobody is reading the feed anymore.
+            Err(TrySendError::Disconnected(_)) => Ok(()),
+        }
+    }
+}
+
+impl LoggerPlugin for ChannelLogger {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn log_string(&self, s: &str) -> Result<(), String> {
+        self.send(LogEntry::Result(String::from(s)))
+    }
+
+    fn log_snapshot(&self, s: &str) -> Result<(), String> {
+        self.send(LogEntry::Snapshot(String::from(s)))
+    }
+
+    fn log_status(&self, logs: &str) -> Result<(), String> {
+        self.send(LogEntry::Status(String::from(logs)))
+    }
+}
+
+impl OsqueryClient {
+    /// Registers a logger plugin named `name` with the extension manager the
+    /// client is connected to, and returns a feed of the logs osquery sends
+    /// it. osquery only sends logs to the logger plugins named by its
+    /// `--logger_plugin` flag, such as `--logger_plugin=filesystem,tail`.
+    /// The logger is registered over a connection of its own, made with the
+    /// settings of this client, as `ExtensionServer::start_with` does.
+    ///
+    /// The feed holds up to `capacity` logs. While it is full, new logs are
+    /// dropped rather than holding up osquery, and counted by
+    /// `LogTail::dropped`. A capacity of 0 is raised to 1: a feed without
+    /// room would only pass on the logs sent while the receiver happens to
+    /// be waiting, and drop nearly all others.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// let tail = client.tail_logs("tail", 1024).unwrap();
+    /// for entry in tail.receiver() {
+    ///     println!("{:?}", entry);
+    /// }
+    /// ```
+    pub fn tail_logs(&self, name: &str, capacity: usize) -> Result<LogTail, OsqueryError> {
+        self.check_not_inspecting()?;
+        let (sender, receiver) = mpsc::sync_channel(capacity.max(1));
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let plugins = Plugins::new().add_logger(ChannelLogger {
+            name: String::from(name),
+            sender: Mutex::new(sender),
+            dropped: dropped.clone(),
+        });
+
+        let registry = plugins.registry();
+        let server = ExtensionServer::start_with(self.config(), name, registry, plugins)?;
+        Ok(LogTail {
+            server,
+            receiver,
+            dropped,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osquery::builder::OsqueryClientBuilder;
+    use crate::osquery::fake::{test_socket, FakeExtensionManager};
+    use crate::osquery::osquery::{ExtensionSyncClient, TExtensionSyncClient};
+    use crate::osquery::plugin::LOGGER_REGISTRY;
+
+    use std::collections::BTreeMap;
+    use std::os::unix::net::UnixStream;
+    use thrift::protocol::{TBinaryInputProtocol, TBinaryOutputProtocol};
+
+    #[test]
+    fn tail_connects_with_the_settings_of_the_client() {
+        let manager = FakeExtensionManager::new()
+            .strict(false)
+            .start(&test_socket("tail-settings"))
+            .unwrap();
+        let client = OsqueryClientBuilder::new(manager.socket_path())
+            .strict(false)
+            .build()
+            .unwrap();
+
+        let tail = client.tail_logs("tail", 1).unwrap();
+        assert!(manager.extensions().contains_key(&tail.server.uuid()));
+        tail.stop().unwrap();
+    }
+
+    #[test]
+    fn zero_capacity_holds_one_log() {
+        let manager = FakeExtensionManager::new()
+            .start(&test_socket("tail-zero"))
+            .unwrap();
+        let client = OsqueryClient::new(manager.socket_path()).unwrap();
+        let tail = client.tail_logs("tail", 0).unwrap();
+
+        // Log the way osquery does, with nobody waiting on the feed.
+        let stream = UnixStream::connect(tail.server.socket_path()).unwrap();
+        let mut osquery = ExtensionSyncClient::new(
+            TBinaryInputProtocol::new(stream.try_clone().unwrap(), true),
+            TBinaryOutputProtocol::new(stream, true),
+        );
+        for log in &["first", "second"] {
+            let request = BTreeMap::from([(String::from("string"), String::from(*log))]);
+            osquery
+                .call(String::from(LOGGER_REGISTRY), String::from("tail"), request)
+                .unwrap();
+        }
+
+        assert_eq!(
+            tail.receiver().try_recv(),
+            Ok(LogEntry::Result(String::from("first")))
+        );
+        assert_eq!(tail.dropped(), 1);
+        tail.stop().unwrap();
+    }
+}
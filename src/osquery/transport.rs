@@ -0,0 +1,242 @@
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often a read waiting on a unix socket checks the shutdown flag.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A read transport that counts the bytes read through it. The count is
+/// shared with the client so it can be inspected and reset between calls
+/// without reaching into the thrift protocol that owns the transport.
+///
+/// The time the first bytes arrive after the count was reset is recorded
+/// as well, which splits a call into waiting for the response and reading
+/// it.
+pub(crate) struct CountingReader<R: Read> {
+    inner: R,
+    count: Arc<AtomicUsize>,
+    first_read: Arc<Mutex<Option<Instant>>>,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub(crate) fn new(
+        inner: R,
+        count: Arc<AtomicUsize>,
+        first_read: Arc<Mutex<Option<Instant>>>,
+    ) -> Self {
+        CountingReader {
+            inner,
+            count,
+            first_read,
+        }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if self.count.fetch_add(n, Ordering::Relaxed) == 0 && n > 0 {
+            *self.first_read.lock().unwrap() = Some(Instant::now());
+        }
+        Ok(n)
+    }
+}
+
+/// A read transport that enforces an overall deadline for a call. When
+/// reading from a unix socket, the socket read timeout is tightened to the
+/// time left until the deadline before every read, so a peer dribbling
+/// bytes can't keep the call alive past it. Other streams are only checked
+/// against the deadline between reads.
+///
+/// With a shutdown flag, reads from a unix socket also wake up regularly
+/// to give up once the flag is set.
+pub(crate) struct DeadlineReader<R: Read> {
+    inner: R,
+    socket: Option<UnixStream>,
+    deadline: Arc<Mutex<Option<Instant>>>,
+    shutdown: Option<Arc<AtomicBool>>,
+}
+
+impl<R: Read> DeadlineReader<R> {
+    pub(crate) fn new(
+        inner: R,
+        socket: Option<UnixStream>,
+        deadline: Arc<Mutex<Option<Instant>>>,
+        shutdown: Option<Arc<AtomicBool>>,
+    ) -> Self {
+        DeadlineReader {
+            inner,
+            socket,
+            deadline,
+            shutdown,
+        }
+    }
+}
+
+impl<R: Read> Read for DeadlineReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let polling = self.socket.is_some() && self.shutdown.is_some();
+        loop {
+            let deadline = *self.deadline.lock().unwrap();
+            let mut timeout = None;
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.as_nanos() == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "call deadline exceeded",
+                    ));
+                }
+                timeout = Some(remaining);
+            }
+            if polling {
+                timeout = Some(timeout.map_or(SHUTDOWN_POLL_INTERVAL, |timeout| {
+                    timeout.min(SHUTDOWN_POLL_INTERVAL)
+                }));
+            }
+            if let (Some(socket), Some(_)) = (&self.socket, timeout) {
+                socket.set_read_timeout(timeout)?;
+            }
+
+            match self.inner.read(buf) {
+                Err(ref e)
+                    if polling
+                        && matches!(
+                            e.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) =>
+                {
+                    let shut_down = self
+                        .shutdown
+                        .as_ref()
+                        .is_some_and(|shutdown| shutdown.load(Ordering::Relaxed));
+                    if shut_down {
+                        return Err(io::Error::other("shut down"));
+                    }
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// A handle on a stream shared between the read and write side of the
+/// thrift protocols, for streams that can't be cloned. Calls are strictly
+/// request then response, so the two sides never contend for the lock.
+pub(crate) struct SharedStream<S>(Arc<Mutex<S>>);
+
+impl<S> SharedStream<S> {
+    pub(crate) fn new(stream: S) -> Self {
+        SharedStream(Arc::new(Mutex::new(stream)))
+    }
+}
+
+impl<S> Clone for SharedStream<S> {
+    fn clone(&self) -> Self {
+        SharedStream(self.0.clone())
+    }
+}
+
+impl<S: Read> Read for SharedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+impl<S: Write> Write for SharedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// Sets a socket buffer size option, such as `SO_RCVBUF`, on the socket.
+pub(crate) fn set_buffer_size(
+    socket: &UnixStream,
+    option: libc::c_int,
+    size: usize,
+) -> io::Result<()> {
+    let size = libc::c_int::try_from(size).unwrap_or(libc::c_int::MAX);
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            &size as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Returns the uid of the process on the other end of the socket, or `None`
+/// if the platform has no way to read it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn peer_uid(socket: &UnixStream) -> io::Result<Option<u32>> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Some(cred.uid))
+}
+
+/// Returns the uid of the process on the other end of the socket, or `None`
+/// if the platform has no way to read it.
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+))]
+pub(crate) fn peer_uid(socket: &UnixStream) -> io::Result<Option<u32>> {
+    let mut uid: libc::uid_t = 0;
+    let mut gid: libc::gid_t = 0;
+    let res = unsafe { libc::getpeereid(socket.as_raw_fd(), &mut uid, &mut gid) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Some(uid))
+}
+
+/// Returns the uid of the process on the other end of the socket, or `None`
+/// if the platform has no way to read it.
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+)))]
+pub(crate) fn peer_uid(_socket: &UnixStream) -> io::Result<Option<u32>> {
+    Ok(None)
+}
@@ -0,0 +1,305 @@
+use crate::osquery::error::{OsqueryError, Phase};
+use crate::osquery::register_extension::OsqueryClient;
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+const VERSION_QUERY: &str = "SELECT version FROM osquery_info";
+const WATCHER_QUERY: &str = "SELECT watcher FROM osquery_info";
+
+/// An osquery version, which compares by its numbers rather than as text,
+/// so that `10.0.0` is newer than `9.0.0`.
+///
+/// osquery versions look like `5.2.2`, while development builds add what
+/// `git describe` says about them, as in `5.2.2-26-gcb8dd3a`. That suffix
+/// is kept, and such a build is newer than the release it names, with the
+/// number of commits since the release compared as a number. Any other
+/// suffix, as in `5.0.0-rc1`, marks a pre-release, which is older than the
+/// release. Suffixes of the same kind compare their runs of digits as
+/// numbers, so `5.0.0-rc10` is newer than `5.0.0-rc9`.
+///
+/// This is a type of its own rather than `semver::Version`: the crate
+/// doesn't depend on `semver`, and the suffixes `git describe` adds aren't
+/// valid semver, which would read `5.2.2-26-gcb8dd3a` as a pre-release
+/// older than `5.2.2` instead of a build made after it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OsqueryVersion {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+
+    /// Whatever follows the numbers, without the separating `-`, or empty.
+    pub suffix: String,
+}
+
+impl OsqueryVersion {
+    /// Returns the version without a suffix.
+    pub fn new(major: u64, minor: u64, patch: u64) -> Self {
+        OsqueryVersion {
+            major,
+            minor,
+            patch,
+            suffix: String::new(),
+        }
+    }
+
+    /// Parses a version as osquery reports it, returning `None` if it
+    /// doesn't start with a `major.minor.patch` triple. A missing patch
+    /// number is read as 0, as older osquery versions sometimes omitted it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use osquery_rs::osquery::version::OsqueryVersion;
+    /// let version = OsqueryVersion::parse("5.2.2-26-gcb8dd3a").unwrap();
+    /// assert_eq!((version.major, version.minor, version.patch), (5, 2, 2));
+    /// assert_eq!(version.suffix, "26-gcb8dd3a");
+    /// assert!(OsqueryVersion::parse("10.0.0") > OsqueryVersion::parse("9.0.0"));
+    /// assert_eq!(OsqueryVersion::parse("unknown"), None);
+    /// ```
+    pub fn parse(version: &str) -> Option<Self> {
+        let version = version.trim();
+        let (numbers, suffix) = match version.find(|c: char| !(c.is_ascii_digit() || c == '.')) {
+            Some(idx) => (&version[..idx], &version[idx..]),
+            None => (version, ""),
+        };
+
+        let mut parts = numbers.split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next()?.parse().ok()?;
+        let patch = match parts.next() {
+            Some(patch) => patch.parse().ok()?,
+            None => 0,
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+
+        let suffix = suffix.trim_start_matches(['-', '+']);
+        Some(OsqueryVersion {
+            major,
+            minor,
+            patch,
+            suffix: String::from(suffix),
+        })
+    }
+}
+
+impl OsqueryVersion {
+    /// Returns the rank of the suffix, with pre-releases before the release
+    /// and development builds after it.
+    fn suffix_rank(&self) -> u8 {
+        match self.suffix.chars().next() {
+            None => 1,
+            Some(c) if c.is_ascii_digit() => 2,
+            Some(_) => 0,
+        }
+    }
+}
+
+impl Ord for OsqueryVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| self.suffix_rank().cmp(&other.suffix_rank()))
+            .then_with(|| compare_suffixes(&self.suffix, &other.suffix))
+            // Tells apart suffixes only differing in leading zeros, to agree
+            // with `Eq`.
+            .then_with(|| self.suffix.cmp(&other.suffix))
+    }
+}
+
+impl PartialOrd for OsqueryVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Compares two suffixes run by run, with runs of digits compared as
+/// numbers and anything else as text.
+fn compare_suffixes(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        let (run_a, rest_a) = split_run(a);
+        let (run_b, rest_b) = split_run(b);
+        let ordering = match (run_a, run_b) {
+            ("", "") => return Ordering::Equal,
+            (x, y) if is_number(x) && is_number(y) => {
+                let (x, y) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+            }
+            (x, y) => x.cmp(y),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+        a = rest_a;
+        b = rest_b;
+    }
+}
+
+/// Splits off the leading run of digits or of other characters.
+fn split_run(s: &str) -> (&str, &str) {
+    let digits = s.starts_with(|c: char| c.is_ascii_digit());
+    let end = s
+        .find(|c: char| c.is_ascii_digit() != digits)
+        .unwrap_or(s.len());
+    s.split_at(end)
+}
+
+fn is_number(run: &str) -> bool {
+    run.starts_with(|c: char| c.is_ascii_digit())
+}
+
+impl FromStr for OsqueryVersion {
+    type Err = OsqueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        OsqueryVersion::parse(s).ok_or_else(|| OsqueryError::Protocol {
+            phase: Phase::Query,
+            message: format!("invalid osquery version {:?}", s),
+        })
+    }
+}
+
+impl fmt::Display for OsqueryVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if !self.suffix.is_empty() {
+            write!(f, "-{}", self.suffix)?;
+        }
+        Ok(())
+    }
+}
+
+impl OsqueryClient {
+    /// Returns the version of the connected osquery, read from
+    /// `osquery_info`, or `None` if the version can't be parsed.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// # use osquery_rs::osquery::version::OsqueryVersion;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// if client.server_version().unwrap() >= Some(OsqueryVersion::new(5, 0, 0)) {
+    ///     println!("osquery 5 or newer");
+    /// }
+    /// ```
+    pub fn server_version(&mut self) -> Result<Option<OsqueryVersion>, OsqueryError> {
+        let rows = self.query(VERSION_QUERY)?;
+        Ok(rows
+            .first()
+            .and_then(|row| row.get("version"))
+            .and_then(|version| OsqueryVersion::parse(version)))
+    }
+
+    /// Returns the version of the connected osquery like `server_version`,
+    /// or `None` if it can't be read or parsed. See `OsqueryVersion` for
+    /// why this isn't a `semver::Version`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// # use osquery_rs::osquery::version::OsqueryVersion;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// if client.server_semver() >= Some(OsqueryVersion::new(5, 0, 0)) {
+    ///     println!("osquery 5 or newer");
+    /// }
+    /// ```
+    pub fn server_semver(&mut self) -> Option<OsqueryVersion> {
+        self.server_version().ok().flatten()
+    }
+
+    /// Returns whether the connected osquery is a worker process started by
+    /// a watcher, read from `osquery_info.watcher`, which holds the pid of
+    /// the watcher or -1 when osquery runs without one, as with
+    /// `--disable_watchdog` or in `osqueryi`. The watcher only supervises
+    /// the worker and runs no queries itself, so an extension seeing a
+    /// worker can expect it to be restarted, with a new pid, when it goes
+    /// over its limits.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use osquery_rs::osquery::*;
+    /// let mut client = OsqueryClient::new("/tmp/osquery.sock").unwrap();
+    /// if client.is_worker().unwrap() {
+    ///     println!("osquery runs under a watcher");
+    /// }
+    /// ```
+    pub fn is_worker(&mut self) -> Result<bool, OsqueryError> {
+        let watcher = self
+            .query(WATCHER_QUERY)?
+            .into_iter()
+            .next()
+            .and_then(|mut row| row.remove("watcher"))
+            .ok_or_else(|| OsqueryError::Protocol {
+                phase: Phase::Query,
+                message: String::from("missing watcher pid"),
+            })?;
+        let pid: i64 = watcher.trim().parse().map_err(|_| OsqueryError::Protocol {
+            phase: Phase::Query,
+            message: format!("invalid watcher pid {:?}", watcher),
+        })?;
+        Ok(pid > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osquery::fake::{test_socket, FakeExtensionManager};
+
+    use std::collections::BTreeMap;
+
+    fn version(version: &str) -> OsqueryVersion {
+        OsqueryVersion::parse(version).unwrap()
+    }
+
+    #[test]
+    fn commit_counts_compare_as_numbers() {
+        assert!(version("5.2.2-100-gcb8dd3a") > version("5.2.2-26-gcb8dd3a"));
+        assert!(version("5.2.2-9-gffffff") < version("5.2.2-10-g000000"));
+        assert!(version("5.2.2-26-gcb8dd3a") > version("5.2.2"));
+        assert!(version("5.2.2-26-gcb8dd3a") < version("5.2.3"));
+    }
+
+    #[test]
+    fn pre_releases_come_before_the_release() {
+        assert!(version("5.0.0-rc1") < version("5.0.0"));
+        assert!(version("5.0.0-rc1") > version("4.9.0"));
+        assert!(version("5.0.0-rc10") > version("5.0.0-rc9"));
+        assert!(version("5.0.0-beta") < version("5.0.0-rc1"));
+        assert!(version("5.0.0-rc1") < version("5.0.0-1-gabcdef"));
+    }
+
+    #[test]
+    fn server_semver_parses_the_reported_version() {
+        let mut row = BTreeMap::new();
+        row.insert(String::from("version"), String::from("5.2.2-26-gcb8dd3a"));
+        let manager = FakeExtensionManager::new()
+            .query_response(VERSION_QUERY, vec![row])
+            .start(&test_socket("server-semver"))
+            .unwrap();
+        let mut client = OsqueryClient::new(manager.socket_path()).unwrap();
+        assert_eq!(client.server_semver(), Some(version("5.2.2-26-gcb8dd3a")));
+
+        manager.set_query_response(VERSION_QUERY, Vec::new());
+        assert_eq!(client.server_semver(), None);
+    }
+
+    #[test]
+    fn ordering_agrees_with_equality() {
+        assert_eq!(
+            version("5.0.0-rc1").cmp(&version("5.0.0-rc1")),
+            Ordering::Equal
+        );
+        assert_ne!(
+            version("5.0.0-rc01").cmp(&version("5.0.0-rc1")),
+            Ordering::Equal
+        );
+    }
+}